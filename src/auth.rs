@@ -0,0 +1,160 @@
+use std::{collections::HashMap, env, fmt, fs, io::Write, path::PathBuf, process::{Command, Stdio}};
+
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Deserialize;
+
+/// Keys Docker Hub credentials can be stored under in config.json, `docker login` uses the first
+const DOCKER_HUB_KEYS: [&str; 4] = [
+    "https://index.docker.io/v1/",
+    "index.docker.io",
+    "docker.io",
+    "registry-1.docker.io",
+];
+
+/// Username a credential helper reports when its secret is an identity token
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// What the registry's token server is authenticated with
+#[derive(Clone)]
+pub enum Credentials {
+    /// HTTP Basic auth
+    Basic { username: String, password: String },
+    /// OAuth2 refresh token, as stored by `docker login` with some credential helpers
+    IdentityToken(String),
+}
+
+// Keeps secrets out of `{:?}` and therefore out of debug logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Basic { username, .. } => f.debug_struct("Basic")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+            Credentials::IdentityToken(_) => f.debug_tuple("IdentityToken").field(&"<redacted>").finish(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    /// Registry to helper name, run as `docker-credential-<name>`
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+    /// Helper for every registry without its own entry in `credHelpers`
+    #[serde(default)]
+    creds_store: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct AuthEntry {
+    /// base64 of `username:password`
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    identitytoken: Option<String>,
+}
+
+/// Reply of `docker-credential-<helper> get`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct HelperResponse {
+    username: String,
+    secret: String,
+}
+
+/// Docker Hub credentials saved by `docker login`, if there are any.
+///
+/// Like the docker CLI, a registry's `credHelpers` entry wins over `credsStore`,
+/// which wins over credentials stored inline in `auths`.
+pub fn from_docker_config() -> anyhow::Result<Option<Credentials>> {
+    let path = match docker_config_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(None),
+    };
+
+    let content = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let config: DockerConfig = serde_json::from_slice(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    if let Some(helper) = DOCKER_HUB_KEYS.iter().find_map(|key| config.cred_helpers.get(*key)) {
+        return from_helper(helper);
+    }
+    if let Some(store) = &config.creds_store {
+        if let Some(credentials) = from_helper(store)? {
+            return Ok(Some(credentials));
+        }
+    }
+
+    let entry = match DOCKER_HUB_KEYS.iter().find_map(|key| config.auths.get(*key)) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    if let Some(token) = entry.identitytoken.as_deref().filter(|token| !token.is_empty()) {
+        return Ok(Some(Credentials::IdentityToken(token.to_string())));
+    }
+
+    entry.auth.as_deref()
+        .filter(|auth| !auth.is_empty())
+        .map(decode_auth)
+        .transpose()
+        .with_context(|| format!("Invalid credentials in {}", path.display()))
+}
+
+/// Ask `docker-credential-<helper>` for Docker Hub's credentials, `None` if it has none
+fn from_helper(helper: &str) -> anyhow::Result<Option<Credentials>> {
+    let program = format!("docker-credential-{}", helper);
+
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+
+    // The protocol takes the server URL on stdin, dropping the pipe signals its end
+    child.stdin.take().context("Helper stdin is not piped")?
+        .write_all(DOCKER_HUB_KEYS[0].as_bytes())?;
+    let output = child.wait_with_output().with_context(|| format!("Failed to run {}", program))?;
+
+    if !output.status.success() {
+        let message = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        // Not an error, the store just has nothing for this registry
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        bail!("{} get failed: {}", program, message.trim());
+    }
+
+    let response: HelperResponse = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Failed to parse the reply of {}", program))?;
+
+    Ok(Some(match response.username.as_str() {
+        IDENTITY_TOKEN_USERNAME => Credentials::IdentityToken(response.secret),
+        _ => Credentials::Basic { username: response.username, password: response.secret },
+    }))
+}
+
+/// `$DOCKER_CONFIG/config.json`, falling back to `~/.docker/config.json` like the docker CLI
+fn docker_config_path() -> Option<PathBuf> {
+    match env::var_os("DOCKER_CONFIG") {
+        Some(dir) => Some(PathBuf::from(dir).join("config.json")),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker/config.json")),
+    }
+}
+
+fn decode_auth(auth: &str) -> anyhow::Result<Credentials> {
+    let decoded = STANDARD.decode(auth.trim()).context("auth is not valid base64")?;
+    let decoded = String::from_utf8(decoded).context("auth is not valid UTF-8")?;
+
+    match decoded.split_once(':') {
+        Some((username, password)) => Ok(Credentials::Basic { username: username.to_string(), password: password.to_string() }),
+        None => bail!("auth is not in username:password form"),
+    }
+}
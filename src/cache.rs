@@ -0,0 +1,127 @@
+use std::{collections::HashSet, fs, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+/// Manifests and configs from earlier pulls, stored as `<repository>/blobs/<digest>`.
+///
+/// Entries are addressed by digest, so once read back and verified they can be trusted
+/// without asking the registry. Tags move, for those the ETag the registry last sent is
+/// kept in `<repository>/tags/<tag>.json` and revalidated with `If-None-Match`.
+pub(crate) struct ManifestCache {
+    root: PathBuf,
+}
+
+/// What a tag resolved to the last time it was fetched
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct TagEntry {
+    pub etag: String,
+    pub digest: String,
+}
+
+impl ManifestCache {
+    pub fn new(root: &Path) -> Self {
+        ManifestCache { root: root.to_path_buf() }
+    }
+
+    /// The bytes stored for `repository@digest`, `None` if missing or corrupted
+    pub fn get(&self, repository: &str, digest: &str) -> Option<Vec<u8>> {
+        let content = fs::read(self.blob_path(repository, digest)).ok()?;
+        (format!("sha256:{:x}", Sha256::digest(&content)) == digest).then_some(content)
+    }
+
+    /// Store `content` under its own digest, which is returned
+    pub fn put(&self, repository: &str, content: &[u8]) -> anyhow::Result<String> {
+        let digest = format!("sha256:{:x}", Sha256::digest(content));
+        write_atomic(&self.blob_path(repository, &digest), content)?;
+        Ok(digest)
+    }
+
+    pub fn tag(&self, repository: &str, tag: &str) -> Option<TagEntry> {
+        let content = fs::read(self.tag_path(repository, tag)).ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    pub fn set_tag(&self, repository: &str, tag: &str, entry: &TagEntry) -> anyhow::Result<()> {
+        write_atomic(&self.tag_path(repository, tag), &serde_json::to_vec(entry)?)
+    }
+
+    /// Delete every blob whose digest isn't in `keep`, then the tags left pointing
+    /// at a deleted blob. Returns the number of bytes freed.
+    pub fn prune(&self, keep: &HashSet<String>) -> anyhow::Result<u64> {
+        let mut tag_dirs = Vec::new();
+        let mut freed = prune_blobs(&self.root, keep, &mut tag_dirs)?;
+
+        for tag_dir in tag_dirs {
+            let blobs = tag_dir.parent().map(|repository| repository.join("blobs")).unwrap_or_default();
+
+            for entry in fs::read_dir(&tag_dir)? {
+                let path = entry?.path();
+                let target = fs::read(&path).ok()
+                    .and_then(|content| serde_json::from_slice::<TagEntry>(&content).ok())
+                    .map(|entry| blobs.join(entry.digest.replace(':', "-")));
+
+                if !target.is_some_and(|target| target.exists()) {
+                    freed += fs::symlink_metadata(&path)?.len();
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        Ok(freed)
+    }
+
+    fn blob_path(&self, repository: &str, digest: &str) -> PathBuf {
+        self.root.join(repository).join("blobs").join(digest.replace(':', "-"))
+    }
+
+    fn tag_path(&self, repository: &str, tag: &str) -> PathBuf {
+        self.root.join(repository).join("tags").join(format!("{}.json", tag))
+    }
+}
+
+/// Repositories nest like `library/alpine`, so the blobs and tags dirs can be at any depth
+fn prune_blobs(dir: &Path, keep: &HashSet<String>, tag_dirs: &mut Vec<PathBuf>) -> anyhow::Result<u64> {
+    let mut freed = 0;
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(0),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        match entry.file_name().to_str() {
+            Some("blobs") => {
+                for blob in fs::read_dir(entry.path())? {
+                    let blob = blob?;
+                    let digest = blob.file_name().to_string_lossy().replacen('-', ":", 1);
+                    if !keep.contains(&digest) {
+                        freed += blob.metadata()?.len();
+                        fs::remove_file(blob.path())?;
+                    }
+                }
+            }
+            Some("tags") => tag_dirs.push(entry.path()),
+            _ => freed += prune_blobs(&entry.path(), keep, tag_dirs)?,
+        }
+    }
+
+    Ok(freed)
+}
+
+/// Concurrent pulls of the same image must never read a half-written entry
+fn write_atomic(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::fs;
+
+use anyhow::{bail, Context};
+use caps::{CapSet, Capability, CapsHashSet};
+use tracing::debug;
+
+use crate::users::{self, ExecUser};
+
+/// Capabilities kept when nothing is added or dropped on the command line
+pub(crate) const DEFAULT_CAPS: [Capability; 6] = [
+    Capability::CAP_CHOWN,
+    Capability::CAP_DAC_OVERRIDE,
+    Capability::CAP_SETUID,
+    Capability::CAP_SETGID,
+    Capability::CAP_NET_BIND_SERVICE,
+    Capability::CAP_KILL,
+];
+
+/// Resolve the final allowlist from the defaults plus `--cap-add` / `--cap-drop`.
+///
+/// Both accept `NET_ADMIN`, `cap_net_admin` or `ALL`, drops are applied after adds.
+pub fn resolve(cap_add: &[String], cap_drop: &[String]) -> anyhow::Result<CapsHashSet> {
+    apply(DEFAULT_CAPS.into_iter().collect(), cap_add, cap_drop)
+}
+
+/// Like [`resolve`], starting from `allowed` instead of the defaults
+pub fn apply(mut allowed: CapsHashSet, cap_add: &[String], cap_drop: &[String]) -> anyhow::Result<CapsHashSet> {
+    for name in cap_add {
+        match parse(name)? {
+            Some(cap) => { allowed.insert(cap); }
+            None => allowed = caps::runtime::thread_all_supported(),
+        }
+    }
+
+    for name in cap_drop {
+        match parse(name)? {
+            Some(cap) => { allowed.remove(&cap); }
+            None => allowed.clear(),
+        }
+    }
+
+    Ok(allowed)
+}
+
+/// `None` stands for ALL
+fn parse(name: &str) -> anyhow::Result<Option<Capability>> {
+    if name.eq_ignore_ascii_case("all") {
+        return Ok(None);
+    }
+
+    let canonical = caps::to_canonical(name);
+    match canonical.parse::<Capability>() {
+        Ok(cap) => Ok(Some(cap)),
+        Err(_) => bail!("Unknown capability: {}", name),
+    }
+}
+
+/// Restrict the calling process to `allowed` and become `user`, must run right before exec.
+///
+/// The bounding set is trimmed first, it needs CAP_SETPCAP which is likely
+/// about to be dropped from the effective set. The user is switched while
+/// CAP_SETUID and CAP_SETGID are still effective, whatever `allowed` says;
+/// a non-root user then loses the rest, like under docker.
+pub fn drop_capabilities(allowed: &CapsHashSet, user: Option<&ExecUser>) -> anyhow::Result<()> {
+    for cap in caps::runtime::thread_all_supported() {
+        if !allowed.contains(&cap) {
+            caps::drop(None, CapSet::Bounding, cap)
+                .with_context(|| format!("Failed to drop {} from the bounding set", cap))?;
+        }
+    }
+
+    caps::clear(None, CapSet::Ambient).context("Failed to clear ambient capabilities")?;
+    caps::set(None, CapSet::Inheritable, allowed).context("Failed to set inheritable capabilities")?;
+
+    if let Some(user) = user {
+        users::switch_to(user)?;
+    }
+
+    // Only what the switch left can be kept
+    let permitted = caps::read(None, CapSet::Permitted).context("Failed to read permitted capabilities")?;
+    let kept: CapsHashSet = allowed.intersection(&permitted).copied().collect();
+    caps::set(None, CapSet::Effective, &kept).context("Failed to set effective capabilities")?;
+    caps::set(None, CapSet::Permitted, &kept).context("Failed to set permitted capabilities")?;
+
+    if let Some(cap_eff) = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| status.lines().find(|l| l.starts_with("CapEff:")).map(str::to_string))
+    {
+        debug!("{}", cap_eff);
+    }
+
+    Ok(())
+}
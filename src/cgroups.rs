@@ -1,80 +1,176 @@
-use std::io::Write as _;
+use std::{fmt, io, path::Path, str::FromStr};
 
-use crate::ActionResult;
+use anyhow::{bail, Context};
+use serde::Deserialize;
 
-pub struct CgroupManager {
-    pub cgroup_path: String,
+use crate::{lrng_cgroup::{self, Controller}, ActionResult};
+
+/// Where the cgroup2 hierarchy is mounted
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// A container's cgroup, relative to [`CGROUP_ROOT`]
+pub fn cgroup_name(container_id: &str) -> String {
+    format!("woody/{}", container_id)
 }
 
-impl CgroupManager {
-    pub fn new(container_id: &str) -> Self {
-        CgroupManager {
-            cgroup_path: format!("/sys/fs/cgroup/woody/{}", container_id)
+/// `--cgroup`, whether a container gets a cgroup of its own
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CgroupMode {
+    /// Created when possible, only required for resource limits
+    #[default]
+    Auto,
+    /// Any cgroup setup failure aborts the run
+    Enabled,
+    /// Never touched, resource limits are ignored
+    Disabled,
+}
+
+impl FromStr for CgroupMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "auto" => Ok(CgroupMode::Auto),
+            "enabled" => Ok(CgroupMode::Enabled),
+            "disabled" => Ok(CgroupMode::Disabled),
+            other => bail!("Unknown cgroup mode {:?}, expected auto, enabled or disabled", other),
         }
     }
+}
 
-    pub fn create(&self) -> ActionResult {
-        std::fs::create_dir_all(&self.cgroup_path)?;
-        Ok(())
+/// Cgroup failures the runtime can decide to live with
+#[derive(Debug)]
+pub enum CgroupError {
+    /// No writable cgroup2 hierarchy, as in containers without cgroup delegation or some CI runners
+    Unavailable(io::Error),
+}
+
+impl fmt::Display for CgroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CgroupError::Unavailable(e) => write!(f, "cgroups are unavailable under {}: {}", CGROUP_ROOT, e),
+        }
     }
+}
 
-    // This enables controllers for the cgroup we are about to use.
-    // It must be run before setting limits.
-    pub fn enable_controllers(&self) -> ActionResult {
-        // You enable controllers from the parent directory.
-        // NOTE: This assumes "/sys/fs/cgroup/woody" already exists.
-        // Your setup script might need to run `mkdir /sys/fs/cgroup/woody` once.
-        let subtree_path = std::path::Path::new(&self.cgroup_path).parent().unwrap().join("cgroup.subtree_control");
+impl std::error::Error for CgroupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CgroupError::Unavailable(e) => Some(e),
+        }
+    }
+}
 
-        // The `+` enables the controller for child cgroups.
-        std::fs::write(subtree_path, "+pids +memory")?;
-        Ok(())
+impl CgroupError {
+    /// Whether `error` comes down to cgroups being unavailable
+    pub fn is_unavailable(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| matches!(cause.downcast_ref::<CgroupError>(), Some(CgroupError::Unavailable(_))))
     }
+}
 
-    // Crucial for preventing "fork: Cannot allocate memory"
-    pub fn set_pid_limit(&self, limit: u32) -> ActionResult {
-        let path = format!("{}/pids.max", self.cgroup_path);
-        std::fs::write(path, limit.to_string())?;
-        Ok(())
+/// Limits for a container's cgroup, unset ones are left at the kernel default
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// Bytes
+    pub memory: Option<u64>,
+    /// Bytes of memory plus swap, -1 is unlimited swap
+    pub memory_swap: Option<i64>,
+    pub pids: Option<u32>,
+    /// Relative weight, 1024 is the default
+    pub cpu_shares: Option<u64>,
+    /// Microseconds of CPU time per period, -1 is unlimited
+    pub cpu_quota: Option<i64>,
+    /// Microseconds, [`DEFAULT_CPU_PERIOD`] if only a quota is set
+    pub cpu_period: Option<u64>,
+}
+
+/// The kernel's default CFS period
+pub const DEFAULT_CPU_PERIOD: u64 = 100_000;
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// Limit to `cpus` CPUs worth of time, e.g. 1.5 for 150% of one core
+    pub fn set_cpus(&mut self, cpus: f64) {
+        self.cpu_quota = Some((cpus * DEFAULT_CPU_PERIOD as f64).round() as i64);
+        self.cpu_period = Some(DEFAULT_CPU_PERIOD);
     }
 
+    fn limits_cpu(&self) -> bool {
+        self.cpu_shares.is_some() || self.cpu_quota.is_some()
+    }
+}
 
-    pub fn set_memory_limit(&self, limit: u64) -> ActionResult {
-        let memory_limit_path = format!("{}/memory.max", self.cgroup_path);
-        let mut file = std::fs::File::create(memory_limit_path)?;
+pub struct CgroupManager {
+    pub cgroup_path: String,
+}
+
+impl CgroupManager {
+    pub fn new(container_id: &str) -> Self {
+        CgroupManager {
+            cgroup_path: format!("{}/{}", CGROUP_ROOT, cgroup_name(container_id))
+        }
+    }
 
-        file.write_all(limit.to_string().as_bytes())?;
+    /// Whether there is a cgroup2 hierarchy to create the container's cgroup in
+    fn check_available(&self) -> ActionResult {
+        // On a v1 only host /sys/fs/cgroup is a tmpfs, creating directories there would "work"
+        if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            let e = io::Error::new(io::ErrorKind::NotFound, "no cgroup2 hierarchy is mounted");
+            return Err(CgroupError::Unavailable(e).into());
+        }
         Ok(())
     }
 
-    pub fn add_process(&self, pid: nix::unistd::Pid) -> ActionResult {
-        let procs_path = format!("{}/cgroup.procs", self.cgroup_path);
+    /// Create the container's cgroup with `limits` applied and move `pid` into it.
+    ///
+    /// The controllers are enabled in every ancestor of the cgroup and the process goes
+    /// into its leaf, see [`lrng_cgroup::CgroupManager::get_or_create_cgroup`], which also
+    /// reuses a cgroup a crashed run left behind.
+    pub fn setup(container_id: &str, pid: nix::unistd::Pid, limits: &ResourceLimits) -> anyhow::Result<Self> {
+        let manager = CgroupManager::new(container_id);
+        manager.check_available()?;
 
-        let mut file = std::fs::File::create(procs_path)?;
-        file.write_all(pid.to_string().as_bytes())?;
+        // cpu only when needed, it's the one delegated setups most often leave out
+        let controllers: &[Controller] = if limits.limits_cpu() {
+            &[Controller::Pids, Controller::Memory, Controller::Cpu]
+        } else {
+            &[Controller::Pids, Controller::Memory]
+        };
+        let cgroup = lrng_cgroup::CgroupManager::new()
+            .and_then(|cgroups| cgroups.get_or_create_cgroup(&cgroup_name(container_id), controllers))
+            .map_err(setup_error)
+            .with_context(|| format!("Failed to create cgroup {}", manager.cgroup_path))?;
 
-        Ok(())
+        cgroup.apply(limits).context("Failed to set resource limits")?;
+        cgroup.add_process(pid.as_raw() as u32).context("Failed to move the container into its cgroup")?;
+
+        Ok(manager)
     }
 
     pub fn destroy(&self) -> ActionResult {
-        std::fs::remove_dir_all(&self.cgroup_path).ok();
+        // The interface files can't be unlinked, a cgroup without processes is removed with rmdir,
+        // its leaf first
+        std::fs::remove_dir(Path::new(&self.cgroup_path).join("leaf")).ok();
+        std::fs::remove_dir(&self.cgroup_path).ok();
         Ok(())
     }
 }
 
-// use nix::sys::prctl;
-//
-// fn drop_capabilities() -> Result<(), Box<dyn std::error::Error>> {
-//     // Drop all capabilities except the essential ones
-//
-//     let keep_caps = vec![
-//         // Add specific capabilities you want to keep
-//     ];
-//
-//
-//     // This is a simplified version - you'd want to use libcap-ng or similar
-//     // for proper capability management
-//
-//
-//     Ok(())
-// }
+/// A cgroup that can't be created because woody may not manage cgroups here, as in
+/// containers without delegation, is [`CgroupError::Unavailable`]
+fn setup_error(e: lrng_cgroup::CgroupError) -> anyhow::Error {
+    match e {
+        lrng_cgroup::CgroupError::NotFound => {
+            CgroupError::Unavailable(io::Error::new(io::ErrorKind::NotFound, "a controller isn't delegated")).into()
+        }
+        lrng_cgroup::CgroupError::PermissionDenied => {
+            CgroupError::Unavailable(io::Error::from(io::ErrorKind::PermissionDenied)).into()
+        }
+        lrng_cgroup::CgroupError::Io(e) if e.raw_os_error() == Some(libc::EROFS) => CgroupError::Unavailable(e).into(),
+        e => e.into(),
+    }
+}
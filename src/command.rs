@@ -0,0 +1,69 @@
+use anyhow::bail;
+
+use crate::registry::ConfigDetails;
+
+/// A lone Cmd or Entrypoint element containing any of these is a shell command line
+const SHELL_METACHARACTERS: &[char] = &[
+    ' ', '\t', '\n', '|', '&', ';', '<', '>', '(', ')', '$', '`', '\\', '"', '\'', '*', '?', '[', '#', '~',
+];
+
+/// The argv a container starts with.
+///
+/// `--entrypoint` replaces the image's Entrypoint, an empty one clears it, and like
+/// docker it also drops the image's Cmd. Arguments after the image name replace Cmd.
+pub fn resolve(config: &ConfigDetails, entrypoint: Option<&str>, args: &[String]) -> anyhow::Result<Vec<String>> {
+    let (entrypoint, cmd) = match entrypoint {
+        Some("") => (Vec::new(), args.to_vec()),
+        Some(entrypoint) => (vec![entrypoint.to_string()], args.to_vec()),
+        None if !args.is_empty() => (image_argv(&config.entrypoint), args.to_vec()),
+        None => (image_argv(&config.entrypoint), image_argv(&config.cmd)),
+    };
+
+    let argv: Vec<String> = entrypoint.into_iter().chain(cmd).collect();
+    if argv.is_empty() {
+        bail!("Image has no entrypoint or command specified, pass a command after the image name");
+    }
+
+    Ok(argv)
+}
+
+/// An image's Cmd or Entrypoint, with shell form turned into a `/bin/sh -c` call.
+///
+/// Exec form is a list of arguments, shell form a single command line like
+/// `echo hi && ls` that only a shell can run.
+fn image_argv(value: &Option<Vec<String>>) -> Vec<String> {
+    match value.as_deref() {
+        Some([line]) if line.contains(SHELL_METACHARACTERS) => {
+            vec!["/bin/sh".to_string(), "-c".to_string(), line.clone()]
+        }
+        Some(argv) => argv.to_vec(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(cmd: &[&str]) -> ConfigDetails {
+        ConfigDetails {
+            cmd: Some(cmd.iter().map(|s| s.to_string()).collect()),
+            entrypoint: None,
+            env: Vec::new(),
+            working_dir: String::new(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exec_form_is_used_as_is() {
+        let argv = resolve(&config(&["echo", "hi"]), None, &[]).unwrap();
+        assert_eq!(argv, ["echo", "hi"]);
+    }
+
+    #[test]
+    fn shell_form_runs_through_sh() {
+        let argv = resolve(&config(&["echo hi && ls"]), None, &[]).unwrap();
+        assert_eq!(argv, ["/bin/sh", "-c", "echo hi && ls"]);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{fs, os::unix::fs::symlink, path::Path};
+
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+use sha2::{Digest as _, Sha256};
+use tracing::info;
+
+use crate::{
+    images, layers, paths,
+    registry::{self, Digest, Manifest},
+    state::ContainerState,
+};
+
+/// Save a container's changes as a new image `image_ref` in the store at `root`.
+///
+/// The upper dir becomes one more layer on top of the container's image. The layers
+/// underneath are symlinks into the source image rather than copies, so the source
+/// has to stay in the store. Returns the new image's id.
+pub fn commit_container(id: &str, image_ref: &str, root: &Path) -> anyhow::Result<String> {
+    let state = ContainerState::load(id)?;
+    let (name, reference) = registry::parse_image_name(image_ref)?;
+    if registry::is_digest(&reference) {
+        bail!("Cannot commit to a digest, pick a tag for {}", image_ref);
+    }
+
+    let source_id = state.image_id.as_deref()
+        .with_context(|| format!("Container {} has no stored image, it predates image ids or ran a --rootfs", id))?;
+    let source = images::image_path(root, source_id);
+    if !source.exists() {
+        bail!("Image {} of container {} is no longer stored", state.image, id);
+    }
+    let upper = paths::container_dir(id).join("upper");
+
+    let _lock = images::lock_store(root, false)?;
+    let partial = root.join(format!("commit-{}.partial", id));
+    if partial.exists() {
+        fs::remove_dir_all(&partial).with_context(|| format!("Failed to clear {}", partial.display()))?;
+    }
+    let layers_path = partial.join("layers");
+    fs::create_dir_all(&layers_path)?;
+
+    info!("Packing the changes of container {}", id);
+    let blob = partial.join("layer.tar.gz");
+    let (layer_digest, diff_id) = layers::pack_layer(&upper, &blob)?;
+
+    // Relative, so the store can be moved as a whole
+    let source_layers = layers::count_layers(&source.join("layers"));
+    for i in 0..source_layers {
+        symlink(Path::new("../..").join(source_id).join("layers").join(i.to_string()), layers::layer_dir(&layers_path, i))?;
+    }
+    layers::unpack_blob(&blob, &layers::layer_dir(&layers_path, source_layers), &layers::layers_below(&layers_path, source_layers))?;
+    let layer_size = fs::metadata(&blob)?.len();
+    fs::remove_file(&blob)?;
+
+    let config_raw = extend_config(&fs::read(source.join("config.json"))?, &diff_id, id)?;
+    let new_id = format!("{:x}", Sha256::digest(&config_raw));
+
+    let mut manifest: Manifest = serde_json::from_slice(&fs::read(source.join("manifest.json"))?)
+        .context("Corrupted source image manifest")?;
+    manifest.config = Digest { digest: format!("sha256:{}", new_id), size: Some(config_raw.len() as u64) };
+    manifest.layers.push(Digest { digest: layer_digest, size: Some(layer_size) });
+
+    fs::write(partial.join("manifest.json"), serde_json::to_vec(&manifest)?)?;
+    fs::write(partial.join("config.json"), &config_raw)?;
+
+    let image_path = images::image_path(root, &new_id);
+    if image_path.exists() {
+        // Nothing changed since the last commit of the same container
+        fs::remove_dir_all(&partial)?;
+    } else {
+        fs::rename(&partial, &image_path)
+            .with_context(|| format!("Failed to move image into {}", image_path.display()))?;
+    }
+
+    images::tag(root, &name, &reference, &new_id)?;
+    info!("Committed container {} as {}", id, image_ref);
+
+    Ok(new_id)
+}
+
+/// The source config with the new layer recorded in `rootfs.diff_ids` and `history`
+fn extend_config(source: &[u8], diff_id: &str, container_id: &str) -> anyhow::Result<Vec<u8>> {
+    let mut config: Value = serde_json::from_slice(source).context("Corrupted source image config")?;
+    let config_object = config.as_object_mut().context("Image config is not a JSON object")?;
+
+    let rootfs = config_object.entry("rootfs").or_insert_with(|| json!({ "type": "layers", "diff_ids": [] }));
+    match rootfs.get_mut("diff_ids").and_then(Value::as_array_mut) {
+        Some(diff_ids) => diff_ids.push(json!(diff_id)),
+        None => rootfs["diff_ids"] = json!([diff_id]),
+    }
+
+    let history = config_object.entry("history").or_insert_with(|| json!([]));
+    if let Some(history) = history.as_array_mut() {
+        history.push(json!({ "created_by": format!("woody commit {}", container_id) }));
+    }
+
+    Ok(serde_json::to_vec(&config)?)
+}
@@ -1,14 +1,18 @@
 use std::ffi::CString;
 
-use nix::{sched::CloneFlags, unistd::ForkResult};
-#[allow(unused)]
-use crate::{cgroups::CgroupManager, ActionResult};
+use anyhow::{bail, Context};
+use nix::{sched::CloneFlags, sys::wait::WaitStatus, unistd::ForkResult};
+use tracing::{debug, error};
+use crate::{exit_code, mounts, ActionResult};
 
 #[derive(Debug)]
 pub struct ContainerConfig {
     pub command: Vec<String>,
     pub args: Vec<String>,
     pub rootfs: String,
+    /// `--bind-host-bins`: bind the host's /bin, /lib, ... over the rootfs's own
+    pub bind_host_bins: bool,
+    pub hostname: String,
 }
 
 pub struct Container {
@@ -22,190 +26,107 @@ impl Container {
 
     /// Create / Await child container proccess
     ///
-    pub fn run(&self) {
-        match unsafe { nix::unistd::fork().expect("Error forking new child process") } {
+    pub fn run(&self) -> ActionResult {
+        match unsafe { nix::unistd::fork() }.context("Error forking new child process")? {
             ForkResult::Parent { child } => {
-                nix::sys::wait::waitpid(child, None).expect("Error waiting for child");
+                match nix::sys::wait::waitpid(child, None).context("Error waiting for child")? {
+                    WaitStatus::Exited(_, 0) => Ok(()),
+                    WaitStatus::Exited(_, code) => bail!("Container exited with status {}", code),
+                    status => bail!("Container terminated abnormally: {:?}", status),
+                }
             }
             ForkResult::Child => {
-                self.setup_container();
-                self.exec_command();
-                std::process::exit(0);
+                if let Err(e) = self.setup_namespaces() {
+                    error!("{:#}", e);
+                    std::process::exit(1);
+                }
+
+                // unshare(CLONE_NEWPID) only applies to children, fork again to become the
+                // namespace's init so the /proc mounted below shows the container's pids
+                match unsafe { nix::unistd::fork() } {
+                    Ok(ForkResult::Parent { child }) => {
+                        let code = nix::sys::wait::waitpid(child, None).map_or(1, exit_code);
+                        std::process::exit(code);
+                    }
+                    Ok(ForkResult::Child) => {
+                        // Only reached if something failed, the exit code is all the parent gets
+                        if let Err(e) = self.setup_container().and_then(|_| self.exec_command()) {
+                            error!("{:#}", e);
+                        }
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        error!("Error forking into the pid namespace: {}", e);
+                        std::process::exit(1);
+                    }
+                }
             }
         }
     }
 
-
-    /// Unshare, setup fs and hostname for newly decoupled process
+    /// Detach from the host's namespaces, the pid one takes effect for our next child
     ///
-    fn setup_container(&self) {
+    fn setup_namespaces(&self) -> ActionResult {
         /* ensure new process is completely isolated */
         let flags = CloneFlags::CLONE_NEWNS
                                 | CloneFlags::CLONE_NEWUTS
                                 | CloneFlags::CLONE_NEWIPC
-                                | CloneFlags::CLONE_NEWNET;
+                                | CloneFlags::CLONE_NEWNET
+                                | CloneFlags::CLONE_NEWPID;
 
         /* apply parent process unbound */
-        nix::sched::unshare(flags).expect("Could not unshare container process");
-
-        /* mount fs */
-        self.setup_filesystem().expect("Could not setup fs");
+        nix::sched::unshare(flags).context("Could not unshare container process")?;
+        mounts::make_root_private()?;
 
-        /* define hostname */
-        self.setup_hostname().expect("Could not set hostname");
+        Ok(())
     }
 
 
-    fn setup_filesystem(&self) -> ActionResult {
-        use std::path::Path;
+    /// Setup fs and hostname for newly decoupled process
+    ///
+    fn setup_container(&self) -> ActionResult {
+        /* mount fs */
+        self.setup_filesystem().context("Could not setup fs")?;
 
-        /* mount new fs */
-        let rootfs = Path::new(&self.config.rootfs);
+        /* define hostname */
+        self.setup_hostname().context("Could not set hostname")?;
 
-        std::fs::create_dir_all(rootfs)?;
-        std::env::set_current_dir(rootfs)?;
+        Ok(())
+    }
 
-        println!("Initializing container on: {:?}", std::env::current_dir().unwrap());
 
-        /* mount essential fs */
-        self.mount_essential_fs();
-        println!("[Container]: Success on fs mount");
+    fn setup_filesystem(&self) -> ActionResult {
+        let rootfs = std::path::Path::new(&self.config.rootfs);
 
-        /* bind process' vision of OS */
-        nix::unistd::chroot(".")?;
-        println!("[Container]: Changed root");
+        std::fs::create_dir_all(rootfs)
+            .with_context(|| format!("Could not create rootfs {}", rootfs.display()))?;
+        debug!("Initializing container on: {:?}", rootfs.canonicalize()?);
 
-        Ok(())
+        let opts = mounts::RootfsOptions { bind_host_bins: self.config.bind_host_bins, ..Default::default() };
+        mounts::setup_rootfs(rootfs, &opts)
     }
 
     fn setup_hostname(&self) -> ActionResult {
-        nix::unistd::sethostname("woody_container")?;
+        nix::unistd::sethostname(&self.config.hostname)?;
 
         Ok(())
     }
 
-    fn exec_command(&self) {
-        let program = CString::new(self.config.command[0].clone()).unwrap();
-        let mut args: Vec<CString> = vec![program.clone()]; 
+    /// Only returns if the exec failed
+    fn exec_command(&self) -> ActionResult {
+        let command = self.config.command.first().context("No command to run")?;
+        let program = CString::new(command.as_str())?;
+        let mut args: Vec<CString> = vec![program.clone()];
 
-        let additional_args: Vec<CString> = self.config.args
+        let additional_args = self.config.args
             .iter()
-            .map(|arg| CString::new(arg.clone()).unwrap())
-            .collect();
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
 
         args.extend(additional_args);
 
-        println!("[Container] Executing internal command...");
-        nix::unistd::execv(&program, &args).expect("Could not execve");
-    }
-
-    fn mount_essential_fs(&self) {
-        use nix::mount::{mount, MsFlags};
-        use nix::sys::stat::{mknod, Mode, SFlag};
-        use std::fs::{create_dir_all as cd};
-
-        
-        /* create dir if they do not exist */
-        let dirs: Vec<&str> = vec![
-            "./proc",
-            "./sys",
-            "./dev",
-            "./tmp",
-            "./bin",
-            "./usr/bin",
-            "./usr/lib",
-            "./usr/lib64",
-            "./lib",
-            "./lib64",
-        ];
-
-        for dir in dirs {
-            cd(dir).expect("Could not create essential dir [{dir}]");
-        };
-
-        // proc
-        mount(
-            None::<&str>,
-            "./proc",
-            Some("proc"),
-            MsFlags::empty(),
-            None::<&str>
-        ).expect("Could not mount proc");
-
-        // sys
-        mount(
-            None::<&str>,
-            "./sys",
-            Some("sysfs"),
-            MsFlags::empty(),
-            None::<&str>
-        ).expect("Could not mount sys");
-
-        // dev
-        mount(
-            None::<&str>,
-            "./dev",
-            Some("tmpfs"),
-            MsFlags::empty(),
-            Some("mode=0755,size=65536k")
-        ).expect("Could not mount dev");
-
-        mknod(
-            "./dev/null",
-            SFlag::S_IFCHR,
-            Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IWGRP | Mode::S_IROTH | Mode::S_IWOTH,
-            nix::sys::stat::makedev(1, 3),
-        ).expect("Could not create /dev/null");
-
-        // bin
-        mount(
-            Some("/bin"),
-            "./bin",
-            None::<&str>,
-            MsFlags::MS_BIND,
-            None::<&str>
-        ).expect("Could not mount bin");
-
-        // Bind mount /usr/bin
-        mount(
-            Some("/usr/bin"),
-            "./usr/bin",
-            None::<&str>,
-            MsFlags::MS_BIND,
-            None::<&str>
-        ).expect("Could not mount usr/bin");
-
-        // Bind mount /lib and /lib64 for shared libraries
-        mount(
-            Some("/lib"),
-            "./lib",
-            None::<&str>,
-            MsFlags::MS_BIND,
-            None::<&str>
-        ).expect("Could not mount lib");
-
-        mount(
-            Some("/lib64"),
-            "./lib64",
-            None::<&str>,
-            MsFlags::MS_BIND,
-            None::<&str>
-        ).expect("Could not mount lib64");
-
-        mount(
-            Some("/usr/lib"),
-            "./usr/lib",
-            None::<&str>,
-            MsFlags::MS_BIND,
-            None::<&str>
-        ).expect("Could not mount usr/lib");
-
-        mount(
-            Some("/usr/lib64"),
-            "./usr/lib64",
-            None::<&str>,
-            MsFlags::MS_BIND,
-            None::<&str>
-        ).expect("Could not mount usr/lib64");
+        debug!("Executing internal command...");
+        let Err(e) = nix::unistd::execv(&program, &args);
+        Err(e).with_context(|| format!("Could not execve {}", command))
     }
 }
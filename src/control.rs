@@ -0,0 +1,117 @@
+use std::{fs, thread, time::{Duration, Instant}};
+
+use anyhow::{bail, Context};
+use nix::{errno::Errno, sys::{signal::{kill, Signal}, wait::WaitStatus}, unistd::Pid};
+use tracing::{info, warn};
+
+use crate::{mounts, paths, state::{ContainerState, Status}};
+
+/// The container's stop signal, SIGTERM unless its image or `--stop-signal` said
+/// otherwise, then SIGKILL if the container is still around after `grace`
+pub fn stop_container(id: &str, grace: Duration) -> anyhow::Result<()> {
+    ContainerState::request_stop(id)?;
+    let state = ContainerState::load(id)?;
+    let pid = Pid::from_raw(state.pid);
+
+    if !is_alive(pid) {
+        info!("Container {} is not running", id);
+        return ContainerState::set_status(id, Status::Stopped);
+    }
+
+    let signal = state.stop_signal.as_deref().map(parse_signal).transpose()?.unwrap_or(Signal::SIGTERM);
+    info!("Sending {} to container {} (PID {})", signal, id, pid);
+    if send_signal(pid, signal)? {
+        let deadline = Instant::now() + grace;
+        while is_alive(pid) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        if is_alive(pid) {
+            warn!("Grace period of {}s expired, sending SIGKILL", grace.as_secs());
+            send_signal(pid, Signal::SIGKILL)?;
+            return ContainerState::set_status(id, Status::Killed);
+        }
+    }
+
+    ContainerState::set_status(id, Status::Stopped)
+}
+
+pub fn kill_container(id: &str, signal: Signal) -> anyhow::Result<()> {
+    ContainerState::request_stop(id)?;
+    let state = ContainerState::load(id)?;
+    let pid = Pid::from_raw(state.pid);
+
+    if !is_alive(pid) || !send_signal(pid, signal)? {
+        info!("Container {} is not running", id);
+        return ContainerState::set_status(id, Status::Stopped);
+    }
+
+    info!("Sent {} to container {} (PID {})", signal, id, pid);
+    ContainerState::set_status(id, Status::Killed)
+}
+
+/// Delete a container's directory, a running container is only killed first with `force`
+pub fn remove_container(id: &str, force: bool) -> anyhow::Result<()> {
+    let state = ContainerState::load(id)?;
+    let pid = Pid::from_raw(state.pid);
+
+    if is_alive(pid) {
+        if !force {
+            bail!("Container {} is running, stop it first or use --force", id);
+        }
+
+        send_signal(pid, Signal::SIGKILL)?;
+        // Its supervisor unmounts on the way out, give it a moment
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while is_alive(pid) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    mounts::remove_dir_all(&paths::container_dir(id))?;
+    println!("{}", id);
+
+    Ok(())
+}
+
+/// Accepts `SIGKILL`, `KILL` or a raw signal number
+pub fn parse_signal(name: &str) -> anyhow::Result<Signal> {
+    if let Ok(num) = name.parse::<i32>() {
+        return Signal::try_from(num).with_context(|| format!("Invalid signal number: {}", num));
+    }
+
+    let name = name.to_uppercase();
+    let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+
+    name.parse::<Signal>().with_context(|| format!("Unknown signal: {}", name))
+}
+
+/// Returns false if the process was already gone
+pub(crate) fn send_signal(pid: Pid, signal: Signal) -> anyhow::Result<bool> {
+    match kill(pid, signal) {
+        Ok(()) => Ok(true),
+        Err(Errno::ESRCH) => Ok(false),
+        Err(e) => Err(e).context(format!("Failed to send {} to PID {}", signal, pid)),
+    }
+}
+
+/// Shell convention: the exit status, or 128 + signal number
+pub fn exit_code(status: WaitStatus) -> i32 {
+    match status {
+        WaitStatus::Exited(_, code) => code,
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _ => 1,
+    }
+}
+
+/// Zombies still accept signals, so check the /proc state as well
+pub fn is_alive(pid: Pid) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // The state field follows the parenthesized command name
+        Ok(stat) => stat.rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .map(|state| state != "Z" && state != "X")
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
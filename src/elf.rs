@@ -0,0 +1,99 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// `PT_INTERP`, the program header naming the dynamic linker
+const PT_INTERP: u32 = 3;
+
+/// What the kernel loads along with an executable
+#[derive(Debug, PartialEq)]
+pub enum Interpreter {
+    /// `PT_INTERP` of a dynamically linked ELF binary
+    DynamicLinker(PathBuf),
+    /// The program on a script's `#!` line
+    Shebang(PathBuf),
+}
+
+/// The interpreter `path` needs, `None` for a static binary or a file that is neither
+pub fn interpreter(path: &Path) -> io::Result<Option<Interpreter>> {
+    let mut file = fs::File::open(path)?;
+    let mut ident = [0u8; 16];
+    let read = file.read(&mut ident)?;
+
+    match &ident[..read] {
+        [0x7f, b'E', b'L', b'F', ..] if read == ident.len() => {
+            Ok(elf_interpreter(&mut file, &ident)?.map(Interpreter::DynamicLinker))
+        }
+        [b'#', b'!', ..] => {
+            file.seek(SeekFrom::Start(0))?;
+            let mut line = Vec::new();
+            file.take(256).read_to_end(&mut line)?;
+            Ok(shebang_interpreter(&line).map(Interpreter::Shebang))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn elf_interpreter(file: &mut fs::File, ident: &[u8; 16]) -> io::Result<Option<PathBuf>> {
+    let is_64 = ident[4] == 2;
+    let big_endian = ident[5] == 2;
+    let uint = |bytes: &[u8]| -> u64 {
+        let shift = |value: u64, byte: &u8| value << 8 | *byte as u64;
+        if big_endian { bytes.iter().fold(0, shift) } else { bytes.iter().rev().fold(0, shift) }
+    };
+
+    // Where the program headers are, and how many of what size
+    let mut header = [0u8; 64];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header[..if is_64 { 64 } else { 52 }])?;
+    let (phoff, phentsize, phnum) = if is_64 {
+        (uint(&header[32..40]), uint(&header[54..56]), uint(&header[56..58]))
+    } else {
+        (uint(&header[28..32]), uint(&header[42..44]), uint(&header[44..46]))
+    };
+
+    // Too small to hold the fields read below
+    if phentsize < if is_64 { 40 } else { 20 } {
+        return Ok(None);
+    }
+
+    let mut entry = vec![0u8; phentsize as usize];
+    for i in 0..phnum {
+        file.seek(SeekFrom::Start(phoff + i * phentsize))?;
+        file.read_exact(&mut entry)?;
+        if uint(&entry[0..4]) as u32 != PT_INTERP {
+            continue;
+        }
+
+        let (offset, size) = if is_64 {
+            (uint(&entry[8..16]), uint(&entry[32..40]))
+        } else {
+            (uint(&entry[4..8]), uint(&entry[16..20]))
+        };
+        // Only ever a path, anything longer is a corrupt header
+        if size > 4096 {
+            return Ok(None);
+        }
+
+        let mut path = vec![0u8; size as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut path)?;
+        let path = path.split(|&b| b == 0).next().unwrap_or_default();
+        return Ok(Some(PathBuf::from(OsStr::from_bytes(path))));
+    }
+
+    Ok(None)
+}
+
+/// `/bin/sh` of `#!/bin/sh -e`
+fn shebang_interpreter(line: &[u8]) -> Option<PathBuf> {
+    let line = line.strip_prefix(b"#!")?;
+    let line = line.split(|&b| b == b'\n').next()?;
+    let program = line.split(|&b| b == b' ' || b == b'\t').find(|part| !part.is_empty())?;
+
+    Some(PathBuf::from(OsStr::from_bytes(program)))
+}
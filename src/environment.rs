@@ -0,0 +1,120 @@
+use std::{ffi::CString, fs, path::Path};
+
+use anyhow::{bail, Context};
+use tracing::warn;
+
+/// What docker gives a container whose image sets no PATH
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// A `-e` value: `KEY=VALUE`, or a bare `KEY` copied from woody's own environment
+pub fn parse_var(spec: &str) -> anyhow::Result<Option<String>> {
+    if spec.is_empty() || spec.starts_with('=') {
+        bail!("Invalid environment variable {:?}, expected KEY=VALUE", spec);
+    }
+    // execve takes NUL terminated strings, there's no way to pass one on
+    if spec.contains('\0') {
+        bail!("Invalid environment variable {:?}, it contains a NUL byte", spec);
+    }
+
+    if spec.contains('=') {
+        return Ok(Some(spec.to_string()));
+    }
+
+    // Like docker, an unset bare key is dropped instead of set to empty
+    Ok(std::env::var(spec).ok().map(|value| format!("{}={}", spec, value)))
+}
+
+/// `KEY=VALUE` lines, blank lines and `#` comments are skipped
+pub fn parse_env_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read env file {}", path.display()))?;
+
+    let mut vars = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let var = parse_var(line)
+            .with_context(|| format!("{}:{}", path.display(), number + 1))?;
+        vars.extend(var);
+    }
+
+    Ok(vars)
+}
+
+/// Image env with the CLI's variables layered on top, a later key replaces an earlier one in place
+pub fn merge(image: &[String], overrides: &[String]) -> Vec<String> {
+    let mut merged: Vec<String> = Vec::with_capacity(image.len() + overrides.len());
+
+    for var in image.iter().chain(overrides) {
+        let name = key(var);
+        match merged.iter_mut().find(|existing| key(existing) == name) {
+            Some(existing) => *existing = var.clone(),
+            None => merged.push(var.clone()),
+        }
+    }
+
+    merged
+}
+
+fn key(var: &str) -> &str {
+    var.split_once('=').map_or(var, |(key, _)| key)
+}
+
+/// `env` as execve takes it. What can't be passed on, a NUL byte or no `KEY=`, is
+/// dropped with a warning: flags are checked by [`parse_var`], so that's image env.
+pub fn to_exec(env: &[String]) -> Vec<CString> {
+    env.iter()
+        .filter_map(|var| {
+            if !var.contains('=') || var.starts_with('=') {
+                warn!("Dropping environment variable {:?}, expected KEY=VALUE", var);
+                return None;
+            }
+            CString::new(var.as_bytes())
+                .map_err(|_| warn!("Dropping environment variable {:?}, it contains a NUL byte", var))
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_nul_bytes_in_flags() {
+        let err = parse_var("KEY=va\0lue").unwrap_err();
+        assert!(err.to_string().contains("NUL byte"), "{}", err);
+        assert!(parse_var("KEY\0=value").is_err());
+    }
+
+    #[test]
+    fn drops_entries_execve_cant_take() {
+        let env = vec![
+            "PATH=/bin".to_string(),
+            "BAD=a\0b".to_string(),
+            "NOVALUE".to_string(),
+            "=value".to_string(),
+            "EMPTY=".to_string(),
+        ];
+
+        let exec: Vec<String> = to_exec(&env).into_iter().map(|var| var.into_string().unwrap()).collect();
+        assert_eq!(exec, ["PATH=/bin", "EMPTY="]);
+    }
+
+    #[test]
+    fn later_keys_win_in_place() {
+        let image = vec!["PATH=/bin".to_string(), "LANG=C".to_string(), "PATH=/usr/bin".to_string()];
+        let overrides = vec!["LANG=en_US.UTF-8".to_string(), "DEBUG=1".to_string(), "DEBUG=2".to_string()];
+
+        assert_eq!(merge(&image, &overrides), ["PATH=/usr/bin", "LANG=en_US.UTF-8", "DEBUG=2"]);
+    }
+
+    #[test]
+    fn keys_match_whole_names_only() {
+        let merged = merge(&["PATH=/bin".to_string()], &["PATHS=x".to_string()]);
+        assert_eq!(merged, ["PATH=/bin", "PATHS=x"]);
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    fs,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+
+use crate::run;
+
+/// Used when the host only knows about a local stub resolver the container can't reach
+const FALLBACK_NAMESERVERS: [&str; 2] = ["8.8.8.8", "8.8.4.4"];
+
+/// Write `<root>/etc/resolv.conf`, either from `--dns` servers or derived from the host's.
+///
+/// A container on the host network can reach the host's stub resolver, it gets
+/// the host's file as it is.
+pub fn write_resolv_conf(root: &Path, dns: &[IpAddr], host_network: bool) -> anyhow::Result<()> {
+    let content = if !dns.is_empty() {
+        dns.iter().map(|ip| format!("nameserver {}\n", ip)).collect()
+    } else if host_network {
+        fs::read_to_string("/etc/resolv.conf").unwrap_or_default()
+    } else {
+        container_resolv_conf(&host_resolv_conf())
+    };
+
+    write_etc_file(root, "resolv.conf", &content)
+}
+
+pub fn write_hostname(root: &Path, hostname: &str) -> anyhow::Result<()> {
+    write_etc_file(root, "hostname", &format!("{}\n", hostname))
+}
+
+/// A line of /etc/hosts, as given by `--add-host name:ip`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostEntry {
+    pub name: String,
+    pub ip: IpAddr,
+}
+
+impl FromStr for HostEntry {
+    type Err = anyhow::Error;
+
+    /// `name:ip`, an IPv6 address may be bracketed as in `db:[::1]`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, ip) = s.split_once(':')
+            .with_context(|| format!("Invalid host entry {:?}, expected name:ip", s))?;
+        run::validate_hostname(name)?;
+
+        let ip = ip.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')).unwrap_or(ip);
+        let ip = match ip.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => bail!("Invalid IP address {:?} in host entry {:?}", ip, s),
+        };
+
+        Ok(HostEntry { name: name.to_string(), ip })
+    }
+}
+
+/// Write `<root>/etc/hosts` with localhost, `extra` entries and the container's own
+/// hostname, which resolves to `ip` or to loopback without a network.
+///
+/// Like docker it replaces the image's file, which rarely has more than localhost.
+pub fn write_hosts(root: &Path, hostname: &str, ip: Option<IpAddr>, extra: &[HostEntry]) -> anyhow::Result<()> {
+    let mut content = String::from("127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n");
+    for entry in extra {
+        content.push_str(&format!("{}\t{}\n", entry.ip, entry.name));
+    }
+    content.push_str(&format!("{}\t{}\n", ip.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)), hostname));
+
+    write_etc_file(root, "hosts", &content)
+}
+
+/// systemd-resolved hosts point at 127.0.0.53, the real upstreams live in its own file
+fn host_resolv_conf() -> String {
+    ["/etc/resolv.conf", "/run/systemd/resolve/resolv.conf"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find(|content| content.lines().any(is_usable_nameserver))
+        .unwrap_or_default()
+}
+
+/// Drop loopback nameservers, they point at the host's stub resolver, not the container's
+fn container_resolv_conf(host: &str) -> String {
+    let mut content: String = host.lines()
+        .filter(|line| !is_nameserver(line) || is_usable_nameserver(line))
+        .map(|line| format!("{}\n", line))
+        .collect();
+
+    if !host.lines().any(is_usable_nameserver) {
+        for server in FALLBACK_NAMESERVERS {
+            content.push_str(&format!("nameserver {}\n", server));
+        }
+    }
+
+    content
+}
+
+fn is_nameserver(line: &str) -> bool {
+    line.trim_start().starts_with("nameserver")
+}
+
+fn is_usable_nameserver(line: &str) -> bool {
+    line.trim_start()
+        .strip_prefix("nameserver")
+        .and_then(|server| server.trim().parse::<IpAddr>().ok())
+        .is_some_and(|ip| !ip.is_loopback())
+}
+
+/// Runs on the host before the container is chrooted into `root`, which the image
+/// controls: nothing on the way to the file may be a symlink, it would resolve on the host.
+fn write_etc_file(root: &Path, name: &str, content: &str) -> anyhow::Result<()> {
+    let etc = root.join("etc");
+    match etc.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => bail!("The image's /etc is not a directory, refusing to write {}", name),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&etc)?,
+        Err(e) => return Err(e).with_context(|| format!("Failed to inspect {}", etc.display())),
+    }
+
+    let path = etc.join(name);
+    // Images often ship these as symlinks to the host-managed location, replace them
+    if path.is_symlink() {
+        fs::remove_file(&path)?;
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(content.as_bytes()).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlinked_etc_is_refused() {
+        let tmp = std::env::temp_dir().join(format!("woody-etc-{}", std::process::id()));
+        let (root, host_etc) = (tmp.join("root"), tmp.join("host-etc"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&host_etc).unwrap();
+        std::os::unix::fs::symlink(&host_etc, root.join("etc")).unwrap();
+
+        let written = write_hostname(&root, "web");
+        let leaked = host_etc.join("hostname").exists();
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert!(written.is_err());
+        assert!(!leaked);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{convert::Infallible, ffi::CString, fs, os::unix::{fs::PermissionsExt, io::AsRawFd}, path::Path};
+
+use anyhow::{bail, Context};
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    sched::{setns, CloneFlags},
+    sys::stat::Mode,
+    sys::wait::waitpid,
+    unistd::{chroot, close, dup2, execve, fchdir, fork, ForkResult, Pid},
+};
+use caps::CapsHashSet;
+use seccompiler::BpfProgram;
+use tracing::error;
+
+use crate::{
+    capabilities, environment, exit_code, is_alive, lrng_cgroup,
+    seccomp::{self, SeccompMode}, state::ContainerState, tty, users,
+};
+
+/// Order matters: the mount namespace goes last, joining it changes what /proc/<pid> resolves to
+const NAMESPACES: [(&str, CloneFlags); 5] = [
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+];
+
+/// Run `command` inside the namespaces and root of running container `id`, held to the
+/// same cgroup, user, capabilities and seccomp profile as its main process. Exits woody
+/// with the command's exit code.
+pub fn exec_in_container(id: &str, command: &[String], interactive: bool, tty: bool) -> anyhow::Result<()> {
+    if command.is_empty() {
+        bail!("No command given to exec");
+    }
+
+    let state = ContainerState::load(id)?;
+    let pid = Pid::from_raw(state.pid);
+    if !is_alive(pid) {
+        bail!("Container {} is not running", id);
+    }
+    let confinement = Confinement::of(&state)?;
+
+    let pty = if tty { Some(tty::open_pty()?) } else { None };
+
+    // woody's runtime has threads, which may not join a mount namespace, and a pid
+    // namespace only applies to children: everything happens in a fork
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            let run = || -> anyhow::Result<Infallible> {
+                confinement.join_cgroup()?;
+                let env = join_container(id, pid)?;
+                let program = resolve_program(&command[0], &env)?;
+                let args: Vec<CString> = command.iter()
+                    .map(|a| CString::new(a.as_bytes()))
+                    .collect::<Result<_, _>>()?;
+
+                if let Some(pty) = &pty {
+                    tty::attach_to_slave(pty)?;
+                }
+                confinement.apply()?;
+                execve(&program, &args, &env).with_context(|| format!("Failed to run {}", command[0]))
+            };
+            let Err(e) = run();
+            error!("{:#}", e);
+
+            // The forked runtime must not unwind
+            unsafe { libc::_exit(127) }
+        }
+        ForkResult::Parent { child } => {
+            let mut raw_mode = None;
+            let mut output = None;
+            if let Some(pty) = &pty {
+                close(pty.slave)?;
+                raw_mode = Some(tty::RawModeGuard::enable()?);
+                tty::forward_input(pty.master, interactive)?;
+                output = Some(tty::forward_output(pty.master));
+            }
+
+            let status = loop {
+                match waitpid(child, None) {
+                    Err(Errno::EINTR) => continue,
+                    result => break result?,
+                }
+            };
+
+            if let Some(output) = output {
+                output.join().ok();
+            }
+            drop(raw_mode);
+
+            std::process::exit(exit_code(status));
+        }
+    }
+}
+
+/// What the container's main process is held to, for processes started in it later
+struct Confinement {
+    /// Name of the container's cgroup
+    cgroup: Option<String>,
+    user: Option<String>,
+    capabilities: CapsHashSet,
+    seccomp: Vec<BpfProgram>,
+}
+
+impl Confinement {
+    /// From the container's state, compiled before forking like `woody run` does
+    fn of(state: &ContainerState) -> anyhow::Result<Self> {
+        let capabilities = match &state.capabilities {
+            Some(names) => names.iter()
+                .map(|name| name.parse().with_context(|| format!("Unknown capability {} in the container's state", name)))
+                .collect::<anyhow::Result<_>>()?,
+            None => capabilities::DEFAULT_CAPS.into_iter().collect(),
+        };
+        let seccomp = match &state.seccomp {
+            Some(mode) => mode.parse()?,
+            None => SeccompMode::Default,
+        };
+
+        Ok(Confinement {
+            cgroup: state.cgroup.clone(),
+            user: state.user.clone(),
+            seccomp: seccomp::compile(&seccomp, &capabilities)?,
+            capabilities,
+        })
+    }
+
+    /// Move the calling process into the container's cgroup, while the host's /sys is still
+    /// in reach. It goes into the leaf next to the main process, the cgroup itself has
+    /// controllers enabled for its children and can't hold processes.
+    fn join_cgroup(&self) -> anyhow::Result<()> {
+        if let Some(name) = &self.cgroup {
+            lrng_cgroup::CgroupManager::new()
+                .and_then(|cgroups| cgroups.get_cgroup(name, None))
+                .and_then(|cgroup| cgroup.add_current_process())
+                .with_context(|| format!("Failed to join cgroup {}", name))?;
+        }
+        Ok(())
+    }
+
+    /// Become the container's user with its capabilities and seccomp filters, after
+    /// [`join_container`] and right before exec
+    fn apply(&self) -> anyhow::Result<()> {
+        // Looked up in the container's own passwd and group files, like for its main process
+        let user = self.user.as_deref().map(|spec| users::resolve(spec, Path::new("/"))).transpose()?;
+        capabilities::drop_capabilities(&self.capabilities, user.as_ref())?;
+        seccomp::apply(&self.seccomp)
+    }
+}
+
+/// Move woody into the namespaces and root of container `id`, whose process is `pid`,
+/// returning the environment its process got.
+///
+/// Joining a mount namespace needs a single threaded process, callers with threads
+/// have to fork first.
+fn join_container(id: &str, pid: Pid) -> anyhow::Result<Vec<CString>> {
+    // Everything under /proc/<pid> has to be opened before the first setns
+    let mut namespaces = Vec::new();
+    for (name, flag) in NAMESPACES {
+        // Joining our own namespace is pointless and fails for some types
+        let ours = fs::read_link(format!("/proc/self/ns/{}", name))?;
+        let theirs = fs::read_link(format!("/proc/{}/ns/{}", pid, name))?;
+        if ours != theirs {
+            let file = fs::File::open(format!("/proc/{}/ns/{}", pid, name))
+                .with_context(|| format!("Failed to open {} namespace of container {}", name, id))?;
+            namespaces.push((file, flag));
+        }
+    }
+    let root = fs::File::open(format!("/proc/{}/root", pid)).context("Failed to open container root")?;
+    let env = read_environ(pid)?;
+
+    for (file, flag) in &namespaces {
+        setns(file.as_raw_fd(), *flag).with_context(|| format!("Failed to join {:?}", flag))?;
+    }
+
+    // The container was chrooted, joining its mount namespace alone lands at the real root
+    fchdir(root.as_raw_fd())?;
+    chroot(".")?;
+
+    Ok(env)
+}
+
+/// Start `command` inside running container `id` with its stdio on /dev/null, for
+/// checks that only care about the exit code. Confined like [`exec_in_container`].
+/// Returns the pid to wait for.
+pub fn spawn_in_container(id: &str, pid: Pid, command: &[String]) -> anyhow::Result<Pid> {
+    if command.is_empty() {
+        bail!("No command given to run in container {}", id);
+    }
+    let confinement = Confinement::of(&ContainerState::load(id)?)?;
+
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            let run = || -> anyhow::Result<Infallible> {
+                let null = open("/dev/null", OFlag::O_RDWR, Mode::empty())?;
+                for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                    dup2(null, fd)?;
+                }
+                close(null)?;
+
+                confinement.join_cgroup()?;
+                let env = join_container(id, pid)?;
+                let program = resolve_program(&command[0], &env)?;
+                let args: Vec<CString> = command.iter()
+                    .map(|a| CString::new(a.as_bytes()))
+                    .collect::<Result<_, _>>()?;
+                confinement.apply()?;
+                Ok(execve(&program, &args, &env)?)
+            };
+            run().ok();
+
+            // Only the exit code is looked at, and the forked runtime must not unwind
+            unsafe { libc::_exit(127) }
+        }
+        ForkResult::Parent { child } => Ok(child),
+    }
+}
+
+/// Run the command with the same environment the container's main process got
+fn read_environ(pid: Pid) -> anyhow::Result<Vec<CString>> {
+    let environ = fs::read(format!("/proc/{}/environ", pid)).context("Failed to read container environment")?;
+
+    Ok(environ.split(|&b| b == 0)
+        .filter(|var| !var.is_empty())
+        .filter_map(|var| CString::new(var).ok())
+        .collect())
+}
+
+/// execve doesn't search PATH, and it must be the container's PATH, not ours
+fn resolve_program(program: &str, env: &[CString]) -> anyhow::Result<CString> {
+    if program.contains('/') {
+        return Ok(CString::new(program)?);
+    }
+
+    let path = env.iter()
+        .filter_map(|var| var.to_str().ok())
+        .find_map(|var| var.strip_prefix("PATH="))
+        .unwrap_or(environment::DEFAULT_PATH);
+
+    for dir in path.split(':') {
+        let candidate = Path::new(dir).join(program);
+        let executable = fs::metadata(&candidate)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+
+        if executable {
+            return Ok(CString::new(candidate.to_string_lossy().as_bytes())?);
+        }
+    }
+
+    bail!("{}: executable not found in the container's PATH", program)
+}
@@ -0,0 +1,144 @@
+use std::{fs, io::{self, Write}, os::unix::fs::FileTypeExt, path::{Path, PathBuf}, thread};
+
+use anyhow::{bail, Context};
+use nix::unistd::{isatty, Pid};
+use tracing::{debug, info};
+
+use crate::{images, is_alive, layers, mounts, paths, state::ContainerState, LocalImage};
+
+/// Pseudo filesystems mounted at runtime, exported as empty directories
+const SKIPPED_DIRS: [&str; 3] = ["proc", "sys", "dev"];
+
+/// Write the container's filesystem as a tar archive to `output`, `-` for stdout.
+///
+/// A running container is read through its root in /proc, a stopped one through
+/// a temporary read-only overlay of its upper dir on top of the image.
+pub fn export_container(id: &str, output: &Path) -> anyhow::Result<()> {
+    let state = ContainerState::load(id)?;
+    info!("Exporting container {}", id);
+
+    let writer: Box<dyn Write> = if output == Path::new("-") {
+        if isatty(libc::STDOUT_FILENO).unwrap_or(false) {
+            bail!("Refusing to write a tar archive to a terminal, redirect stdout or use -o <file>");
+        }
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(fs::File::create(output).with_context(|| format!("Failed to create {}", output.display()))?)
+    };
+
+    if is_alive(Pid::from_raw(state.pid)) {
+        let root = PathBuf::from(format!("/proc/{}/root", state.pid));
+        return write_archive(&root, writer);
+    }
+
+    let image_id = state.image_id.as_deref()
+        .with_context(|| format!("Container {} has no stored image, it predates image ids or ran a --rootfs", id))?;
+    let layers_path = images::image_path(&paths::images_dir(), image_id).join("layers");
+    if !layers_path.exists() {
+        bail!("Image {} of container {} is no longer stored", state.image, id);
+    }
+
+    let container_root = paths::container_dir(id);
+    let view = container_root.join("export");
+    fs::create_dir_all(&view)?;
+    layers::mount_readonly(&layers_path, &container_root.join("upper"), &view)?;
+
+    let result = write_archive(&view, writer);
+    mounts::remove_dir_all(&view)?;
+
+    result
+}
+
+/// Copy `image`'s filesystem into the new or empty directory `dest`, and write its
+/// entrypoint, cmd, env and working dir as JSON to `<dest>.json`, which is returned.
+///
+/// The layers are flattened through a read-only overlay, so whiteouts are applied
+/// the same way a container would see them.
+pub fn unpack_image(image: &LocalImage, dest: &Path) -> anyhow::Result<PathBuf> {
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        bail!("{} is not empty", dest.display());
+    }
+    fs::create_dir_all(dest).with_context(|| format!("Failed to create {}", dest.display()))?;
+
+    let scratch = std::env::temp_dir().join(format!("woody-unpack-{}", std::process::id()));
+    let (upper, view) = (scratch.join("upper"), scratch.join("view"));
+    fs::create_dir_all(&upper)?;
+    fs::create_dir_all(&view)?;
+    layers::mount_readonly(&image.layers_path, &upper, &view)?;
+
+    let result = copy_tree(&view, dest);
+    mounts::remove_dir_all(&scratch)?;
+    result?;
+
+    // components() drops a trailing slash, `rootfs/` becomes `rootfs.json`
+    let mut config_path = dest.components().as_path().as_os_str().to_owned();
+    config_path.push(".json");
+    let config_path = PathBuf::from(config_path);
+    fs::write(&config_path, serde_json::to_string_pretty(&image.config.config)?)
+        .with_context(|| format!("Failed to write {}", config_path.display()))?;
+
+    Ok(config_path)
+}
+
+/// Copy `from` into `to` keeping ownership, modes, links and device nodes, by
+/// streaming it through tar
+fn copy_tree(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let (reader, writer) = io::pipe()?;
+    let root = from.to_path_buf();
+    let archiver = thread::spawn(move || write_archive(&root, writer));
+
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    let unpacked = archive.unpack(to).with_context(|| format!("Failed to unpack into {}", to.display()));
+    // Unblocks the archiver if unpacking stopped half way
+    drop(archive);
+
+    let archived = archiver.join().map_err(|_| anyhow::anyhow!("Archiving {} panicked", from.display()))?;
+    unpacked.and(archived)
+}
+
+fn write_archive<W: Write>(root: &Path, writer: W) -> anyhow::Result<()> {
+    debug!("Archiving {}", root.display());
+
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    append_dir(&mut builder, root, Path::new(""))?;
+    builder.into_inner()?.flush()?;
+
+    Ok(())
+}
+
+/// Add the contents of `dir` under the archive path `prefix`, recursively
+fn append_dir<W: Write>(builder: &mut tar::Builder<W>, dir: &Path, prefix: &Path) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    // Stable archives for the same filesystem
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = prefix.join(entry.file_name());
+
+        // tar has no representation for them, docker export leaves them out too
+        if entry.file_type()?.is_socket() {
+            debug!("Skipping socket /{}", name.display());
+            continue;
+        }
+
+        builder.append_path_with_name(&path, &name)
+            .with_context(|| format!("Failed to archive {}", path.display()))?;
+
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if prefix.as_os_str().is_empty() && SKIPPED_DIRS.iter().any(|skipped| entry.file_name() == *skipped) {
+            debug!("Skipping the contents of /{}", name.display());
+            continue;
+        }
+        append_dir(builder, &path, &name)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use std::{
+    fmt,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    sys::{signal::{kill, Signal}, wait::{waitpid, WaitPidFlag, WaitStatus}},
+    unistd::Pid,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{exec, registry::Healthcheck, state::ContainerState};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_RETRIES: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    /// No check has passed or failed often enough yet
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Health::Starting => "starting",
+            Health::Healthy => "healthy",
+            Health::Unhealthy => "unhealthy",
+        };
+        f.write_str(name)
+    }
+}
+
+/// `--health-*` flags, each overriding the image's HEALTHCHECK
+#[derive(Debug, Clone, Default)]
+pub struct HealthOptions {
+    /// Run with `/bin/sh -c`, like `CMD-SHELL`
+    pub cmd: Option<String>,
+    pub interval: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub retries: Option<u32>,
+    /// `--no-healthcheck`, ignore the image's
+    pub disabled: bool,
+}
+
+/// A container's health command and how often it runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub command: Vec<String>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Failures in a row before the container is unhealthy
+    pub retries: u32,
+}
+
+/// The check a container gets from its image and the flags, if any
+pub fn resolve(image: Option<&Healthcheck>, opts: &HealthOptions) -> Option<HealthCheck> {
+    if opts.disabled {
+        return None;
+    }
+
+    let command = match &opts.cmd {
+        Some(cmd) => vec!["/bin/sh".to_string(), "-c".to_string(), cmd.clone()],
+        None => match image.map(|check| check.test.as_slice()) {
+            Some([kind, argv @ ..]) if kind == "CMD" && !argv.is_empty() => argv.to_vec(),
+            Some([kind, line]) if kind == "CMD-SHELL" => vec!["/bin/sh".to_string(), "-c".to_string(), line.clone()],
+            _ => return None,
+        },
+    };
+
+    let nanos = |value: Option<u64>| value.filter(|nanos| *nanos > 0).map(Duration::from_nanos);
+    Some(HealthCheck {
+        command,
+        interval: opts.interval.or(nanos(image.map(|check| check.interval))).unwrap_or(DEFAULT_INTERVAL),
+        timeout: opts.timeout.or(nanos(image.map(|check| check.timeout))).unwrap_or(DEFAULT_TIMEOUT),
+        retries: opts.retries.or(image.map(|check| check.retries).filter(|retries| *retries > 0)).unwrap_or(DEFAULT_RETRIES),
+    })
+}
+
+/// Runs a container's check in the background and records the outcome in its state
+pub struct Monitor {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Monitor {
+    /// First check after one interval, the container is `starting` until then
+    pub fn start(container_id: &str, pid: Pid, check: HealthCheck) -> Monitor {
+        let (stop, stopped) = mpsc::channel();
+        let container_id = container_id.to_string();
+
+        let thread = thread::spawn(move || {
+            let mut failures = 0;
+            let mut health = Health::Starting;
+
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(check.interval) {
+                let next = if run_check(&container_id, pid, &check) {
+                    failures = 0;
+                    Health::Healthy
+                } else {
+                    failures += 1;
+                    if failures >= check.retries { Health::Unhealthy } else { health }
+                };
+
+                if next != health {
+                    info!("Container {} is {}", container_id, next);
+                    health = next;
+                    if let Err(e) = ContainerState::set_health(&container_id, health) {
+                        warn!("Failed to record health of container {}: {:#}", container_id, e);
+                    }
+                }
+            }
+        });
+
+        Monitor { stop, thread }
+    }
+
+    /// Wait for a check that is running to finish, then stop checking
+    pub fn stop(self) {
+        drop(self.stop);
+        self.thread.join().ok();
+    }
+}
+
+/// Whether the command exited 0 within the timeout, one that didn't is killed
+fn run_check(container_id: &str, pid: Pid, check: &HealthCheck) -> bool {
+    let child = match exec::spawn_in_container(container_id, pid, &check.command) {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to run the health check of container {}: {:#}", container_id, e);
+            return false;
+        }
+    };
+
+    let deadline = Instant::now() + check.timeout;
+    loop {
+        match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::EINTR) => {}
+            Ok(WaitStatus::Exited(_, code)) => return code == 0,
+            Ok(_) | Err(_) => return false,
+        }
+
+        if Instant::now() >= deadline {
+            info!("Health check of container {} timed out after {:?}", container_id, check.timeout);
+            kill(child, Signal::SIGKILL).ok();
+            waitpid(child, None).ok();
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,254 @@
+use std::{fs, path::PathBuf, sync::atomic::{AtomicBool, Ordering}, time::{Duration, SystemTime}};
+
+use anyhow::{bail, Context};
+use reqwest::{header::RETRY_AFTER, Certificate, ClientBuilder, NoProxy, Proxy, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Docker Hub can ask for hours once the pull limit is used up, that's better reported than slept through
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+/// Warn once fewer pulls than this share of the limit are left
+const RATE_LIMIT_WARN_PERCENT: u64 = 10;
+
+/// Error body of the registry API, `{"errors": [{"code": ..., "message": ...}]}`
+#[derive(Deserialize, Debug)]
+struct ErrorBody {
+    #[serde(default)]
+    errors: Vec<RegistryError>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RegistryError {
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+/// How registry certificates are verified
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// PEM files of root certificates trusted on top of the system's
+    pub ca_certs: Vec<PathBuf>,
+    /// `host` or `host:port` of registries whose certificates aren't verified at all
+    pub insecure_hosts: Vec<String>,
+}
+
+/// reqwest client that gives up on stalled connections and retries transient failures
+pub struct HttpClient {
+    inner: reqwest::Client,
+    /// For [`TlsOptions::insecure_hosts`], `None` if there are none
+    insecure: Option<reqwest::Client>,
+    insecure_hosts: Vec<String>,
+    max_retries: u32,
+    /// The rate limit warning is shown once per client
+    warned_rate_limit: AtomicBool,
+}
+
+impl HttpClient {
+    /// Without `proxy`, `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY` are used like
+    /// curl does. `NO_PROXY` exempts hosts from either.
+    pub fn new(timeout: Duration, max_retries: u32, proxy: Option<&str>, tls: &TlsOptions) -> anyhow::Result<Self> {
+        let mut certs = Vec::new();
+        for path in &tls.ca_certs {
+            let pem = fs::read(path).with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+            let bundle = Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid PEM certificate in {}", path.display()))?;
+            if bundle.is_empty() {
+                bail!("No PEM certificate in {}", path.display());
+            }
+            certs.extend(bundle);
+        }
+
+        let builder = || -> anyhow::Result<ClientBuilder> {
+            let mut builder = reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(timeout);
+            if let Some(proxy) = proxy {
+                builder = builder.proxy(Proxy::all(proxy)
+                    .with_context(|| format!("Invalid proxy URL {}", proxy))?
+                    .no_proxy(NoProxy::from_env()));
+            }
+            for cert in &certs {
+                builder = builder.add_root_certificate(cert.clone());
+            }
+            Ok(builder)
+        };
+
+        if let Some(proxy) = proxy {
+            debug!(proxy, "Sending registry requests through a proxy");
+        }
+        let inner = builder()?.build().context("Failed to build HTTP client")?;
+
+        let insecure = if tls.insecure_hosts.is_empty() {
+            None
+        } else {
+            warn!(
+                "TLS certificates of {} are NOT verified, anyone on the network path can serve any image as theirs",
+                tls.insecure_hosts.join(", ")
+            );
+            Some(builder()?.danger_accept_invalid_certs(true).build().context("Failed to build HTTP client")?)
+        };
+
+        Ok(HttpClient {
+            inner,
+            insecure,
+            insecure_hosts: tls.insecure_hosts.clone(),
+            max_retries,
+            warned_rate_limit: AtomicBool::new(false),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client_for(url).get(url)
+    }
+
+    /// How often [`send`](Self::send) retries, callers retrying on their own stick to it too
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.client_for(url).post(url)
+    }
+
+    /// The unverified client for insecure hosts, the verifying one for everything else
+    fn client_for(&self, url: &str) -> &reqwest::Client {
+        let Some(insecure) = &self.insecure else {
+            return &self.inner;
+        };
+        let Ok(url) = Url::parse(url) else {
+            return &self.inner;
+        };
+
+        let host = url.host_str().unwrap_or_default();
+        let host_port = url.port_or_known_default().map(|port| format!("{}:{}", host, port));
+        if self.insecure_hosts.iter().any(|name| name == host || Some(name) == host_port.as_ref()) {
+            insecure
+        } else {
+            &self.inner
+        }
+    }
+
+    /// Send `request`, retrying connection errors, timeouts, 429 and 5xx with exponential
+    /// backoff, or after `Retry-After` when the registry says how long to wait
+    pub async fn send(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        let mut attempt = 0;
+
+        loop {
+            let result = request.try_clone()
+                .context("Request body can't be retried")?
+                .send().await;
+
+            if let Ok(response) = &result {
+                self.check_rate_limit(response);
+            }
+
+            let delay = match &result {
+                Ok(response) if is_transient_status(response.status()) => {
+                    retry_after(response).unwrap_or_else(|| backoff(attempt))
+                }
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => backoff(attempt),
+                _ => return Ok(result?),
+            };
+
+            if attempt >= self.max_retries {
+                return Ok(result?);
+            }
+            attempt += 1;
+
+            match &result {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    warn!("{} is rate limited, retrying in {:?}", response.url(), delay)
+                }
+                Ok(response) => warn!("{} returned {}, retrying in {:?}", response.url(), response.status(), delay),
+                Err(e) => warn!("Request failed ({}), retrying in {:?}", e, delay),
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Warn when Docker Hub's `RateLimit-Remaining` says few pulls are left
+    fn check_rate_limit(&self, response: &Response) {
+        let Some(remaining) = rate_limit_header(response, "ratelimit-remaining") else {
+            return;
+        };
+        let low = match rate_limit_header(response, "ratelimit-limit") {
+            Some(limit) => remaining * 100 <= limit * RATE_LIMIT_WARN_PERCENT,
+            None => remaining == 0,
+        };
+
+        if low && !self.warned_rate_limit.swap(true, Ordering::Relaxed) {
+            warn!("Only {} pulls left before the registry rate limits this client, log in for a higher limit", remaining);
+        }
+    }
+}
+
+/// `76;w=21600` is 76 pulls in a 6 hour window, only the count is returned
+fn rate_limit_header(response: &Response, name: &str) -> Option<u64> {
+    response.headers()
+        .get(name)?
+        .to_str().ok()?
+        .split(';')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Pass a 2xx response through, anything else becomes an error quoting the
+/// registry's error codes and messages, or the start of the body when it has none
+pub async fn check_status(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let url = response.url().clone();
+    let body = response.text().await.unwrap_or_default();
+    let details = match serde_json::from_str::<ErrorBody>(&body) {
+        Ok(body) if !body.errors.is_empty() => body.errors.iter()
+            .map(|e| format!("{}: {}", e.code, e.message))
+            .collect::<Vec<_>>()
+            .join(", "),
+        _ => body.trim().chars().take(200).collect(),
+    };
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        bail!("{} returned {}, the pull rate limit is used up, wait or log in for a higher one", url, status);
+    }
+    if details.is_empty() {
+        bail!("{} returned {}", url, status);
+    }
+    bail!("{} returned {}: {}", url, status, details)
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// 0.5s, 1s, 2s, ... capped at MAX_BACKOFF
+pub fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(500)
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_BACKOFF)
+}
+
+/// Seconds or an HTTP date, capped at MAX_RETRY_AFTER
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    let delay = match value.parse() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        // A date in the past means now
+        Err(_) => httpdate::parse_http_date(value).ok()?
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
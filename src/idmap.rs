@@ -0,0 +1,129 @@
+use std::{fs, io, os::unix::fs::MetadataExt, path::Path};
+
+use anyhow::{bail, Context};
+use nix::{
+    errno::Errno,
+    unistd::{chown, Gid, Uid},
+};
+
+/// A user namespace's uid or gid map, as in `/proc/<pid>/uid_map`
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdMap {
+    ranges: Vec<IdRange>,
+}
+
+/// `count` ids from `inside` on, which are `outside` on in the parent namespace
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IdRange {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+impl IdMap {
+    /// The uid and gid maps of the user namespace woody runs in, the identity outside of one
+    pub fn current() -> anyhow::Result<(IdMap, IdMap)> {
+        let read = |path: &str| -> anyhow::Result<IdMap> {
+            let content = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+            IdMap::parse(&content).with_context(|| format!("Invalid {}", path))
+        };
+        Ok((read("/proc/self/uid_map")?, read("/proc/self/gid_map")?))
+    }
+
+    /// Lines of `inside outside count`
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let ranges = content.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let fields = line.split_whitespace().map(str::parse).collect::<Result<Vec<u32>, _>>();
+                match fields.as_deref() {
+                    Ok([inside, outside, count]) => Ok(IdRange { inside: *inside, outside: *outside, count: *count }),
+                    _ => bail!("Invalid id map line {:?}, expected inside outside count", line),
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(IdMap { ranges })
+    }
+
+    /// Whether `id` exists in the namespace, files can't be owned by one that doesn't
+    pub fn contains(&self, id: u32) -> bool {
+        self.range_of(id).is_some()
+    }
+
+    /// `id` as the parent namespace sees it
+    pub fn to_outside(&self, id: u32) -> Option<u32> {
+        self.range_of(id).map(|range| range.outside + (id - range.inside))
+    }
+
+    fn range_of(&self, id: u32) -> Option<&IdRange> {
+        self.ranges.iter().find(|range| id >= range.inside && id - range.inside < range.count)
+    }
+}
+
+/// Create `dir` and its missing parents, owned by the root of the user namespace
+/// woody runs in.
+///
+/// Meant for what woody creates inside a container, its working directory and
+/// mount points. They then belong to the container's root like the image's own
+/// directories, rather than to a setgid parent's group. This has to run while woody
+/// still has all its capabilities: in a user namespace only its root can chown, and
+/// a parent owned by an unmapped id can't be written to at all, which the error says.
+pub fn create_dir_all_owned(dir: &Path) -> anyhow::Result<()> {
+    let (uids, gids) = IdMap::current()?;
+
+    let mut missing = Vec::new();
+    let mut existing = dir;
+    while !existing.exists() {
+        missing.push(existing);
+        existing = match existing.parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+
+    for path in missing.into_iter().rev() {
+        match fs::create_dir(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                let owner = path.parent().and_then(|parent| fs::metadata(parent).ok()).map(|meta| meta.uid());
+                return match owner {
+                    Some(uid) if e.kind() == io::ErrorKind::PermissionDenied && !uids.contains(uid) => {
+                        Err(e).with_context(|| format!(
+                            "Failed to create {}, its parent is owned by a user outside of woody's user namespace",
+                            path.display()
+                        ))
+                    }
+                    _ => Err(e).with_context(|| format!("Failed to create {}", path.display())),
+                };
+            }
+        }
+
+        // Left to woody's user where the namespace has no root to give it to
+        if uids.contains(0) && gids.contains(0) {
+            match chown(path, Some(Uid::from_raw(0)), Some(Gid::from_raw(0))) {
+                Ok(()) | Err(Errno::EPERM) => {}
+                Err(e) => return Err(e).with_context(|| format!("Failed to chown {}", path.display())),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_maps_translate_only_mapped_ids() {
+        let map = IdMap::parse("         0     100000      65536\n  65536 1000 1\n").unwrap();
+        assert!(map.contains(0) && map.contains(65535) && map.contains(65536));
+        assert!(!map.contains(65537));
+        assert_eq!(map.to_outside(1234), Some(101234));
+        assert_eq!(map.to_outside(65536), Some(1000));
+        assert_eq!(map.to_outside(70000), None);
+        assert!(IdMap::parse("0 0\n").is_err());
+    }
+}
@@ -0,0 +1,429 @@
+use std::{collections::HashSet, fs, io::ErrorKind, os::unix::io::AsRawFd, path::{Path, PathBuf}, str::FromStr};
+
+use anyhow::{bail, Context};
+use nix::{errno::Errno, fcntl::{flock, FlockArg}, unistd::Pid};
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use tracing::info;
+
+use crate::{
+    cache::ManifestCache,
+    is_alive,
+    registry::{self, ImageConfig, LocalImage, Manifest, PullEvent, PullOptions},
+    state::ContainerState,
+};
+
+// The store, `images/` under the woody root by default:
+//
+// Each image lives in a directory named after its config digest, so tags that point
+// at the same image share it, and `refs/<repository>/<tag>` records which one a tag
+// was last pulled as. Registry responses are cached under `cache/`, and pulled layers
+// are extracted once into `layers/<digest>`, which the images link to. A layer being
+// downloaded is spooled to `downloads/<digest>.partial`, where a later pull resumes it,
+// and `downloads/<digest>.lock` keeps two pulls from writing it at once. `store.lock`
+// keeps removals from deleting what a pull is still building, see [`lock_store`].
+
+/// When `woody run` contacts the registry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PullPolicy {
+    /// Pull every time, picking up a moved tag
+    Always,
+    /// Pull only if the image isn't stored yet
+    #[default]
+    Missing,
+    /// Never pull, fail if the image isn't stored
+    Never,
+}
+
+impl FromStr for PullPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "always" => Ok(PullPolicy::Always),
+            "missing" => Ok(PullPolicy::Missing),
+            "never" => Ok(PullPolicy::Never),
+            other => bail!("Unknown pull policy {:?}, expected always, missing or never", other),
+        }
+    }
+}
+
+/// What `woody inspect` shows of an image
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageDetails {
+    /// Config digest, `None` if only the registry has it
+    pub id: Option<String>,
+    pub name: String,
+    pub reference: String,
+    pub manifest: Manifest,
+    pub config: ImageConfig,
+}
+
+/// Metadata of the stored image for `image_ref`, or else of what the registry has
+/// for it, without downloading any layers. `policy` is what [`get`] would pull by.
+pub async fn inspect(image_ref: &str, policy: PullPolicy, opts: &PullOptions) -> anyhow::Result<ImageDetails> {
+    let stored = match policy {
+        PullPolicy::Always => None,
+        _ => find(&opts.root, image_ref)?,
+    };
+    if let Some(image) = stored {
+        return Ok(ImageDetails {
+            id: Some(image.id().to_string()),
+            name: image.name,
+            reference: image.reference,
+            manifest: image.manifest,
+            config: image.config,
+        });
+    }
+    if policy == PullPolicy::Never {
+        bail!("Image {} is not stored locally and --pull=never was given", image_ref);
+    }
+
+    let (name, reference) = registry::parse_image_name(image_ref)?;
+    let (manifest, config) = registry::fetch_metadata(image_ref, opts).await?;
+    Ok(ImageDetails { id: None, name, reference, manifest, config })
+}
+
+/// The stored image for `image_ref`, pulling it first if `policy` says so, see [`registry::pull_image`]
+pub async fn get(
+    image_ref: &str,
+    policy: PullPolicy,
+    opts: &PullOptions,
+    on_event: &mut dyn FnMut(PullEvent)
+) -> anyhow::Result<LocalImage> {
+    let stored = match policy {
+        PullPolicy::Always => None,
+        _ => find(&opts.root, image_ref)?,
+    };
+
+    match (stored, policy) {
+        // The stored one may be for another platform than asked for
+        (Some(image), PullPolicy::Missing) if !image.config.matches_platform(opts) => {
+            info!("Stored image {} is {}, pulling {}", image, image.config.platform(), opts.platform());
+            registry::pull_image(image_ref, opts, on_event).await
+        }
+        (Some(image), _) => {
+            registry::check_platform(image_ref, &image.config, opts)?;
+            info!("Using stored image {}", image);
+            Ok(image)
+        }
+        (None, PullPolicy::Never) => bail!("Image {} is not stored locally and --pull=never was given", image_ref),
+        (None, _) => registry::pull_image(image_ref, opts, on_event).await,
+    }
+}
+
+/// Look `image_ref` up in the store without touching the network
+pub fn find(root: &Path, image_ref: &str) -> anyhow::Result<Option<LocalImage>> {
+    let (name, reference) = registry::parse_image_name(image_ref)?;
+
+    let ref_path = ref_path(root, &name, &reference);
+    let id = match fs::read_to_string(&ref_path) {
+        Ok(id) => id.trim().to_string(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", ref_path.display())),
+    };
+
+    // The ref outlived its image, e.g. the directory was deleted by hand
+    if !image_path(root, &id).exists() {
+        return Ok(None);
+    }
+
+    load(root, name, reference, &id).map(Some)
+}
+
+/// Lock the store at `root` until the returned file is dropped. Pulls, loads and commits
+/// share it, removing and pruning need it to themselves: they delete whatever no stored
+/// image uses, which includes the layers and `.partial` directories of a pull in progress.
+pub fn lock_store(root: &Path, exclusive: bool) -> anyhow::Result<fs::File> {
+    fs::create_dir_all(root).with_context(|| format!("Failed to create {}", root.display()))?;
+    let path = root.join("store.lock");
+    let lock = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let (nonblocking, blocking) = match exclusive {
+        true => (FlockArg::LockExclusiveNonblock, FlockArg::LockExclusive),
+        false => (FlockArg::LockSharedNonblock, FlockArg::LockShared),
+    };
+    match flock(lock.as_raw_fd(), nonblocking) {
+        Ok(()) => return Ok(lock),
+        Err(Errno::EWOULDBLOCK) if exclusive => info!("Waiting for pulls in progress to finish"),
+        Err(Errno::EWOULDBLOCK) => info!("Waiting for an image removal to finish"),
+        Err(e) => return Err(e).with_context(|| format!("Failed to lock {}", path.display())),
+    }
+    flock(lock.as_raw_fd(), blocking).with_context(|| format!("Failed to lock {}", path.display()))?;
+
+    Ok(lock)
+}
+
+/// Untag `image`, a reference or an image id, and delete the image once no tag is left.
+///
+/// An image a running container was started from, or that a committed image is
+/// layered on, is left alone. Returns the bytes freed.
+pub fn remove(root: &Path, image: &str) -> anyhow::Result<u64> {
+    let _lock = lock_store(root, true)?;
+    let refs = list_refs(root)?;
+
+    let id_arg = image.trim_start_matches("sha256:");
+    let (id, untag) = if is_image_id(id_arg) && image_path(root, id_arg).exists() {
+        let untag: Vec<PathBuf> = refs.iter().filter(|(_, id)| id == id_arg).map(|(path, _)| path.clone()).collect();
+        (id_arg.to_string(), untag)
+    } else {
+        let (name, reference) = registry::parse_image_name(image)?;
+        let path = ref_path(root, &name, &reference);
+        let id = refs.iter().find(|(ref_path, _)| *ref_path == path).map(|(_, id)| id.clone())
+            .with_context(|| format!("No such image: {}", image))?;
+        (id, vec![path])
+    };
+
+    let remaining = refs.iter().filter(|(path, ref_id)| *ref_id == id && !untag.contains(path)).count();
+    if remaining == 0 {
+        ensure_unused(root, &id)?;
+    }
+
+    for path in &untag {
+        fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        println!("Untagged: {}", ref_name(root, path));
+    }
+    if remaining > 0 {
+        return Ok(0);
+    }
+
+    let path = image_path(root, &id);
+    let freed = dir_size(&path)?;
+    fs::remove_dir_all(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    println!("Deleted: sha256:{}", id);
+
+    Ok(freed + prune_layers(root)?)
+}
+
+/// Delete untagged unused images, interrupted pulls and cached registry responses
+/// no stored image needs anymore. Returns the bytes freed.
+pub fn prune(root: &Path) -> anyhow::Result<u64> {
+    let _lock = lock_store(root, true)?;
+    let tagged: HashSet<String> = list_refs(root)?.into_iter().map(|(_, id)| id).collect();
+    let mut freed = 0;
+
+    for entry in fs::read_dir(root).into_iter().flatten() {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        let leftover = name.ends_with(".partial");
+        let dangling = is_image_id(&name) && !tagged.contains(&name) && ensure_unused(root, &name).is_ok();
+        if leftover || dangling {
+            freed += dir_size(&entry.path())?;
+            fs::remove_dir_all(entry.path())
+                .with_context(|| format!("Failed to remove {}", entry.path().display()))?;
+            if dangling {
+                println!("Deleted: sha256:{}", name);
+            }
+        }
+    }
+
+    // Interrupted layer downloads, only a pull of the same layer would resume them.
+    // One whose lock is taken is still being written.
+    for entry in fs::read_dir(root.join("downloads")).into_iter().flatten() {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "partial") {
+            continue;
+        }
+        let lock_path = path.with_extension("lock");
+        let lock = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+            .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+        match flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to lock {}", lock_path.display())),
+        }
+
+        freed += fs::metadata(&path)?.len();
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+
+    // Whatever a stored image was resolved from: its config and its manifest
+    let mut keep = HashSet::new();
+    for id in list_images(root)? {
+        keep.insert(format!("sha256:{}", id));
+        if let Ok(manifest) = fs::read(image_path(root, &id).join("manifest.json")) {
+            keep.insert(format!("sha256:{:x}", Sha256::digest(&manifest)));
+        }
+    }
+    freed += ManifestCache::new(&root.join("cache")).prune(&keep)?;
+
+    Ok(freed + prune_layers(root)?)
+}
+
+/// Delete the shared layers no stored image links to, and interrupted extractions
+fn prune_layers(root: &Path) -> anyhow::Result<u64> {
+    let mut used = HashSet::new();
+    for id in list_images(root)? {
+        for entry in fs::read_dir(image_path(root, &id).join("layers")).into_iter().flatten() {
+            if let Ok(target) = entry?.path().canonicalize() {
+                used.insert(target);
+            }
+        }
+    }
+
+    let mut freed = 0;
+    for entry in fs::read_dir(root.join("layers")).into_iter().flatten() {
+        let path = entry?.path();
+        if path.canonicalize().is_ok_and(|target| used.contains(&target)) {
+            continue;
+        }
+        freed += dir_size(&path)?;
+        fs::remove_dir_all(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+
+    Ok(freed)
+}
+
+/// `12.3MB` style, like docker reports reclaimed space
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{}B", bytes),
+        _ => format!("{:.1}{}", size, UNITS[unit]),
+    }
+}
+
+/// Bail if a running container uses image `id` or a stored image is layered on it
+fn ensure_unused(root: &Path, id: &str) -> anyhow::Result<()> {
+    for state in ContainerState::list()? {
+        if state.image_id.as_deref() == Some(id) && is_alive(Pid::from_raw(state.pid)) {
+            bail!("Image sha256:{} is in use by running container {}", id, state.id);
+        }
+    }
+
+    for other in list_images(root)? {
+        let layers = image_path(root, &other).join("layers");
+        for entry in fs::read_dir(&layers).into_iter().flatten().flatten() {
+            if fs::read_link(entry.path()).is_ok_and(|target| target.iter().any(|part| part == id)) {
+                bail!("Image sha256:{} is a base of image sha256:{}, remove that first", id, other);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Ids of every stored image
+fn list_images(root: &Path) -> anyhow::Result<Vec<String>> {
+    let mut ids = Vec::new();
+
+    for entry in fs::read_dir(root).into_iter().flatten() {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if is_image_id(&name) {
+            ids.push(name);
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Every ref file under `refs/` with the image id it points at
+fn list_refs(root: &Path) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    fn walk(dir: &Path, refs: &mut Vec<(PathBuf, String)>) -> anyhow::Result<()> {
+        for entry in fs::read_dir(dir).into_iter().flatten() {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), refs)?;
+            } else if !entry.file_name().to_string_lossy().ends_with(".tmp") {
+                refs.push((entry.path(), fs::read_to_string(entry.path())?.trim().to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    let mut refs = Vec::new();
+    walk(&root.join("refs"), &mut refs)?;
+    Ok(refs)
+}
+
+/// `library/alpine:3.19` back from the ref file path
+fn ref_name(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root.join("refs")).unwrap_or(path);
+    let name = relative.parent().map(|name| name.display().to_string()).unwrap_or_default();
+    let reference = relative.file_name().map(|r| r.to_string_lossy().to_string()).unwrap_or_default();
+
+    match reference.strip_prefix("sha256-") {
+        Some(hex) => format!("{}@sha256:{}", name, hex),
+        None => format!("{}:{}", name, reference),
+    }
+}
+
+fn is_image_id(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Bytes used by the files under `path`, symlinks are not followed
+fn dir_size(path: &Path) -> anyhow::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += dir_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Directory holding the image with config digest `sha256:<id>`
+pub(crate) fn image_path(root: &Path, id: &str) -> PathBuf {
+    root.join(id)
+}
+
+/// Point `name:reference` at the image `id`
+pub(crate) fn tag(root: &Path, name: &str, reference: &str, id: &str) -> anyhow::Result<()> {
+    let path = ref_path(root, name, reference);
+    fs::create_dir_all(path.parent().context("Reference path has no parent")?)?;
+
+    // Not with_extension, tags like 3.11 already have one
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&tmp_path, id)?;
+    fs::rename(&tmp_path, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(())
+}
+
+pub(crate) fn load(root: &Path, name: String, reference: String, id: &str) -> anyhow::Result<LocalImage> {
+    let dir = image_path(root, id);
+
+    let manifest_raw = fs::read(dir.join("manifest.json"))
+        .with_context(|| format!("Failed to read {}", dir.join("manifest.json").display()))?;
+    let manifest: Manifest = serde_json::from_slice(&manifest_raw)
+        .with_context(|| format!("Corrupted image file: {}", dir.join("manifest.json").display()))?;
+    let config: ImageConfig = read_json(&dir.join("config.json"))?;
+    let digest = registry::manifest_digest(&manifest, &manifest_raw);
+
+    Ok(LocalImage { name, reference, digest, manifest, config, layers_path: dir.join("layers") })
+}
+
+/// Tag `image` by its manifest digest as well and refer to it by that from now on,
+/// so it can be run as `name@digest` even after its tag moved
+pub fn pin(root: &Path, image: &mut LocalImage) -> anyhow::Result<()> {
+    let digest = image.digest.clone()
+        .with_context(|| format!("{} has a schema 1 manifest, it has no digest to pin", image))?;
+    tag(root, &image.name, &digest, image.id())?;
+    image.reference = digest;
+    Ok(())
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_slice(&content).with_context(|| format!("Corrupted image file: {}", path.display()))
+}
+
+fn ref_path(root: &Path, name: &str, reference: &str) -> PathBuf {
+    // `sha256:...` isn't a friendly file name
+    root.join("refs").join(name).join(reference.replace(':', "-"))
+}
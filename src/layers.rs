@@ -0,0 +1,700 @@
+use std::{
+    ffi::{CString, OsString},
+    fs,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::{ffi::OsStrExt, fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt}},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use flate2::{write::GzEncoder, Compression};
+use nix::{
+    errno::Errno,
+    mount::{mount, MsFlags},
+    sys::stat::{makedev, mknod, Mode, SFlag},
+    unistd::{fchownat, FchownatFlags, Gid, Uid},
+};
+use sha2::{Digest as _, Sha256};
+use tracing::debug;
+
+use crate::idmap::IdMap;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Directory the `index`-th layer of an image is extracted into
+pub fn layer_dir(layers_root: &Path, index: usize) -> PathBuf {
+    layers_root.join(index.to_string())
+}
+
+/// Where the layer with digest `sha256:<hex>` is extracted once for every image in
+/// the store at `root` that has it, an image's [`layer_dir`]s are symlinks to these
+pub fn shared_layer_dir(root: &Path, digest: &str) -> PathBuf {
+    root.join("layers").join(digest.trim_start_matches("sha256:"))
+}
+
+/// Link the shared copy of layer `digest` in as the `index`-th layer of the image at
+/// `layers_root`. False if the store at `root` doesn't have the layer yet.
+pub fn link_shared_layer(root: &Path, digest: &str, layers_root: &Path, index: usize) -> anyhow::Result<bool> {
+    let shared = shared_layer_dir(root, digest);
+    if !shared.is_dir() {
+        return Ok(false);
+    }
+
+    // Relative, so the store can be moved as a whole
+    let link = Path::new("../..").join(shared.strip_prefix(root)?);
+    symlink(&link, layer_dir(layers_root, index))?;
+    Ok(true)
+}
+
+/// Extract `blob` into the shared store as layer `digest`, then link it in like [`link_shared_layer`]
+pub fn store_shared_layer(root: &Path, digest: &str, blob: &Path, layers_root: &Path, index: usize) -> anyhow::Result<()> {
+    // Extracted next to its final place and renamed in, an interrupted extraction leaves no half
+    // layer. One directory per process, a concurrent pull of the same layer has its own.
+    let shared = shared_layer_dir(root, digest);
+    let partial = PathBuf::from(format!("{}.{}.partial", shared.display(), std::process::id()));
+    if partial.exists() {
+        fs::remove_dir_all(&partial)
+            .with_context(|| format!("Failed to clear {}", partial.display()))?;
+    }
+    unpack_blob(blob, &partial, &layers_below(layers_root, index))?;
+    if let Err(e) = fs::rename(&partial, &shared) {
+        // The other pull got there first, its copy is as good as this one
+        if !shared.is_dir() {
+            return Err(e).with_context(|| format!("Failed to move layer into {}", shared.display()));
+        }
+        fs::remove_dir_all(&partial)
+            .with_context(|| format!("Failed to clear {}", partial.display()))?;
+    }
+
+    link_shared_layer(root, digest, layers_root, index)?;
+    Ok(())
+}
+
+/// Directories of the layers below the `index`-th, topmost first
+pub fn layers_below(layers_root: &Path, index: usize) -> Vec<PathBuf> {
+    (0..index).rev().map(|i| layer_dir(layers_root, i)).collect()
+}
+
+/// `sha256:<hex>` of a blob's content
+pub fn blob_digest(blob: &Path) -> anyhow::Result<String> {
+    let mut file = fs::File::open(blob).with_context(|| format!("Failed to open {}", blob.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+/// Extract a downloaded layer blob, sniffing its compression from the magic bytes
+pub fn unpack_blob(blob: &Path, dest: &Path, lowers: &[PathBuf]) -> anyhow::Result<()> {
+    let mut file = fs::File::open(blob).with_context(|| format!("Failed to open {}", blob.display()))?;
+    let mut magic = [0u8; 4];
+    let read = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    match &magic[..read] {
+        [0x1f, 0x8b, ..] => unpack_layer(flate2::read::GzDecoder::new(BufReader::new(file)), dest, lowers),
+        [0x28, 0xb5, 0x2f, 0xfd] => bail!("zstd compressed layers are not supported"),
+        _ => unpack_layer(BufReader::new(file), dest, lowers),
+    }
+}
+
+/// Extract one layer tarball into its own directory.
+///
+/// OCI whiteout files are turned into what overlayfs understands: a 0/0 char
+/// device for a deleted path and the opaque xattr for a replaced directory.
+/// Entries keep the owners recorded in the layer, see [`restore_owner`].
+///
+/// Hardlinks to files of a lower layer become copies of the file as the topmost
+/// of `lowers` has it, a link can't cross layer directories.
+pub fn unpack_layer<R: Read>(tarball: R, dest: &Path, lowers: &[PathBuf]) -> anyhow::Result<()> {
+    fs::create_dir_all(dest)?;
+    let (uids, gids) = IdMap::current()?;
+    let mut archive = tar::Archive::new(tarball);
+    archive.set_preserve_permissions(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // Defaults for the entries after it, which the tar crate doesn't apply
+        if entry.header().entry_type().is_pax_global_extensions() {
+            continue;
+        }
+        let path = entry_path(&entry.path()?)?;
+
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => {
+                entry.unpack_in(dest)?;
+                continue;
+            }
+        };
+
+        if name == OPAQUE_WHITEOUT {
+            let parent = create_parent(dest, &path)?;
+            set_opaque(&parent)?;
+        } else if let Some(deleted) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let parent = create_parent(dest, &path)?;
+            mknod(&parent.join(deleted), SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))
+                .with_context(|| format!("Failed to create whiteout for {}", parent.join(deleted).display()))?;
+        } else if let Some(target) = cross_layer_target(&entry, dest)? {
+            let dst = create_parent(dest, &path)?.join(name);
+            copy_from_lowers(&target, &dst, lowers)
+                .with_context(|| format!("Failed to unpack hardlink {}", path.display()))?;
+            restore_owner(&dst, entry.header(), &uids, &gids)?;
+        } else if entry.unpack_in(dest).with_context(|| format!("Failed to unpack {}", path.display()))? {
+            restore_owner(&dest.join(&path), entry.header(), &uids, &gids)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// An entry's path relative to the layer, `unpack_in` unpacks absolute ones inside it too.
+/// Every path joined onto the layer directory must come through here.
+fn entry_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => bail!("Layer entry {} leaves the layer", path.display()),
+        }
+    }
+
+    Ok(relative)
+}
+
+/// Create the directories above the entry at `path` in `dest` and return its parent.
+///
+/// The tar crate checks this for the entries it unpacks, woody has to for the ones it
+/// writes itself: a symlink an earlier entry planted could lead out of the layer.
+fn create_parent(dest: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let mut dir = dest.to_path_buf();
+    for part in path.parent().into_iter().flat_map(Path::iter) {
+        dir.push(part);
+        match dir.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => bail!("Cannot unpack {}, {} is not a directory", path.display(), dir.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to inspect {}", dir.display())),
+        }
+    }
+
+    Ok(dir)
+}
+
+/// Target of a hardlink entry that isn't in the layer being unpacked
+fn cross_layer_target<R: Read>(entry: &tar::Entry<R>, dest: &Path) -> anyhow::Result<Option<PathBuf>> {
+    if !entry.header().entry_type().is_hard_link() {
+        return Ok(None);
+    }
+
+    let link_name = entry.link_name()?.context("Hardlink entry without a target")?;
+    let target = entry_path(&link_name).with_context(|| format!("Invalid hardlink target {}", link_name.display()))?;
+
+    Ok(dest.join(&target).symlink_metadata().is_err().then_some(target))
+}
+
+/// Copy `target` from the topmost layer in `lowers` that has it to `dst`, whose directory exists
+fn copy_from_lowers(target: &Path, dst: &Path, lowers: &[PathBuf]) -> anyhow::Result<()> {
+    for lower in lowers {
+        let source = lower.join(target);
+        let Ok(metadata) = source.symlink_metadata() else {
+            continue;
+        };
+        // Whiteout, a layer in between deleted the target
+        if metadata.file_type().is_char_device() && metadata.rdev() == 0 {
+            break;
+        }
+        if !metadata.is_file() {
+            bail!("Hardlink target {} is not a regular file", target.display());
+        }
+
+        // A symlinked directory on the way must not lead out of the layer
+        let resolved = source.parent().map(Path::canonicalize).transpose()?;
+        if !resolved.is_some_and(|dir| lower.canonicalize().is_ok_and(|lower| dir.starts_with(lower))) {
+            bail!("Hardlink target {} leaves the layer", target.display());
+        }
+
+        // Writing through an existing hardlink would change the file it links to
+        match fs::remove_file(dst) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        fs::copy(&source, dst).with_context(|| format!("Failed to copy {}", source.display()))?;
+        return Ok(());
+    }
+
+    bail!("Hardlink target {} is in none of the layers below", target.display())
+}
+
+/// chown an unpacked entry to the uid and gid in its header.
+///
+/// Ids the user namespace woody runs in has no mapping for can't own files, those
+/// are left to woody's user, as is everything when woody may not chown at all.
+fn restore_owner(path: &Path, header: &tar::Header, uids: &IdMap, gids: &IdMap) -> anyhow::Result<()> {
+    let uid = u32::try_from(header.uid()?).ok().filter(|uid| uids.contains(*uid));
+    let gid = u32::try_from(header.gid()?).ok().filter(|gid| gids.contains(*gid));
+    if uid.is_none() || gid.is_none() {
+        debug!("{} is owned by {}:{}, which is unmapped here", path.display(), header.uid()?, header.gid()?);
+    }
+
+    match fchownat(None, path, uid.map(Uid::from_raw), gid.map(Gid::from_raw), FchownatFlags::NoFollowSymlink) {
+        Ok(()) => {}
+        Err(Errno::EPERM) => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to chown {}", path.display())),
+    }
+
+    // chown clears the setuid and setgid bits
+    let mode = header.mode()?;
+    if mode & 0o6000 != 0 && header.entry_type() != tar::EntryType::Symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to chmod {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Tar and gzip an overlay upper dir into the layer blob `blob`, the inverse of
+/// [`unpack_layer`]: whiteouts are turned back into OCI `.wh.` files.
+///
+/// Returns the digest of the blob and the diff id, the digest of the uncompressed tar.
+pub fn pack_layer(upper: &Path, blob: &Path) -> anyhow::Result<(String, String)> {
+    let file = fs::File::create(blob).with_context(|| format!("Failed to create {}", blob.display()))?;
+    let gzip = GzEncoder::new(HashingWriter::new(file), Compression::default());
+
+    let mut builder = tar::Builder::new(HashingWriter::new(gzip));
+    builder.follow_symlinks(false);
+    pack_dir(&mut builder, upper, Path::new(""))?;
+
+    let (gzip, diff_id) = builder.into_inner()?.finish();
+    let (mut file, digest) = gzip.finish()?.finish();
+    file.flush()?;
+
+    Ok((digest, diff_id))
+}
+
+fn pack_dir<W: Write>(builder: &mut tar::Builder<W>, dir: &Path, prefix: &Path) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = prefix.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_char_device() && metadata.rdev() == 0 {
+            let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+            whiteout.push(entry.file_name());
+            append_empty(builder, &prefix.join(whiteout), metadata.mtime())?;
+            continue;
+        }
+        if file_type.is_socket() {
+            continue;
+        }
+
+        builder.append_path_with_name(&path, &name)
+            .with_context(|| format!("Failed to archive {}", path.display()))?;
+
+        if file_type.is_dir() {
+            if is_opaque(&path) {
+                append_empty(builder, &name.join(OPAQUE_WHITEOUT), metadata.mtime())?;
+            }
+            pack_dir(builder, &path, &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn append_empty<W: Write>(builder: &mut tar::Builder<W>, name: &Path, mtime: i64) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(0);
+    header.set_mode(0o644);
+    header.set_mtime(mtime.max(0) as u64);
+
+    builder.append_data(&mut header, name, io::empty())
+        .with_context(|| format!("Failed to archive {}", name.display()))
+}
+
+/// Passes writes through while computing their sha256
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        HashingWriter { inner, hasher: Sha256::new() }
+    }
+
+    /// The wrapped writer and the `sha256:<hex>` digest of everything written
+    fn finish(self) -> (W, String) {
+        (self.inner, format!("sha256:{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// `lowerdir` value for `count` layers, topmost layer first as overlayfs expects.
+/// Symlinked layers resolve to the shared directory they link to.
+pub fn lowerdir(layers_root: &Path, count: usize) -> anyhow::Result<String> {
+    if count == 0 {
+        bail!("Image has no layers");
+    }
+
+    let dirs = (0..count).rev()
+        .map(|i| {
+            let dir = layer_dir(layers_root, i);
+            dir.canonicalize().with_context(|| format!("Missing layer directory {}", dir.display()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(dirs.iter()
+        .map(|dir| dir.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+/// Stack the extracted layers under a writable upper dir at `merged`
+pub fn mount_overlay(layers_root: &Path, upper: &Path, work: &Path, merged: &Path) -> anyhow::Result<()> {
+    mount_writable(&lowerdir(layers_root, count_layers(layers_root))?, upper, work, merged)
+}
+
+/// [`mount_overlay`] with a plain directory as the only lower layer, which stays untouched
+pub fn mount_overlay_dir(lower: &Path, upper: &Path, work: &Path, merged: &Path) -> anyhow::Result<()> {
+    let lower = lower.canonicalize().with_context(|| format!("Failed to resolve {}", lower.display()))?;
+    let lower = lower.to_string_lossy();
+    // Separators in the mount options, overlayfs would split the path on them
+    if lower.contains([':', ',']) {
+        bail!("Cannot use {} as an overlay layer, it contains ':' or ','", lower);
+    }
+
+    mount_writable(&lower, upper, work, merged)
+}
+
+fn mount_writable(lowerdir: &str, upper: &Path, work: &Path, merged: &Path) -> anyhow::Result<()> {
+    // Absolute paths, overlayfs resolves relative ones against whatever the cwd is at mount time
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lowerdir,
+        upper.canonicalize()?.display(),
+        work.canonicalize()?.display(),
+    );
+
+    debug!(target = %merged.display(), %options, "Mounting overlayfs");
+    mount(Some("overlay"), merged, Some("overlay"), MsFlags::empty(), Some(options.as_str()))
+        .context("Failed to mount overlayfs")
+}
+
+/// Read-only view of `upper` over the image's layers, nothing is written through it
+pub fn mount_readonly(layers_root: &Path, upper: &Path, target: &Path) -> anyhow::Result<()> {
+    // Without an upperdir overlayfs is read-only, upper just becomes the topmost lower layer
+    let options = format!(
+        "lowerdir={}:{}",
+        upper.canonicalize()?.display(),
+        lowerdir(layers_root, count_layers(layers_root))?,
+    );
+
+    debug!(target = %target.display(), %options, "Mounting read-only overlayfs");
+    mount(Some("overlay"), target, Some("overlay"), MsFlags::MS_RDONLY, Some(options.as_str()))
+        .context("Failed to mount overlayfs")
+}
+
+/// Number of extracted layers, they are numbered from 0 without gaps
+pub fn count_layers(layers_root: &Path) -> usize {
+    (0..).take_while(|&i| layer_dir(layers_root, i).is_dir()).count()
+}
+
+fn is_opaque(dir: &Path) -> bool {
+    let (Ok(path), Ok(name)) = (CString::new(dir.as_os_str().as_bytes()), CString::new("trusted.overlay.opaque")) else {
+        return false;
+    };
+    let mut value = [0u8; 1];
+
+    let len = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+    len == 1 && value[0] == b'y'
+}
+
+fn set_opaque(dir: &Path) -> anyhow::Result<()> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let name = CString::new("trusted.overlay.opaque")?;
+
+    let ret = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to mark {} opaque", dir.display()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_through_merged_land_in_upper() {
+        let tmp = std::env::temp_dir().join(format!("woody-layers-{}", std::process::id()));
+        let layers_root = tmp.join("layers");
+        let (upper, work, merged) = (tmp.join("upper"), tmp.join("work"), tmp.join("merged"));
+        for dir in [layer_dir(&layers_root, 0), upper.clone(), work.clone(), merged.clone()] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(layer_dir(&layers_root, 0).join("base"), "lower").unwrap();
+
+        if mount_overlay(&layers_root, &upper, &work, &merged).is_err() {
+            eprintln!("skipping, overlayfs needs CAP_SYS_ADMIN and a supported backing fs");
+            fs::remove_dir_all(&tmp).ok();
+            return;
+        }
+
+        fs::write(merged.join("created"), "new").unwrap();
+        fs::write(merged.join("base"), "changed").unwrap();
+        let seen = fs::read_to_string(merged.join("base")).unwrap();
+        crate::mounts::unmount_all(&merged).unwrap();
+
+        assert_eq!(seen, "changed");
+        assert_eq!(fs::read_to_string(upper.join("created")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(upper.join("base")).unwrap(), "changed");
+        assert!(!layer_dir(&layers_root, 0).join("created").exists());
+        assert_eq!(fs::read_to_string(layer_dir(&layers_root, 0).join("base")).unwrap(), "lower");
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    fn fixture_entry(builder: &mut tar::Builder<Vec<u8>>, path: &str, mode: u32, uid: u64, gid: u64) {
+        let content = b"#!/bin/sh\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(content.len() as u64);
+        header.set_mode(mode);
+        header.set_uid(uid);
+        header.set_gid(gid);
+        builder.append_data(&mut header, path, &content[..]).unwrap();
+    }
+
+    #[test]
+    fn unpacked_entries_keep_their_owner_and_mode() {
+        let tmp = std::env::temp_dir().join(format!("woody-layers-owners-{}", std::process::id()));
+        let mut builder = tar::Builder::new(Vec::new());
+        fixture_entry(&mut builder, "srv/app.sh", 0o640, 1234, 5678);
+        fixture_entry(&mut builder, "usr/bin/helper", 0o4755, 1234, 5678);
+        let tarball = builder.into_inner().unwrap();
+
+        unpack_layer(&tarball[..], &tmp, &[]).unwrap();
+        let app = fs::metadata(tmp.join("srv/app.sh")).unwrap();
+        let helper = fs::metadata(tmp.join("usr/bin/helper")).unwrap();
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert_eq!(app.mode() & 0o7777, 0o640);
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("skipping the ownership checks, only root can chown");
+            return;
+        }
+        assert_eq!((app.uid(), app.gid()), (1234, 5678));
+        assert_eq!((helper.uid(), helper.gid()), (1234, 5678));
+        assert_eq!(helper.mode() & 0o7777, 0o4755);
+    }
+
+    /// A regular entry with `path` written into the header as is, the tar crate refuses to build absolute ones
+    fn raw_entry(builder: &mut tar::Builder<Vec<u8>>, path: &str, mode: u32) {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(0);
+        header.set_mode(mode);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_cksum();
+        builder.append(&header, io::empty()).unwrap();
+    }
+
+    #[test]
+    fn absolute_entries_stay_inside_the_layer() {
+        let tmp = std::env::temp_dir().join(format!("woody-layers-absolute-{}", std::process::id()));
+        let (outside, dest) = (tmp.join("outside"), tmp.join("layer"));
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("bash"), "host").unwrap();
+        fs::set_permissions(outside.join("bash"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let absolute = format!("{}/bash", outside.display());
+        let mut builder = tar::Builder::new(Vec::new());
+        raw_entry(&mut builder, &absolute, 0o4755);
+        let tarball = builder.into_inner().unwrap();
+
+        let unpacked = unpack_layer(&tarball[..], &dest, &[]);
+        let host = fs::metadata(outside.join("bash")).unwrap();
+        let inside = fs::metadata(dest.join(absolute.trim_start_matches('/')));
+        fs::remove_dir_all(&tmp).unwrap();
+
+        unpacked.unwrap();
+        assert_eq!(host.mode() & 0o7777, 0o755);
+        assert_eq!(inside.unwrap().mode() & 0o7777, 0o4755);
+    }
+
+    #[test]
+    fn whiteouts_stay_inside_the_layer() {
+        let tmp = std::env::temp_dir().join(format!("woody-layers-whiteouts-{}", std::process::id()));
+        let (outside, dest) = (tmp.join("outside"), tmp.join("layer"));
+        fs::create_dir_all(&outside).unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        raw_entry(&mut builder, "../outside/.wh.victim", 0o644);
+        let traversing = builder.into_inner().unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "escape", &outside).unwrap();
+        raw_entry(&mut builder, "escape/.wh.victim", 0o644);
+        let through_symlink = builder.into_inner().unwrap();
+
+        // Into the same layer, which has the symlink by then
+        let mut builder = tar::Builder::new(Vec::new());
+        raw_entry(&mut builder, "escape/.wh..wh..opq", 0o644);
+        let opaque_through_symlink = builder.into_inner().unwrap();
+
+        let traversed = unpack_layer(&traversing[..], &dest, &[]);
+        let followed = unpack_layer(&through_symlink[..], &dest, &[]);
+        let followed_opaque = unpack_layer(&opaque_through_symlink[..], &dest, &[]);
+        let victim = outside.join("victim").symlink_metadata();
+        let opaque = is_opaque(&outside);
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert!(traversed.is_err());
+        assert!(followed.is_err());
+        assert!(followed_opaque.is_err());
+        assert!(victim.is_err());
+        assert!(!opaque);
+    }
+
+    #[test]
+    fn hardlinks_to_lower_layers_become_copies() {
+        let tmp = std::env::temp_dir().join(format!("woody-layers-links-{}", std::process::id()));
+        let (lower, dest) = (tmp.join("0"), tmp.join("1"));
+        fs::create_dir_all(lower.join("usr/bin")).unwrap();
+        fs::write(lower.join("usr/bin/python3.11"), "python").unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        fixture_entry(&mut builder, "etc/motd", 0o644, 0, 0);
+        for (path, target) in [("etc/issue", "etc/motd"), ("usr/bin/python3", "/usr/bin/python3.11")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Link);
+            header.set_size(0);
+            header.set_mode(0o755);
+            header.set_uid(0);
+            header.set_gid(0);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap();
+
+        unpack_layer(&tarball[..], &dest, std::slice::from_ref(&lower)).unwrap();
+        let motd = fs::metadata(dest.join("etc/motd")).unwrap();
+        let issue = fs::metadata(dest.join("etc/issue")).unwrap();
+        let copy = fs::read_to_string(dest.join("usr/bin/python3")).unwrap();
+        let copy_ino = fs::metadata(dest.join("usr/bin/python3")).unwrap().ino();
+        let lower_ino = fs::metadata(lower.join("usr/bin/python3.11")).unwrap().ino();
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert_eq!(motd.ino(), issue.ino());
+        assert_eq!(copy, "python");
+        assert_ne!(copy_ino, lower_ino);
+    }
+
+    #[test]
+    fn hardlink_copies_stay_inside_the_layer() {
+        let tmp = std::env::temp_dir().join(format!("woody-layers-link-escape-{}", std::process::id()));
+        let (lower, dest, outside) = (tmp.join("0"), tmp.join("1"), tmp.join("outside"));
+        fs::create_dir_all(lower.join("etc")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(lower.join("etc/shadow"), "lower").unwrap();
+        fs::write(outside.join("victim"), "host").unwrap();
+
+        let link = |builder: &mut tar::Builder<Vec<u8>>, path: &str| {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.as_gnu_mut().unwrap().linkname[..10].copy_from_slice(b"etc/shadow");
+            header.set_entry_type(tar::EntryType::Link);
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, io::empty()).unwrap();
+        };
+        let absolute = format!("{}/victim", outside.display());
+        let mut builder = tar::Builder::new(Vec::new());
+        link(&mut builder, &absolute);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "escape", &outside).unwrap();
+        link(&mut builder, "escape/victim");
+        let tarball = builder.into_inner().unwrap();
+
+        let unpacked = unpack_layer(&tarball[..], &dest, std::slice::from_ref(&lower));
+        let victim = fs::read_to_string(outside.join("victim")).unwrap();
+        let copy = fs::read_to_string(dest.join(absolute.trim_start_matches('/')));
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert!(unpacked.is_err());
+        assert_eq!(victim, "host");
+        assert_eq!(copy.unwrap(), "lower");
+    }
+
+    #[test]
+    fn long_names_are_decoded() {
+        let tmp = std::env::temp_dir().join(format!("woody-layers-names-{}", std::process::id()));
+        let deep = format!("usr/share/{}", "nested-directory/".repeat(8));
+        let (pax_name, gnu_name) = (format!("{}pax-file", deep), format!("{}gnu-file", deep));
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let global = b"22 comment=woody-test\n";
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XGlobalHeader);
+        header.set_size(global.len() as u64);
+        builder.append_data(&mut header, "pax_global_header", &global[..]).unwrap();
+
+        builder.append_pax_extensions([("path", pax_name.as_bytes())]).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_size(3);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_data(&mut header, "truncated", &b"pax"[..]).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_data(&mut header, &gnu_name, &b"gnu"[..]).unwrap();
+        let tarball = builder.into_inner().unwrap();
+
+        unpack_layer(&tarball[..], &tmp, &[]).unwrap();
+        let pax = fs::read_to_string(tmp.join(&pax_name));
+        let gnu = fs::read_to_string(tmp.join(&gnu_name));
+        let stray = tmp.join("truncated").exists() || tmp.join("pax_global_header").exists();
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert!(pax_name.len() > 100);
+        assert_eq!(pax.unwrap(), "pax");
+        assert_eq!(gnu.unwrap(), "gnu");
+        assert!(!stray);
+    }
+}
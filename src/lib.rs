@@ -0,0 +1,49 @@
+//! Pull images from Docker Hub and run them in Linux namespaces.
+//!
+//! The `woody` binary is a thin CLI over [`images::get`], [`pull_image`] and [`run`].
+
+pub mod auth;
+mod cache;
+pub mod capabilities;
+pub mod cgroups;
+pub mod command;
+pub mod commit;
+pub mod container;
+pub mod control;
+mod elf;
+pub mod environment;
+pub mod etc;
+pub mod exec;
+pub mod export;
+pub mod health;
+pub mod http;
+pub mod idmap;
+pub mod images;
+mod layers;
+pub mod load;
+pub mod logs;
+pub mod lrng_cgroup;
+mod mounts;
+pub mod network;
+pub mod paths;
+pub mod plan;
+pub mod progress;
+pub mod registry;
+pub mod restart;
+pub mod rlimits;
+pub mod run;
+pub mod seccomp;
+pub mod spec;
+pub mod state;
+pub mod stats;
+mod tty;
+pub mod units;
+pub mod users;
+pub mod volumes;
+
+pub use container::ContainerConfig;
+pub use control::{exit_code, is_alive};
+pub use registry::{pull_image, ImageConfig, LocalImage, Manifest, PullEvent, PullOptions};
+pub use run::{run, RunOptions};
+
+pub type ActionResult = anyhow::Result<()>;
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+use tracing::{debug, info};
+
+use crate::{
+    images, layers,
+    registry::{self, Digest, ImageConfig, Manifest, Platform},
+};
+
+/// What `docker save` images get as their manifest, it doesn't write one itself
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// The OCI spec lets a manifest leave out its own media type
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// One image of a `docker save` archive's manifest.json, paths are relative to the archive
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveEntry {
+    config: String,
+    /// null for an untagged image
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// index.json of an OCI image layout, or a multi-platform index blob inside one
+#[derive(Deserialize, Debug)]
+struct OciIndex {
+    manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OciDescriptor {
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// An image found in the extracted archive
+struct ArchiveImage {
+    config: PathBuf,
+    /// As stored in an OCI layout, `docker save` images get one made up
+    manifest_raw: Option<Vec<u8>>,
+    /// Blob and its digest, if the archive names it
+    layers: Vec<(PathBuf, Option<String>)>,
+    names: Vec<String>,
+}
+
+/// An image `woody load` put into the store
+#[derive(Debug)]
+pub struct LoadedImage {
+    /// Config digest hex, like [`LocalImage::id`](crate::LocalImage::id)
+    pub id: String,
+    /// References it was tagged as, none if the archive didn't name it
+    pub names: Vec<String>,
+}
+
+/// Import the images of a `docker save` archive or an OCI image layout tarball at
+/// `input`, `-` for stdin, into the store at `root`, tagged as the archive names them.
+///
+/// The archive is extracted to a scratch directory first, its layers then go into
+/// the shared layer store the same way pulled ones do.
+pub fn load_archive(input: &Path, root: &Path) -> anyhow::Result<Vec<LoadedImage>> {
+    let reader: Box<dyn Read> = if input == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(fs::File::open(input).with_context(|| format!("Failed to open {}", input.display()))?)
+    };
+
+    let _lock = images::lock_store(root, false)?;
+    let staging = root.join(format!("load-{}.partial", std::process::id()));
+    if staging.exists() {
+        fs::remove_dir_all(&staging).with_context(|| format!("Failed to clear {}", staging.display()))?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    let loaded = extract(reader, &staging)
+        .with_context(|| format!("Failed to read image archive {}", input.display()))
+        .and_then(|_| load_extracted(&staging, root));
+    fs::remove_dir_all(&staging).with_context(|| format!("Failed to remove {}", staging.display()))?;
+
+    loaded
+}
+
+/// Unpack the archive itself, gzip compressed or not
+fn extract(reader: impl Read, dest: &Path) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let gzip = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+
+    let reader: Box<dyn Read> = if gzip { Box::new(flate2::read::GzDecoder::new(reader)) } else { Box::new(reader) };
+    tar::Archive::new(reader).unpack(dest)?;
+
+    Ok(())
+}
+
+fn load_extracted(staging: &Path, root: &Path) -> anyhow::Result<Vec<LoadedImage>> {
+    // Recent docker versions write both, manifest.json is the one with the tags
+    let images = if staging.join("manifest.json").is_file() {
+        docker_archive(staging)?
+    } else if staging.join("index.json").is_file() {
+        oci_layout(staging)?
+    } else {
+        bail!("Not an image archive, it has neither a manifest.json nor an index.json");
+    };
+
+    if images.is_empty() {
+        bail!("The archive contains no images");
+    }
+
+    images.into_iter().map(|image| store(root, image)).collect()
+}
+
+fn docker_archive(staging: &Path) -> anyhow::Result<Vec<ArchiveImage>> {
+    let entries: Vec<ArchiveEntry> = read_json(&staging.join("manifest.json"))?;
+
+    entries.into_iter()
+        .map(|entry| {
+            Ok(ArchiveImage {
+                config: archive_path(staging, &entry.config)?,
+                manifest_raw: None,
+                layers: entry.layers.iter()
+                    .map(|layer| Ok((archive_path(staging, layer)?, None)))
+                    .collect::<anyhow::Result<_>>()?,
+                names: entry.repo_tags.unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+fn oci_layout(staging: &Path) -> anyhow::Result<Vec<ArchiveImage>> {
+    let index: OciIndex = read_json(&staging.join("index.json"))?;
+
+    let mut images = Vec::new();
+    for descriptor in index.manifests {
+        let names = image_names(&descriptor.annotations);
+        let mut raw = fs::read(blob_path(staging, &descriptor.digest)?)
+            .with_context(|| format!("Missing manifest blob {}", descriptor.digest))?;
+
+        let mut value: Value = serde_json::from_slice(&raw)
+            .with_context(|| format!("Corrupted manifest blob {}", descriptor.digest))?;
+        // A multi-platform image, only the host's one is loaded
+        if value.get("manifests").is_some() {
+            let nested: OciIndex = serde_json::from_value(value)
+                .with_context(|| format!("Corrupted index blob {}", descriptor.digest))?;
+            let host = Platform::host();
+            let chosen = nested.manifests.into_iter()
+                .find(|manifest| manifest.platform.as_ref()
+                    .is_some_and(|platform| host.matches(&platform.os, &platform.architecture, platform.variant.as_deref())))
+                .with_context(|| format!("Index {} has no image for {}", descriptor.digest, host))?;
+
+            raw = fs::read(blob_path(staging, &chosen.digest)?)
+                .with_context(|| format!("Missing manifest blob {}", chosen.digest))?;
+            value = serde_json::from_slice(&raw)
+                .with_context(|| format!("Corrupted manifest blob {}", chosen.digest))?;
+        }
+
+        if value.get("mediaType").is_none() {
+            value["mediaType"] = Value::from(OCI_MANIFEST);
+            raw = serde_json::to_vec(&value)?;
+        }
+        let manifest: Manifest = serde_json::from_value(value)
+            .with_context(|| format!("Corrupted manifest blob {}", descriptor.digest))?;
+
+        images.push(ArchiveImage {
+            config: blob_path(staging, &manifest.config.digest)?,
+            manifest_raw: Some(raw),
+            layers: manifest.layers.iter()
+                .map(|layer| Ok((blob_path(staging, &layer.digest)?, Some(layer.digest.clone()))))
+                .collect::<anyhow::Result<_>>()?,
+            names,
+        });
+    }
+
+    Ok(images)
+}
+
+/// References an OCI layout names an image by. `ref.name` is often a bare tag, it
+/// only names the image when it includes the repository.
+fn image_names(annotations: &HashMap<String, String>) -> Vec<String> {
+    let containerd = annotations.get("io.containerd.image.name");
+    let ref_name = annotations.get("org.opencontainers.image.ref.name")
+        .filter(|name| name.contains(['/', ':']));
+
+    containerd.or(ref_name).cloned().into_iter().collect()
+}
+
+/// Copy one image into the store and tag it, skipping what's already stored
+fn store(root: &Path, image: ArchiveImage) -> anyhow::Result<LoadedImage> {
+    let config_raw = fs::read(&image.config)
+        .with_context(|| format!("Failed to read image config {}", image.config.display()))?;
+    let _: ImageConfig = serde_json::from_slice(&config_raw)
+        .with_context(|| format!("Corrupted image config {}", image.config.display()))?;
+    let id = format!("{:x}", Sha256::digest(&config_raw));
+
+    let image_path = images::image_path(root, &id);
+    if image_path.exists() {
+        info!("Image sha256:{} is already stored", id);
+    } else {
+        // Built next to its final place and renamed in, like a pulled image
+        let partial = root.join(format!("{}.partial", id));
+        if partial.exists() {
+            fs::remove_dir_all(&partial).with_context(|| format!("Failed to clear {}", partial.display()))?;
+        }
+        let layers_path = partial.join("layers");
+        fs::create_dir_all(&layers_path)?;
+
+        let mut layer_digests = Vec::new();
+        for (index, (blob, expected)) in image.layers.iter().enumerate() {
+            let digest = layers::blob_digest(blob)?;
+            if let Some(expected) = expected.as_ref().filter(|expected| **expected != digest) {
+                bail!("Layer {} is corrupted, its content hashes to {}", expected, digest);
+            }
+
+            if !layers::link_shared_layer(root, &digest, &layers_path, index)? {
+                debug!("Extracting layer {}", digest);
+                layers::store_shared_layer(root, &digest, blob, &layers_path, index)?;
+            }
+            layer_digests.push(Digest { digest, size: Some(fs::metadata(blob)?.len()) });
+        }
+
+        let manifest_raw = match image.manifest_raw {
+            Some(raw) => raw,
+            None => serde_json::to_vec(&Manifest {
+                schema_version: 2,
+                media_type: DOCKER_MANIFEST.to_string(),
+                config: Digest { digest: format!("sha256:{}", id), size: Some(config_raw.len() as u64) },
+                layers: layer_digests,
+            })?,
+        };
+
+        fs::write(partial.join("manifest.json"), &manifest_raw)?;
+        fs::write(partial.join("config.json"), &config_raw)?;
+        fs::rename(&partial, &image_path)
+            .with_context(|| format!("Failed to move image into {}", image_path.display()))?;
+    }
+
+    let mut names = Vec::new();
+    for name in image.names {
+        let (repository, reference) = registry::parse_image_name(&name)?;
+        images::tag(root, &repository, &reference, &id)?;
+        names.push(name);
+    }
+
+    Ok(LoadedImage { id, names })
+}
+
+/// `blobs/<algorithm>/<hex>` of an OCI layout
+fn blob_path(staging: &Path, digest: &str) -> anyhow::Result<PathBuf> {
+    let (algorithm, hex) = digest.split_once(':')
+        .with_context(|| format!("Invalid digest {:?}", digest))?;
+    archive_path(staging, &format!("blobs/{}/{}", algorithm, hex))
+}
+
+/// A path the archive's metadata refers to, which has to stay inside the archive
+fn archive_path(staging: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    if !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        bail!("Archive refers to {}, outside of the archive", path.display());
+    }
+
+    Ok(staging.join(path))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_slice(&content).with_context(|| format!("Corrupted archive file {}", path.display()))
+}
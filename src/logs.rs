@@ -0,0 +1,218 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    os::unix::io::RawFd,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use nix::{errno::Errno, unistd::{read, Pid}};
+use serde::{Deserialize, Serialize};
+
+use crate::{paths, state::{ContainerState, Status}};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Output bytes as-is, stdout and stderr interleaved
+    #[default]
+    Raw,
+    /// One `{"log","stream","time"}` object per line, like docker's json-file driver
+    JsonFile,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "raw" => Ok(LogFormat::Raw),
+            "json-file" => Ok(LogFormat::JsonFile),
+            other => bail!("Unknown log format {:?}, expected raw or json-file", other),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonLogEntry {
+    log: String,
+    stream: Stream,
+    time: String,
+}
+
+pub fn default_log_path(container_id: &str) -> PathBuf {
+    paths::container_dir(container_id).join("container.log")
+}
+
+/// Shared sink for the container's output streams
+#[derive(Clone)]
+pub struct LogWriter {
+    file: Arc<Mutex<File>>,
+    format: LogFormat,
+    /// Also echo to woody's own stdout/stderr
+    tee: bool,
+}
+
+impl LogWriter {
+    pub fn create(path: &Path, format: LogFormat, tee: bool) -> anyhow::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+
+        Ok(LogWriter { file: Arc::new(Mutex::new(file)), format, tee })
+    }
+
+    /// Copy everything read from `fd` into the log until EOF, then close `fd`
+    pub fn pump(self, fd: RawFd, stream: Stream) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut pending = Vec::new();
+
+            loop {
+                let n = match read(fd, &mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(Errno::EINTR) => continue,
+                    // A pty master reports EIO once the container side is gone
+                    Err(_) => break,
+                };
+
+                if self.tee {
+                    let mut out: Box<dyn Write> = match stream {
+                        Stream::Stdout => Box::new(std::io::stdout()),
+                        Stream::Stderr => Box::new(std::io::stderr()),
+                    };
+                    out.write_all(&buf[..n]).ok();
+                    out.flush().ok();
+                }
+
+                match self.format {
+                    LogFormat::Raw => self.append(&buf[..n]),
+                    LogFormat::JsonFile => {
+                        pending.extend_from_slice(&buf[..n]);
+                        while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+                            let line: Vec<u8> = pending.drain(..=pos).collect();
+                            self.append_json(stream, &line);
+                        }
+                    }
+                }
+            }
+
+            if !pending.is_empty() {
+                self.append_json(stream, &pending);
+            }
+            nix::unistd::close(fd).ok();
+        })
+    }
+
+    fn append(&self, data: &[u8]) {
+        if let Ok(mut file) = self.file.lock() {
+            file.write_all(data).ok();
+        }
+    }
+
+    fn append_json(&self, stream: Stream, line: &[u8]) {
+        let entry = JsonLogEntry {
+            log: String::from_utf8_lossy(line).into_owned(),
+            stream,
+            time: rfc3339_now(),
+        };
+
+        if let Ok(mut json) = serde_json::to_vec(&entry) {
+            json.push(b'\n');
+            self.append(&json);
+        }
+    }
+}
+
+/// Print a container's log, with `follow` keep printing until it exits
+pub fn print_logs(id: &str, follow: bool) -> anyhow::Result<()> {
+    let state = ContainerState::load(id)?;
+    let path = state.log_path.clone().unwrap_or_else(|| default_log_path(id));
+    let mut file = File::open(&path)
+        .with_context(|| format!("No logs found for container {}", id))?;
+
+    let mut pending = Vec::new();
+    loop {
+        let mut chunk = Vec::new();
+        file.read_to_end(&mut chunk)?;
+
+        if chunk.is_empty() {
+            if !follow || !is_running(id) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(250));
+            continue;
+        }
+
+        match state.log_format {
+            LogFormat::Raw => {
+                std::io::stdout().write_all(&chunk)?;
+                std::io::stdout().flush()?;
+            }
+            LogFormat::JsonFile => {
+                // A follow read may stop in the middle of a line
+                pending.extend_from_slice(&chunk);
+                while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=pos).collect();
+                    print_json_line(&line)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_json_line(line: &[u8]) -> anyhow::Result<()> {
+    let entry: JsonLogEntry = serde_json::from_slice(line).context("Corrupted log entry")?;
+
+    match entry.stream {
+        Stream::Stdout => print!("{}", entry.log),
+        Stream::Stderr => eprint!("{}", entry.log),
+    }
+
+    Ok(())
+}
+
+fn is_running(id: &str) -> bool {
+    ContainerState::load(id)
+        .map(|state| state.status == Status::Running && crate::is_alive(Pid::from_raw(state.pid)))
+        .unwrap_or(false)
+}
+
+/// UTC timestamp with nanoseconds, e.g. 2024-01-02T03:04:05.123456789Z
+fn rfc3339_now() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, now.subsec_nanos()
+    )
+}
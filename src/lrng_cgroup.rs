@@ -1,3 +1,6 @@
+use crate::cgroups::{ResourceLimits, DEFAULT_CPU_PERIOD};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Controller {
     Memory,
     Cpu,
@@ -6,6 +9,81 @@ pub enum Controller {
     Devices,
     Freezer,
     NetCls,
+    Pids,
+}
+
+impl std::fmt::Display for Controller {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What went wrong in a cgroup operation, so callers can e.g. skip an unsupported
+/// controller without matching on error messages
+#[derive(Debug)]
+pub enum CgroupError {
+    /// The cgroup doesn't exist
+    NotFound,
+    /// The controller isn't available, or not enabled for this cgroup
+    Unsupported(Controller),
+    PermissionDenied,
+    /// The operation only exists on the other cgroup version
+    VersionMismatch,
+    /// The cgroup still has processes
+    Busy,
+    InvalidArgument(String),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for CgroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CgroupError::NotFound => f.write_str("cgroup not found"),
+            CgroupError::Unsupported(controller) => write!(f, "the {} controller is not available", controller),
+            CgroupError::PermissionDenied => f.write_str("permission denied"),
+            CgroupError::VersionMismatch => f.write_str("not supported by this cgroup version"),
+            CgroupError::Busy => f.write_str("cgroup still has processes"),
+            CgroupError::InvalidArgument(message) => f.write_str(message),
+            CgroupError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CgroupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CgroupError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The limits [`Cgroup::apply`] failed to set, by name
+#[derive(Debug)]
+pub struct ApplyError {
+    pub failures: Vec<(&'static str, CgroupError)>,
+}
+
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let failures = self.failures.iter()
+            .map(|(limit, e)| format!("{}: {}", limit, e))
+            .collect::<Vec<_>>();
+        write!(f, "failed to set {}", failures.join(", "))
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
+impl From<std::io::Error> for CgroupError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => CgroupError::NotFound,
+            std::io::ErrorKind::PermissionDenied => CgroupError::PermissionDenied,
+            _ if e.raw_os_error() == Some(libc::EBUSY) => CgroupError::Busy,
+            _ => CgroupError::Io(e),
+        }
+    }
 }
 
 impl Controller {
@@ -18,6 +96,29 @@ impl Controller {
             Controller::Devices => "devices",
             Controller::Freezer => "freezer",
             Controller::NetCls => "net_cls",
+            Controller::Pids => "pids",
+        }
+    }
+}
+
+/// Child of a v2 cgroup its processes live in, cgroup v2 doesn't allow a cgroup that
+/// enables controllers for its children to have processes of its own
+const LEAF: &str = "leaf";
+
+/// Resources with pressure stall information, `<resource>.pressure` on v2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressureResource {
+    Cpu,
+    Memory,
+    Io,
+}
+
+impl PressureResource {
+    fn file_name(&self) -> &'static str {
+        match self {
+            PressureResource::Cpu => "cpu.pressure",
+            PressureResource::Memory => "memory.pressure",
+            PressureResource::Io => "io.pressure",
         }
     }
 }
@@ -41,26 +142,52 @@ pub struct Cgroup {
     manager: CgroupManager,
 }
 
+/// Deletes its cgroup when dropped, after moving any processes still in it to the
+/// root cgroup, so an error or panic between create and delete doesn't leak it
+#[derive(Debug)]
+pub struct CgroupGuard {
+    cgroup: Option<Cgroup>,
+}
+
 
 #[derive(Debug, Default)]
 pub struct MemoryStats {
-    limit_in_bytes: Option<u64>,
-    usage_in_bytes: u64,
-    max_usage_in_bytes: u64,
-    failcnt: u64,
+    pub limit_in_bytes: Option<u64>,
+    pub usage_in_bytes: u64,
+    pub max_usage_in_bytes: u64,
+    pub failcnt: u64,
 }
 
 #[derive(Debug, Default)]
 pub struct CpuStats {
-    shares: Option<u64>,
-    quota: Option<i64>,
-    period: Option<u64>,
-    usage_ns: u64,
+    pub shares: Option<u64>,
+    pub quota: Option<i64>,
+    pub period: Option<u64>,
+    /// `cpu.max.burst` in microseconds, v2 only
+    pub burst: Option<u64>,
+    pub usage_ns: u64,
+}
+
+/// PSI of one resource. `some` is the share of time at least one task was stalled on
+/// it, `full` the share all non-idle tasks were, which older kernels don't report for cpu.
+#[derive(Debug, Default)]
+pub struct PressureStats {
+    pub some: PressureLine,
+    pub full: Option<PressureLine>,
+}
+
+/// Stall percentages averaged over 10s, 60s and 300s, plus the total stall time in microseconds
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PressureLine {
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    pub total: u64,
 }
 
 impl CgroupManager {
     /// Create a new cgroup manager, auto-detecting cgroup version
-    pub fn new() -> std::io::Result<Self> {
+    pub fn new() -> Result<Self, CgroupError> {
         let cgroup_root = std::path::PathBuf::from("/sys/fs/cgroup");
 
         let version = if cgroup_root.join("cgroup.controllers").exists() {
@@ -76,7 +203,7 @@ impl CgroupManager {
     }
 
     /// Create new cgroup with explicit root path
-    pub fn with_root<P: AsRef<std::path::Path>>(root: P) -> std::io::Result<Self> {
+    pub fn with_root<P: AsRef<std::path::Path>>(root: P) -> Result<Self, CgroupError> {
         let cgroup_root = root.as_ref().to_path_buf();
 
         let version = if cgroup_root.join("cgroup.controllers").exists() {
@@ -97,14 +224,41 @@ impl CgroupManager {
         &self.cgroup_version
     }
 
-    pub fn create_cgroup(&self, name: &str, controllers: &[Controller]) -> std::io::Result<Cgroup> {
+    pub fn create_cgroup(&self, name: &str, controllers: &[Controller]) -> Result<Cgroup, CgroupError> {
         match self.cgroup_version {
             CgroupVersion::V1 => self.create_cgroup_v1(name, controllers),
             CgroupVersion::V2 => self.create_cgroup_v2(name, controllers),
         }
     }
 
-    fn create_cgroup_v1(&self, name: &str, controllers: &[Controller]) -> std::io::Result<Cgroup> {
+    /// The cgroup `name`, created if it doesn't exist yet.
+    ///
+    /// An existing one, e.g. left behind by a crashed run, is reused and gets whichever
+    /// of `controllers` it lacks, so this can be called again on every (re)start.
+    pub fn get_or_create_cgroup(&self, name: &str, controllers: &[Controller]) -> Result<Cgroup, CgroupError> {
+        match self.get_cgroup(name, controllers.first().copied()) {
+            Ok(cgroup) => {
+                match self.cgroup_version {
+                    CgroupVersion::V1 => {
+                        for controller in controllers {
+                            std::fs::create_dir_all(self.cgroup_root.join(controller.as_str()).join(name))?;
+                        }
+                    }
+                    CgroupVersion::V2 => {
+                        std::fs::create_dir_all(cgroup.path.join(LEAF))?;
+                        if !controllers.is_empty() {
+                            self.enable_controllers_v2(&format!("{}/{}", name, LEAF), controllers)?;
+                        }
+                    }
+                }
+                Ok(cgroup)
+            }
+            Err(CgroupError::NotFound) => self.create_cgroup(name, controllers),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn create_cgroup_v1(&self, name: &str, controllers: &[Controller]) -> Result<Cgroup, CgroupError> {
         for controller in controllers {
             let controller_path = self.cgroup_root.join(controller.as_str()).join(name);
             std::fs::create_dir_all(&controller_path)?;
@@ -113,7 +267,7 @@ impl CgroupManager {
         let main_path = if !controllers.is_empty() {
             self.cgroup_root.join(controllers[0].as_str()).join(name)
         } else {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "At least one controller required for v1"));
+            return Err(CgroupError::InvalidArgument("At least one controller required for v1".to_string()));
         };
 
         Ok(Cgroup {
@@ -126,18 +280,13 @@ impl CgroupManager {
         })
     }
 
-    fn create_cgroup_v2(&self, name: &str, controllers: &[Controller]) -> std::io::Result<Cgroup> {
+    fn create_cgroup_v2(&self, name: &str, controllers: &[Controller]) -> Result<Cgroup, CgroupError> {
         let cgroup_path = self.cgroup_root.join(name);
-        std::fs::create_dir_all(&cgroup_path)?;
+        std::fs::create_dir_all(cgroup_path.join(LEAF))?;
 
+        // Enabled down to the cgroup itself, limits set there cover its leaf
         if !controllers.is_empty() {
-            let controllers_str = controllers.iter()
-                .map(|c| format!("+{}", c.as_str()))
-                .collect::<Vec<_>>()
-                .join(" ");
-
-            let subtree_control_path = cgroup_path.join("cgroup.subtree_control");
-            std::fs::write(&subtree_control_path, &controllers_str)?;
+            self.enable_controllers_v2(&format!("{}/{}", name, LEAF), controllers)?;
         }
 
         Ok (Cgroup {
@@ -150,17 +299,49 @@ impl CgroupManager {
         })
     }
 
-    pub fn get_cgroup(&self, name: &str, controller: Option<Controller>) -> std::io::Result<Cgroup> {
+    /// Enable `controllers` in the `cgroup.subtree_control` of every ancestor of `name`.
+    ///
+    /// A cgroup only gets a controller's interface files if its parent enabled it for
+    /// its children, which in turn needs the grandparent to, and so on up to the root.
+    fn enable_controllers_v2(&self, name: &str, controllers: &[Controller]) -> Result<(), CgroupError> {
+        let mut ancestor = self.cgroup_root.clone();
+        let mut components = std::path::Path::new(name).components().peekable();
+
+        while let Some(component) = components.next() {
+            let subtree_control_path = ancestor.join("cgroup.subtree_control");
+            let enabled = std::fs::read_to_string(&subtree_control_path).unwrap_or_default();
+
+            // Reads back as "cpu memory", writes take "+cpu +memory"
+            let missing = controllers.iter()
+                .filter(|c| !enabled.split_whitespace().any(|e| e.trim_start_matches('+') == c.as_str()))
+                .map(|c| format!("+{}", c.as_str()))
+                .collect::<Vec<_>>();
+
+            if !missing.is_empty() {
+                std::fs::write(&subtree_control_path, missing.join(" "))?;
+            }
+
+            // The new cgroup's own subtree_control is left alone, only its ancestors need it
+            if components.peek().is_none() {
+                break;
+            }
+            ancestor.push(component);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_cgroup(&self, name: &str, controller: Option<Controller>) -> Result<Cgroup, CgroupError> {
         let path = match (&self.cgroup_version, controller) {
             (CgroupVersion::V1, Some(ctrl)) => self.cgroup_root.join(ctrl.as_str()).join(name),
             (CgroupVersion::V1, None) => {
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Controller required for v1"));
+                return Err(CgroupError::InvalidArgument("Controller required for v1".to_string()));
             },
             (CgroupVersion::V2, _) => self.cgroup_root.join(name),
          };
 
         if !path.exists() {
-            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Cgroup not found"));
+            return Err(CgroupError::NotFound);
         }
 
         Ok(Cgroup {
@@ -173,11 +354,11 @@ impl CgroupManager {
         })
     }
 
-    pub fn list_cgroups(&self, controller: Option<Controller>) -> std::io::Result<Vec<String>> {
+    pub fn list_cgroups(&self, controller: Option<Controller>) -> Result<Vec<String>, CgroupError> {
         let search_path = match (&self.cgroup_version, controller) {
             (CgroupVersion::V1, Some(ctrl)) => self.cgroup_root.join(ctrl.as_str()),
             (CgroupVersion::V1, None) => {
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Controller required for v1"));
+                return Err(CgroupError::InvalidArgument("Controller required for v1".to_string()));
             }
             (CgroupVersion::V2, _) => self.cgroup_root.clone()
         };
@@ -188,22 +369,47 @@ impl CgroupManager {
         Ok(cgroups)
     }
 
+    /// Names of the cgroups below `path`, recursively.
+    ///
+    /// Only directories with a `cgroup.procs` are cgroups, anything else (v1 controller
+    /// roots hold unrelated directories) is not descended into. Symlinks are never
+    /// followed, so a link back up the tree can't loop, and an entry that can't be read
+    /// is skipped with a warning instead of failing the whole listing.
     fn collect_cgroups(&self, path: &std::path::Path, prefix: &str, cgroups: &mut Vec<String>) -> std::io::Result<()> {
         for entry in std::fs::read_dir(path)? {
-            let entry = entry?;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    tracing::warn!("Skipping an entry of {}: {}", path.display(), e);
+                    continue;
+                }
+            };
             let entry_path = entry.path();
 
-            if entry_path.is_dir() {
-                let name = entry.file_name(); 
-                let name = name.to_string_lossy();
-                let full_name = if prefix.is_empty() {
-                    name.to_string()
-                } else {
-                    format!("{}/{}", prefix, name)
-                };
+            // file_type doesn't follow symlinks
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {}
+                Ok(_) => continue,
+                Err(e) => {
+                    tracing::warn!("Skipping {}: {}", entry_path.display(), e);
+                    continue;
+                }
+            }
+            if !entry_path.join("cgroup.procs").exists() {
+                continue;
+            }
 
-                cgroups.push(full_name.clone());
-                self.collect_cgroups(&entry_path, &full_name, cgroups)?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let full_name = if prefix.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+
+            cgroups.push(full_name.clone());
+            if let Err(e) = self.collect_cgroups(&entry_path, &full_name, cgroups) {
+                tracing::warn!("Skipping the children of {}: {}", entry_path.display(), e);
             }
         }
 
@@ -220,23 +426,20 @@ impl Cgroup {
         &self.path
     }
 
-    pub fn add_process(&self, pid: u32) -> std::io::Result<()> {
-        let procs_file = match self.manager.cgroup_version {
-            CgroupVersion::V1 => self.path.join("cgroup.procs"),
-            CgroupVersion::V2 => self.path.join("cgroup.procs")
-        };
+    pub fn add_process(&self, pid: u32) -> Result<(), CgroupError> {
+        let procs_file = self.process_dir().join("cgroup.procs");
 
         std::fs::write(&procs_file, pid.to_string())?;
         Ok(())
     }
 
-    pub fn add_current_process(&self) -> std::io::Result<()> {
+    pub fn add_current_process(&self) -> Result<(), CgroupError> {
         let pid = std::process::id();
         self.add_process(pid)
     }
 
-    pub fn get_processes(&self) -> std::io::Result<Vec<u32>> {
-        let procs_file = self.path.join("cgroup.procs");
+    pub fn get_processes(&self) -> Result<Vec<u32>, CgroupError> {
+        let procs_file = self.process_dir().join("cgroup.procs");
         let content = std::fs::read_to_string(&procs_file)?;
 
         let mut pids = Vec::new();
@@ -250,26 +453,53 @@ impl Cgroup {
     }
 
     /// Set memory limit
-    pub fn set_memory_limit(&self, limit_bytes: u64) -> std::io::Result<()> {
+    pub fn set_memory_limit(&self, limit_bytes: u64) -> Result<(), CgroupError> {
         let limit_file = match self.manager.cgroup_version {
             CgroupVersion::V1 => self.get_controller_path(Controller::Memory)?.join("memory.limit_in_bytes"),
             CgroupVersion::V2 => self.path.join("memory.max"),
         };
 
-        std::fs::write(&limit_file, limit_bytes.to_string())?;
+        write_controller_file(&limit_file, &limit_bytes.to_string(), Controller::Memory)?;
         Ok(())
     }
 
+    /// Limit memory plus swap to `limit_bytes`, -1 is unlimited. Needs the memory limit set first.
+    pub fn set_memory_swap_limit(&self, limit_bytes: i64) -> Result<(), CgroupError> {
+        match self.manager.cgroup_version {
+            // Missing without swap accounting, e.g. swapaccount=0
+            CgroupVersion::V1 => {
+                let memsw_file = self.get_controller_path(Controller::Memory)?.join("memory.memsw.limit_in_bytes");
+                write_controller_file(&memsw_file, &limit_bytes.to_string(), Controller::Memory)
+            }
+            // v2 limits swap on its own, what's left after the memory limit
+            CgroupVersion::V2 => {
+                let swap = match u64::try_from(limit_bytes) {
+                    Err(_) => "max".to_string(),
+                    Ok(limit) => {
+                        let memory = std::fs::read_to_string(self.path.join("memory.max"))?;
+                        let memory = memory.trim().parse::<u64>().map_err(|_| CgroupError::InvalidArgument(
+                            "a memory and swap limit needs a memory limit".to_string(),
+                        ))?;
+                        limit.checked_sub(memory).ok_or_else(|| CgroupError::InvalidArgument(
+                            format!("memory and swap limit {} is below the memory limit {}", limit, memory),
+                        ))?.to_string()
+                    }
+                };
+                write_controller_file(&self.path.join("memory.swap.max"), &swap, Controller::Memory)
+            }
+        }
+    }
+
 
     /// Get memory statistics
-    pub fn get_memory_stats(&self) -> std::io::Result<MemoryStats> {
+    pub fn get_memory_stats(&self) -> Result<MemoryStats, CgroupError> {
         match self.manager.cgroup_version {
             CgroupVersion::V1 => self.get_memory_stats_v1(),
             CgroupVersion::V2 => self.get_memory_stats_v2(),
         }
     }
 
-    fn get_memory_stats_v1(&self) -> std::io::Result<MemoryStats> {
+    fn get_memory_stats_v1(&self) -> Result<MemoryStats, CgroupError> {
         let mem_path = self.get_controller_path(Controller::Memory)?;
 
         let mut stats = MemoryStats::default();
@@ -312,7 +542,7 @@ impl Cgroup {
     }
 
 
-    fn get_memory_stats_v2(&self) -> std::io::Result<MemoryStats> {
+    fn get_memory_stats_v2(&self) -> Result<MemoryStats, CgroupError> {
         let mut stats = MemoryStats::default();
 
         // Read limit
@@ -340,15 +570,9 @@ impl Cgroup {
         // Read detailed stats
         if let Ok(content) = std::fs::read_to_string(self.path.join("memory.stat")) {
             for line in content.lines() {
-                if let Some((key, value)) = line.split_once(' ') {
-
-                    match key {
-                        "oom_kill" => {
-                            if let Ok(count) = value.parse::<u64>() {
-                                stats.failcnt = count;
-                            }
-                        }
-                        _ => {}
+                if let Some(("oom_kill", value)) = line.split_once(' ') {
+                    if let Ok(count) = value.parse::<u64>() {
+                        stats.failcnt = count;
                     }
                 }
             }
@@ -359,8 +583,26 @@ impl Cgroup {
         Ok(stats)
     }
 
+    /// Number of processes currently in the cgroup, as counted by the pids controller
+    pub fn get_pids_current(&self) -> Result<u64, CgroupError> {
+        let pids_path = self.get_controller_path(Controller::Pids)?;
+        let content = std::fs::read_to_string(pids_path.join("pids.current")).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => CgroupError::Unsupported(Controller::Pids),
+            _ => e.into(),
+        })?;
+
+        content.trim().parse::<u64>()
+            .map_err(|e| CgroupError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+    }
+
+    /// Cap the number of processes, fork fails with EAGAIN beyond it
+    pub fn set_pids_limit(&self, max: u32) -> Result<(), CgroupError> {
+        let pids_path = self.get_controller_path(Controller::Pids)?;
+        write_controller_file(&pids_path.join("pids.max"), &max.to_string(), Controller::Pids)
+    }
+
     /// Set CPU shares (relative weight)
-    pub fn set_cpu_shares(&self, shares: u64) -> std::io::Result<()> {
+    pub fn set_cpu_shares(&self, shares: u64) -> Result<(), CgroupError> {
         let shares_file = match self.manager.cgroup_version {
             CgroupVersion::V1 => self.get_controller_path(Controller::Cpu)?.join("cpu.shares"),
             CgroupVersion::V2 => self.path.join("cpu.weight"),
@@ -368,27 +610,22 @@ impl Cgroup {
 
         let value = match self.manager.cgroup_version {
             CgroupVersion::V1 => shares.to_string(),
-            CgroupVersion::V2 => {
-                // Convert from v1 shares (1024 default) to v2 weight (100 default)
-
-                let weight = (shares * 100) / 1024;
-                weight.to_string()
-            }
+            CgroupVersion::V2 => shares_to_weight(shares).to_string(),
         };
 
 
-        std::fs::write(&shares_file, value)?;
+        write_controller_file(&shares_file, &value, Controller::Cpu)?;
         Ok(())
     }
 
     /// Set CPU quota (microseconds per period)
-    pub fn set_cpu_quota(&self, quota_us: i64, period_us: u64) -> std::io::Result<()> {
+    pub fn set_cpu_quota(&self, quota_us: i64, period_us: u64) -> Result<(), CgroupError> {
         match self.manager.cgroup_version {
             CgroupVersion::V1 => {
                 let cpu_path = self.get_controller_path(Controller::Cpu)?;
-                std::fs::write(cpu_path.join("cpu.cfs_quota_us"), quota_us.to_string())?;
+                write_controller_file(&cpu_path.join("cpu.cfs_quota_us"), &quota_us.to_string(), Controller::Cpu)?;
 
-                std::fs::write(cpu_path.join("cpu.cfs_period_us"), period_us.to_string())?;
+                write_controller_file(&cpu_path.join("cpu.cfs_period_us"), &period_us.to_string(), Controller::Cpu)?;
             }
             CgroupVersion::V2 => {
                 let quota_str = if quota_us < 0 {
@@ -397,22 +634,89 @@ impl Cgroup {
 
                     format!("{} {}", quota_us, period_us)
                 };
-                std::fs::write(self.path.join("cpu.max"), quota_str)?;
+                write_controller_file(&self.path.join("cpu.max"), &quota_str, Controller::Cpu)?;
             }
         }
         Ok(())
     }
 
 
+    /// Let the cgroup bank up to `burst_us` of unused quota for short bursts above it.
+    ///
+    /// v2 only. The kernel rejects a burst larger than the quota, so a quota has to be
+    /// set with [`set_cpu_quota`](Cgroup::set_cpu_quota) first.
+    pub fn set_cpu_burst(&self, burst_us: u64) -> Result<(), CgroupError> {
+        if let CgroupVersion::V1 = self.manager.cgroup_version {
+            return Err(CgroupError::VersionMismatch);
+        }
+
+        let quota = match self.get_cpu_stats()?.quota {
+            Some(quota) => quota,
+            None => return Err(CgroupError::InvalidArgument(
+                "No CPU quota is set, call set_cpu_quota before set_cpu_burst".to_string(),
+            )),
+        };
+        if burst_us > quota as u64 {
+            return Err(CgroupError::InvalidArgument(
+                format!("CPU burst {}us exceeds the quota of {}us", burst_us, quota),
+            ));
+        }
+
+        write_controller_file(&self.path.join("cpu.max.burst"), &burst_us.to_string(), Controller::Cpu)?;
+        Ok(())
+    }
+
+    /// Set every limit in `limits` that is set, leaving the others alone.
+    ///
+    /// A failing limit doesn't stop the rest from being applied, the error lists all that failed.
+    pub fn apply(&self, limits: &ResourceLimits) -> Result<(), ApplyError> {
+        let mut failures = Vec::new();
+
+        if let Some(memory) = limits.memory {
+            if let Err(e) = self.set_memory_limit(memory) {
+                failures.push(("memory", e));
+            }
+        }
+        if let Some(memory_swap) = limits.memory_swap {
+            if let Err(e) = self.set_memory_swap_limit(memory_swap) {
+                failures.push(("memory swap", e));
+            }
+        }
+        if let Some(pids) = limits.pids {
+            if let Err(e) = self.set_pids_limit(pids) {
+                failures.push(("pids", e));
+            }
+        }
+        if let Some(shares) = limits.cpu_shares {
+            if let Err(e) = self.set_cpu_shares(shares) {
+                failures.push(("cpu shares", e));
+            }
+        }
+        if let Some(quota) = limits.cpu_quota {
+            if let Err(e) = self.set_cpu_quota(quota, limits.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD)) {
+                failures.push(("cpu quota", e));
+            }
+        } else if limits.cpu_period.is_some() {
+            let e = CgroupError::InvalidArgument("a CPU period needs a quota".to_string());
+            failures.push(("cpu period", e));
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(ApplyError { failures })
+        }
+    }
+
     /// Get CPU statistics
-    pub fn get_cpu_stats(&self) -> std::io::Result<CpuStats> {
+    pub fn get_cpu_stats(&self) -> Result<CpuStats, CgroupError> {
         match self.manager.cgroup_version {
             CgroupVersion::V1 => self.get_cpu_stats_v1(),
             CgroupVersion::V2 => self.get_cpu_stats_v2(),
         }
     }
 
-    fn get_cpu_stats_v1(&self) -> std::io::Result<CpuStats> {
+    fn get_cpu_stats_v1(&self) -> Result<CpuStats, CgroupError> {
         let cpu_path = self.get_controller_path(Controller::Cpu)?;
         let mut stats = CpuStats::default();
 
@@ -455,7 +759,7 @@ impl Cgroup {
 
     }
 
-    fn get_cpu_stats_v2(&self) -> std::io::Result<CpuStats> {
+    fn get_cpu_stats_v2(&self) -> Result<CpuStats, CgroupError> {
         let mut stats = CpuStats::default();
 
         // Read weight (convert to shares)
@@ -471,7 +775,7 @@ impl Cgroup {
 
         // Read quota
         if let Ok(content) = std::fs::read_to_string(self.path.join("cpu.max")) {
-            let parts: Vec<&str> = content.trim().split_whitespace().collect();
+            let parts: Vec<&str> = content.split_whitespace().collect();
             if parts.len() == 2 {
                 if parts[0] != "max" {
                     if let Ok(quota) = parts[0].parse::<i64>() {
@@ -487,6 +791,13 @@ impl Cgroup {
             }
         }
 
+        // Read burst, kernels before 5.14 don't have it
+        if let Ok(content) = std::fs::read_to_string(self.path.join("cpu.max.burst")) {
+            if let Ok(burst) = content.trim().parse::<u64>() {
+                stats.burst = Some(burst);
+            }
+        }
+
         // Read usage
         if let Ok(content) = std::fs::read_to_string(self.path.join("cpu.stat")) {
             for line in content.lines() {
@@ -505,8 +816,41 @@ impl Cgroup {
         Ok(stats)
     }
 
+    /// Share of the host's total CPU time the cgroup used over `interval`, 0 to 100.
+    ///
+    /// Blocks for `interval`. Both versions report usage in nanoseconds through
+    /// [`get_cpu_stats`](Cgroup::get_cpu_stats), so the result is comparable across them.
+    pub fn cpu_usage_percent(&self, interval: std::time::Duration) -> Result<f64, CgroupError> {
+        let before = self.get_cpu_stats()?.usage_ns;
+        let start = std::time::Instant::now();
+        std::thread::sleep(interval);
+        let after = self.get_cpu_stats()?.usage_ns;
+
+        Ok(cpu_percent(after.saturating_sub(before), start.elapsed()))
+    }
+
+    /// Pressure stall information for `resource`, `VersionMismatch` on v1 and `NotFound` on kernels without PSI
+    pub fn get_pressure(&self, resource: PressureResource) -> Result<PressureStats, CgroupError> {
+        if let CgroupVersion::V1 = self.manager.cgroup_version {
+            return Err(CgroupError::VersionMismatch);
+        }
+
+        let content = std::fs::read_to_string(self.path.join(resource.file_name()))?;
+
+        let mut stats = PressureStats::default();
+        for line in content.lines() {
+            match line.split_once(' ') {
+                Some(("some", fields)) => stats.some = parse_pressure_line(fields)?,
+                Some(("full", fields)) => stats.full = Some(parse_pressure_line(fields)?),
+                _ => {}
+            }
+        }
+
+        Ok(stats)
+    }
+
     /// Freeze all processes in this cgroup
-    pub fn freeze(&self) -> std::io::Result<()> {
+    pub fn freeze(&self) -> Result<(), CgroupError> {
         let freeze_file = match self.manager.cgroup_version {
             CgroupVersion::V1 => self.get_controller_path(Controller::Freezer)?.join("freezer.state"),
             CgroupVersion::V2 => self.path.join("cgroup.freeze"),
@@ -517,12 +861,12 @@ impl Cgroup {
             CgroupVersion::V2 => "1",
         };
 
-        std::fs::write(&freeze_file, freeze_value)?;
+        write_controller_file(&freeze_file, freeze_value, Controller::Freezer)?;
         Ok(())
     }
 
     /// Unfreeze all processes in this cgroup
-    pub fn unfreeze(&self) -> std::io::Result<()> {
+    pub fn unfreeze(&self) -> Result<(), CgroupError> {
         let freeze_file = match self.manager.cgroup_version {
             CgroupVersion::V1 => self.get_controller_path(Controller::Freezer)?.join("freezer.state"),
             CgroupVersion::V2 => self.path.join("cgroup.freeze"),
@@ -534,25 +878,19 @@ impl Cgroup {
             CgroupVersion::V2 => "0",
         };
 
-        std::fs::write(&freeze_file, unfreeze_value)?;
+        write_controller_file(&freeze_file, unfreeze_value, Controller::Freezer)?;
 
         Ok(())
     }
 
     /// Delete this cgroup
-
-    pub fn delete(&self) -> std::io::Result<()> {
+    pub fn delete(&self) -> Result<(), CgroupError> {
         // First, make sure no processes are in the cgroup
         let procs = self.get_processes()?;
         if !procs.is_empty() {
-
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::PermissionDenied,
-                "Cannot delete cgroup with active processes",
-            ));
+            return Err(CgroupError::Busy);
         }
 
-
         match self.manager.cgroup_version {
             CgroupVersion::V1 => {
                 // For v1, we need to remove from all controller hierarchies
@@ -566,6 +904,10 @@ impl Cgroup {
                 }
             }
             CgroupVersion::V2 => {
+                let leaf = self.path.join(LEAF);
+                if leaf.exists() {
+                    std::fs::remove_dir(&leaf)?;
+                }
                 std::fs::remove_dir(&self.path)?;
             }
         }
@@ -573,8 +915,41 @@ impl Cgroup {
 
     }
 
+    /// Move every process of this cgroup to the root cgroup, so it can be deleted
+    fn move_processes_to_root(&self) -> Result<(), CgroupError> {
+        let roots = match self.manager.cgroup_version {
+            CgroupVersion::V1 => [Controller::Memory, Controller::Cpu, Controller::CpuSet,
+                                  Controller::BlkIo, Controller::Devices, Controller::Freezer]
+                .iter()
+                .map(|controller| self.manager.cgroup_root.join(controller.as_str()))
+                .filter(|root| root.join(&self.name).exists())
+                .collect(),
+            CgroupVersion::V2 => vec![self.manager.cgroup_root.clone()],
+        };
+
+        for pid in self.get_processes()? {
+            for root in &roots {
+                match std::fs::write(root.join("cgroup.procs"), pid.to_string()) {
+                    // Exited in the meantime
+                    Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+                    result => result?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Where processes are added, the leaf on v2 unless the cgroup was created without one
+    fn process_dir(&self) -> std::path::PathBuf {
+        match self.manager.cgroup_version {
+            CgroupVersion::V2 if self.path.join(LEAF).is_dir() => self.path.join(LEAF),
+            _ => self.path.clone(),
+        }
+    }
+
     // Helper method to get controller-specific path for v1
-    fn get_controller_path(&self, controller: Controller) -> std::io::Result<std::path::PathBuf> {
+    fn get_controller_path(&self, controller: Controller) -> Result<std::path::PathBuf, CgroupError> {
 
         match self.manager.cgroup_version {
 
@@ -587,208 +962,145 @@ impl Cgroup {
     }
 }
 
-/// Example usage and demonstrations
-pub mod examples {
-    use super::*;
-    use std::thread;
-    use std::time::Duration;
-
-    /// Create a memory-limited cgroup and add the current process
-    pub fn memory_limit_example() -> std::io::Result<()> {
-        println!("=== Memory Limit Example ===");
-        
-
-        let manager = CgroupManager::new()?;
-        println!("Using cgroups {:?}", manager.version());
-
-        // Create a cgroup with memory controller
-        let cgroup = manager.create_cgroup("memory_test", &[Controller::Memory])?;
-
-        println!("Created cgroup: {}", cgroup.name());
-
-        // Set memory limit to 100MB
-        cgroup.set_memory_limit(100 * 1024 * 1024)?;
-        println!("Set memory limit to 100MB");
-
-
-        // Add current process
-        cgroup.add_current_process()?;
-        println!("Added current process to cgroup");
-
-
-        // Get and display memory stats
-        let stats = cgroup.get_memory_stats()?;
-        println!("Memory stats: {:?}", stats);
-
-        // Clean up
-        // Note: You'd need to move the process out first in a real scenario
-        println!("Example completed (manual cleanup required)");
-
-        
-        Ok(())
+impl CgroupGuard {
+    pub fn new(cgroup: Cgroup) -> Self {
+        CgroupGuard { cgroup: Some(cgroup) }
     }
 
-    /// Create a CPU-limited cgroup
-    pub fn cpu_limit_example() -> std::io::Result<()> {
-        println!("=== CPU Limit Example ===");
-        
-        let manager = CgroupManager::new()?;
-        
-
-        // Create a cgroup with CPU controller
-        let cgroup = manager.create_cgroup("cpu_test", &[Controller::Cpu])?;
-
-        println!("Created cgroup: {}", cgroup.name());
-
-        // Set CPU shares (relative to other cgroups)
-        cgroup.set_cpu_shares(512)?; // Half the default priority
-        println!("Set CPU shares to 512");
-
-
-        // Set CPU quota: 50ms every 100ms (50% of one CPU)
-        cgroup.set_cpu_quota(50_000, 100_000)?;
-        println!("Set CPU quota to 50% of one CPU");
-
-        // Get and display CPU stats
-        let stats = cgroup.get_cpu_stats()?;
-        println!("CPU stats: {:?}", stats);
-
-        Ok(())
+    /// Disarm the guard, for a cgroup that should outlive it
+    pub fn into_persistent(mut self) -> Cgroup {
+        self.cgroup.take().expect("the guard holds its cgroup until dropped")
     }
+}
 
+impl std::ops::Deref for CgroupGuard {
+    type Target = Cgroup;
 
-    /// Demonstrate process freezing
-
-    pub fn freeze_example() -> std::io::Result<()> {
-        println!("=== Freeze Example ===");
-        
-        let manager = CgroupManager::new()?;
-        
-        // Create a cgroup with freezer controller
-        let cgroup = manager.create_cgroup("freeze_test", &[Controller::Freezer])?;
-        println!("Created cgroup: {}", cgroup.name());
-
-        // In a real scenario, you'd add a different process here
-        println!("Would freeze processes here...");
-        
-        // Demonstrate freeze/unfreeze (commented out to avoid freezing the example)
-        // cgroup.freeze()?;
-        // println!("Frozen all processes in cgroup");
-        // 
-        // thread::sleep(Duration::from_secs(2));
-        // 
-        // cgroup.unfreeze()?;
-        // println!("Unfrozen all processes in cgroup");
-
-        Ok(())
+    fn deref(&self) -> &Cgroup {
+        self.cgroup.as_ref().expect("the guard holds its cgroup until dropped")
     }
+}
 
-    /// List all cgroups
-    pub fn list_cgroups_example() -> std::io::Result<()> {
-        println!("=== List cgroups Example ===");
-        
-
-        let manager = CgroupManager::new()?;
-        
-        match manager.version() {
-
-            CgroupVersion::V1 => {
-                println!("Memory cgroups:");
-                let cgroups = manager.list_cgroups(Some(Controller::Memory))?;
-                for cgroup in cgroups.iter().take(10) { // Limit output
-                    println!("  {}", cgroup);
-                }
-            }
-            CgroupVersion::V2 => {
-                println!("All cgroups:");
-                let cgroups = manager.list_cgroups(None)?;
-                for cgroup in cgroups.iter().take(10) { // Limit output
-                    println!("  {}", cgroup);
-                }
-
+impl Drop for CgroupGuard {
+    fn drop(&mut self) {
+        if let Some(cgroup) = self.cgroup.take() {
+            if let Err(e) = cgroup.move_processes_to_root().and_then(|()| cgroup.delete()) {
+                tracing::warn!("Failed to remove cgroup {}: {}", cgroup.name(), e);
             }
         }
-        
-        Ok(())
     }
+}
 
+/// `usage_ns` of CPU time consumed over `elapsed`, as a share of all online CPUs
+pub fn cpu_percent(usage_ns: u64, elapsed: std::time::Duration) -> f64 {
+    let available = elapsed.as_nanos() as f64 * online_cpus() as f64;
+    if available == 0.0 {
+        return 0.0;
+    }
 
-    /// Complete workflow example
-    pub fn complete_workflow() -> std::io::Result<()> {
-        println!("=== Complete Workflow Example ===");
-        
-        let manager = CgroupManager::new()?;
-        println!("Initialized cgroup manager (version: {:?})", manager.version());
-
-        // Create a cgroup with multiple controllers
-        let controllers = match manager.version() {
-            CgroupVersion::V1 => vec![Controller::Memory, Controller::Cpu],
-
-            CgroupVersion::V2 => vec![Controller::Memory, Controller::Cpu],
-        };
-
-        let cgroup = manager.create_cgroup("demo_cgroup", &controllers)?;
-        println!("Created cgroup: {}", cgroup.name());
-
-        // Configure limits
-
-        cgroup.set_memory_limit(50 * 1024 * 1024)?; // 50MB
-        cgroup.set_cpu_shares(256)?; // Low priority
-        println!("Configured resource limits");
+    usage_ns as f64 / available * 100.0
+}
 
-        // Get current stats
-        let mem_stats = cgroup.get_memory_stats()?;
-        let cpu_stats = cgroup.get_cpu_stats()?;
-        println!("Memory stats: {:?}", mem_stats);
-        println!("CPU stats: {:?}", cpu_stats);
+/// Write a controller's interface file, which only exists while the controller is enabled
+fn write_controller_file(path: &std::path::Path, value: &str, controller: Controller) -> Result<(), CgroupError> {
+    std::fs::write(path, value).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => CgroupError::Unsupported(controller),
+        _ => e.into(),
+    })
+}
 
+/// v1 shares (1024 default) as a v2 weight (100 default), rounded and clamped into
+/// the 1..=10000 range `cpu.weight` accepts
+fn shares_to_weight(shares: u64) -> u64 {
+    let weight = (shares.saturating_mul(100).saturating_add(512)) / 1024;
+    weight.clamp(1, 10000)
+}
 
-        // List processes (should be empty since we didn't add any)
-        let processes = cgroup.get_processes()?;
+/// `avg10=0.12 avg60=0.05 avg300=0.01 total=123456`
+fn parse_pressure_line(fields: &str) -> std::io::Result<PressureLine> {
+    let invalid = |field: &str| std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid pressure field {:?}", field),
+    );
+
+    let mut line = PressureLine::default();
+    for field in fields.split_whitespace() {
+        let (key, value) = field.split_once('=').ok_or_else(|| invalid(field))?;
+        match key {
+            "avg10" => line.avg10 = value.parse().map_err(|_| invalid(field))?,
+            "avg60" => line.avg60 = value.parse().map_err(|_| invalid(field))?,
+            "avg300" => line.avg300 = value.parse().map_err(|_| invalid(field))?,
+            "total" => line.total = value.parse().map_err(|_| invalid(field))?,
+            // Newer kernels may add fields
+            _ => {}
+        }
+    }
 
-        println!("Processes in cgroup: {:?}", processes);
+    Ok(line)
+}
 
-        // Clean up would happen here in a real application
-        println!("Workflow completed (cleanup required)");
-        
-        Ok(())
+pub fn online_cpus() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+        n if n > 0 => n as u64,
+        _ => 1,
     }
-
 }
 
-fn main() -> std::io::Result<()> {
-    println!("Rust cgroups Tutorial\n");
-    
-    // Check if we have the necessary permissions
-    if !std::path::Path::new("/sys/fs/cgroup").exists() {
-        eprintln!("Error: /sys/fs/cgroup not found. This tutorial requires Linux with cgroups support.");
-        eprintln!("Note: You may need root privileges to create and manage cgroups.");
-        return Ok(());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_shares_into_the_valid_weight_range() {
+        // The v1 minimum would truncate to an invalid 0
+        assert_eq!(shares_to_weight(2), 1);
+        assert_eq!(shares_to_weight(1024), 100);
+        // The v1 maximum is past the v2 one
+        assert_eq!(shares_to_weight(262144), 10000);
+        // 512 * 100 / 1024 = 50, 100 * 100 / 1024 = 9.77 rounds up
+        assert_eq!(shares_to_weight(512), 50);
+        assert_eq!(shares_to_weight(100), 10);
     }
 
-    // Run examples
-    if let Err(e) = examples::list_cgroups_example() {
-        eprintln!("List cgroups example failed: {}", e);
+    #[test]
+    fn enables_controllers_in_every_ancestor() {
+        let root = std::env::temp_dir().join(format!("woody-cgroup-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("cgroup.controllers"), "cpu memory pids").unwrap();
+        // Already enabled at the root, so only the missing one is written there
+        std::fs::write(root.join("cgroup.subtree_control"), "cpu").unwrap();
+
+        let manager = CgroupManager::with_root(&root).unwrap();
+        let cgroup = manager.create_cgroup("woody/abc", &[Controller::Cpu, Controller::Memory]).unwrap();
+        assert_eq!(cgroup.path(), root.join("woody/abc"));
+
+        let subtree_control = |path: &std::path::Path| std::fs::read_to_string(path.join("cgroup.subtree_control")).ok();
+        assert_eq!(subtree_control(&root).as_deref(), Some("+memory"));
+        assert_eq!(subtree_control(&root.join("woody")).as_deref(), Some("+cpu +memory"));
+        assert_eq!(subtree_control(&root.join("woody/abc")).as_deref(), Some("+cpu +memory"));
+        assert_eq!(subtree_control(&root.join("woody/abc/leaf")), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
-    
-    println!();
 
+    #[test]
+    fn lists_only_real_cgroups_without_following_symlinks() {
+        let root = std::env::temp_dir().join(format!("woody-cgroup-list-{}", std::process::id()));
+        for cgroup in ["a", "a/b", "plain/nested"] {
+            std::fs::create_dir_all(root.join(cgroup)).unwrap();
+            std::fs::write(root.join(cgroup).join("cgroup.procs"), "").unwrap();
+        }
+        std::fs::write(root.join("cgroup.controllers"), "cpu memory").unwrap();
+        // Points back at the root, following it would never end
+        std::os::unix::fs::symlink(&root, root.join("a/b/loop")).unwrap();
 
-    if let Err(e) = examples::complete_workflow() {
-        eprintln!("Complete workflow example failed: {}", e);
-        eprintln!("Note: This might fail without root privileges");
-    }
+        let manager = CgroupManager::with_root(&root).unwrap();
+        let mut cgroups = manager.list_cgroups(None).unwrap();
+        cgroups.sort();
 
-    println!("\n=== Tutorial completed ===");
-    println!("This tutorial demonstrated:");
-    println!("1. Auto-detection of cgroups v1/v2");
-    println!("2. Creating and managing cgroups");
-    println!("3. Setting memory and CPU limits");
-    println!("4. Process management within cgroups");
-    println!("5. Reading resource usage statistics");
+        // plain has no cgroup.procs, so nothing below it is a cgroup of this hierarchy
+        assert_eq!(cgroups, ["a", "a/b"]);
 
-    println!("6. Freezing/unfreezing processes");
-    println!("\nTo run the examples with actual cgroup creation, you'll need root privileges.");
-    
-    Ok(())
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
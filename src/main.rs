@@ -1,357 +1,738 @@
-use std::{env, ffi::CString, fs, path::PathBuf, process::Command};
+use std::{net::IpAddr, os::unix::process::ExitStatusExt, path::{Path, PathBuf}, time::Duration};
 
 use anyhow::{bail, Context};
-use nix::{mount::{mount, MsFlags}, sched::{unshare, CloneFlags}, sys::wait::waitpid, unistd::{execve, fork, pivot_root, sethostname, ForkResult}};
-use serde::Deserialize;
-
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-enum GenericManifest {
-    ManifestList(ManifestList),
-    ImageManifest(Manifest)
+use clap::{Args, Parser, Subcommand};
+use nix::sys::signal::Signal;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+use woody::{
+    auth::Credentials, capabilities, cgroups::CgroupMode, lrng_cgroup, commit, control, environment, etc::HostEntry, exec, export, health::HealthOptions, http::TlsOptions, images::{self, PullPolicy}, load, logs::{self, LogFormat},
+    network::{NetworkMode, PortMapping, Subnet}, paths, plan, rlimits::Ulimit, run::{self, NamespaceMode}, seccomp::SeccompMode, spec::{self, RunSpec},
+    progress::PullProgress, registry::Platform, restart::RestartPolicy, state::{ContainerState, Status}, stats, units, volumes::{TmpfsMount, VolumeMount}, LocalImage, PullOptions, RunOptions,
+};
+
+#[derive(Parser)]
+#[command(name = "woody", version, about = "Run Docker Hub images in Linux namespaces")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Debug output, -vv for trace. RUST_LOG takes precedence
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Directory for containers and images, /var/lib/woody for root, $XDG_DATA_HOME/woody otherwise
+    #[arg(long, global = true, env = "WOODY_ROOT", value_name = "DIR")]
+    root: Option<PathBuf>,
+
+    /// No download progress output
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Registry request timeout in seconds, covering a whole layer download
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = woody::http::DEFAULT_TIMEOUT.as_secs())]
+    timeout: u64,
+
+    /// Retries for connection errors, 429 and 5xx responses
+    #[arg(long, global = true, value_name = "N", default_value_t = woody::http::DEFAULT_MAX_RETRIES)]
+    max_retries: u32,
+
+    /// Registry username, instead of the credentials saved by `docker login`
+    #[arg(long, global = true, requires = "password")]
+    username: Option<String>,
+
+    #[arg(long, global = true, requires = "username")]
+    password: Option<String>,
+
+    /// Platform to pull, os/arch[/variant], the host's by default
+    #[arg(long, global = true)]
+    platform: Option<Platform>,
+
+    /// Proxy for registry requests, e.g. http://proxy:3128. HTTPS_PROXY and HTTP_PROXY are used
+    /// without it, hosts in NO_PROXY are contacted directly either way
+    #[arg(long, global = true, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// PEM file of a root certificate to trust for registries, on top of the system's
+    #[arg(long, global = true, value_name = "PATH")]
+    ca_cert: Vec<PathBuf>,
+
+    /// Don't verify the TLS certificate of this registry host[:port], a last resort for self-signed ones
+    #[arg(long, global = true, value_name = "HOST")]
+    insecure_registry: Vec<String>,
+
+    /// Pull-through mirror of Docker Hub to try first, e.g. https://mirror.internal:5000.
+    /// Docker Hub is used for whatever the mirrors don't have
+    #[arg(long, global = true, value_name = "URL", value_parser = parse_registry_mirror)]
+    registry_mirror: Vec<String>,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct ManifestList {
-    schema_version: u32,
-    media_type: String,
-    manifests: Vec<ManifestListItem>
+/// Where containers and images were kept before the root was configurable
+const LEGACY_ROOT: &str = "./woody-image";
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run an image in a new container, pulling it if it isn't stored yet
+    Run(Box<RunArgs>),
+    /// Download an image into the local store without running it
+    Pull {
+        /// Tag the image by the digest its tag resolved to as well
+        #[arg(long)]
+        pin: bool,
+        image: String,
+    },
+    /// List containers
+    Ps,
+    /// Stop a container with SIGTERM, then SIGKILL after a grace period
+    Stop {
+        id: String,
+        /// Seconds to wait before SIGKILL
+        #[arg(short, long, default_value_t = 10)]
+        time: u64,
+    },
+    /// Send a signal to a container
+    Kill {
+        id: String,
+        /// Name like SIGTERM or TERM, or a number
+        #[arg(short, long, default_value = "SIGKILL", value_parser = control::parse_signal)]
+        signal: Signal,
+    },
+    /// Print a container's output
+    Logs {
+        /// Keep printing new output until the container exits
+        #[arg(short, long)]
+        follow: bool,
+        id: String,
+    },
+    /// Run a command in a running container
+    Exec {
+        #[arg(short, long)]
+        interactive: bool,
+        #[arg(short, long)]
+        tty: bool,
+        id: String,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Save a container's changes as a new image
+    Commit {
+        id: String,
+        /// Name of the new image, image[:tag]
+        image: String,
+    },
+    /// Remove stored images
+    Rmi {
+        #[arg(required = true)]
+        images: Vec<String>,
+    },
+    /// Manage stored images
+    Image {
+        #[command(subcommand)]
+        command: ImageCommand,
+    },
+    /// Write a container's filesystem as a tar archive
+    Export {
+        id: String,
+        /// File to write, - for stdout
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+    /// Import images from a `docker save` archive or an OCI image layout tarball
+    Load {
+        /// Archive to read, - for stdin
+        #[arg(short, long, default_value = "-")]
+        input: PathBuf,
+    },
+    /// Print an image's manifest and config as JSON, from the registry if it isn't stored
+    Inspect {
+        image: String,
+    },
+    /// Write an image's filesystem to a directory, and its config next to it as <dest>.json
+    Unpack {
+        image: String,
+        /// New or empty directory
+        #[arg(long, value_name = "DIR")]
+        dest: PathBuf,
+    },
+    /// Show running containers' CPU, memory and pids usage
+    Stats {
+        /// Print a single sample instead of updating every second
+        #[arg(long)]
+        no_stream: bool,
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Remove containers
+    Rm {
+        /// Kill running containers instead of refusing to remove them
+        #[arg(short, long)]
+        force: bool,
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
 }
 
-#[derive(Deserialize, Debug)]
-struct ManifestListItem {
-    digest: String,
-    platform: Platform
+#[derive(Subcommand)]
+enum ImageCommand {
+    /// Delete untagged images, interrupted downloads and cached registry responses no image needs
+    Prune,
 }
 
-#[derive(Deserialize, Debug)]
-struct Platform {
-    architecture: String,
-    os: String,
-}
-
-#[derive(Deserialize, Debug)]
-struct AuthResponse {
-    token: String,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct Manifest {
-    schema_version: u32,
-    media_type: String,
-    config: Digest,
-    layers: Vec<Digest>
-}
-
-#[derive(Deserialize, Debug)]
-struct Digest {
-    digest: String
-}
-
-#[derive(Deserialize, Debug)]
-struct ImageConfig {
-    architecture: String,
-    os: String,
-    config: ConfigDetails
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct ConfigDetails {
-    // Can be null, thats why option
-    cmd: Option<Vec<String>>,
-    entrypoint: Option<Vec<String>>,
+#[derive(Args)]
+struct RunArgs {
+    /// Run spec, TOML or an OCI runtime config.json, flags override its values
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Container name, a random id otherwise
+    #[arg(long, value_parser = parse_name)]
+    name: Option<String>,
+    /// Bind mount, host:container[:ro|rw]
+    #[arg(short = 'v', long = "volume", value_name = "SPEC", value_parser = VolumeMount::parse)]
+    volumes: Vec<VolumeMount>,
+    /// tmpfs mount, path[:options] with options like size=16m,mode=0755,noexec
+    #[arg(long, value_name = "SPEC", value_parser = TmpfsMount::parse)]
+    tmpfs: Vec<TmpfsMount>,
+    /// bridge (default), loopback, none, host or container:<id> to join another's
+    #[arg(long)]
+    network: Option<NetworkMode>,
+    /// IPC namespace, private (default) or host to share the host's
+    #[arg(long, value_name = "MODE")]
+    ipc: Option<NamespaceMode>,
+    /// UTS namespace, private (default) or host to keep the host's hostname
+    #[arg(long, value_name = "MODE")]
+    uts: Option<NamespaceMode>,
+    /// Bridge network addresses are allocated from
+    #[arg(long, value_name = "CIDR")]
+    subnet: Option<Subnet>,
+    /// Nameserver for the container's resolv.conf
+    #[arg(long, value_name = "IP")]
+    dns: Vec<IpAddr>,
+    /// Extra /etc/hosts entry, name:ip
+    #[arg(long = "add-host", value_name = "HOST:IP")]
+    add_hosts: Vec<HostEntry>,
+    /// Publish a port, host:container[/tcp|udp]
+    #[arg(short = 'p', long = "publish", value_name = "SPEC")]
+    ports: Vec<PortMapping>,
+    /// Publish every port the image exposes on a free host port
+    #[arg(short = 'P', long)]
+    publish_all: bool,
+    #[arg(long, value_name = "CAP")]
+    cap_add: Vec<String>,
+    #[arg(long, value_name = "CAP")]
+    cap_drop: Vec<String>,
+    /// Profile JSON path, or unconfined
+    #[arg(long, value_name = "PROFILE")]
+    seccomp: Option<SeccompMode>,
+    /// Resource limit, name=soft[:hard]
+    #[arg(long = "ulimit", value_name = "SPEC")]
+    ulimits: Vec<Ulimit>,
+    /// Allocate a pseudo terminal
+    #[arg(short, long)]
+    tty: bool,
+    /// Keep stdin open
+    #[arg(short, long, conflicts_with = "detach")]
+    interactive: bool,
+    #[arg(long)]
+    log_path: Option<PathBuf>,
+    /// raw or json-file
+    #[arg(long, default_value = "raw")]
+    log_format: LogFormat,
+    /// Run in the background and print the container id
+    #[arg(short, long)]
+    detach: bool,
+    /// Remove the container once it exits, it's kept until woody rm otherwise
+    #[arg(long)]
+    rm: bool,
+    /// no, on-failure[:max], always or unless-stopped, needs --detach
+    #[arg(long, value_name = "POLICY")]
+    restart: Option<RestartPolicy>,
+    /// Command run with /bin/sh -c in the container to check its health, overrides the image's
+    #[arg(long, value_name = "CMD")]
+    health_cmd: Option<String>,
+    /// Time between health checks, like 30s or 1m30s
+    #[arg(long, value_name = "DURATION", value_parser = units::parse_duration)]
+    health_interval: Option<Duration>,
+    /// Time a health check may take before it counts as failed
+    #[arg(long, value_name = "DURATION", value_parser = units::parse_duration)]
+    health_timeout: Option<Duration>,
+    /// Failed health checks in a row before the container is unhealthy
+    #[arg(long, value_name = "N")]
+    health_retries: Option<u32>,
+    /// Ignore the image's HEALTHCHECK
+    #[arg(long, conflicts_with = "health_cmd")]
+    no_healthcheck: bool,
+    /// Bind mount the host's /bin, /lib and friends over the container's own
+    #[arg(long)]
+    bind_host_bins: bool,
+    /// Signal woody stop sends first, overrides the image's StopSignal
+    #[arg(long, value_name = "SIGNAL", value_parser = control::parse_signal)]
+    stop_signal: Option<Signal>,
+    /// user[:group] to run as, names or ids, overrides the image's
+    #[arg(short, long, value_name = "USER")]
+    user: Option<String>,
+    /// Working directory inside the container
+    #[arg(short, long, value_name = "DIR", value_parser = parse_workdir)]
+    workdir: Option<String>,
+    /// Environment variable, KEY=VALUE or KEY to copy it from woody's environment
+    #[arg(short, long = "env", value_name = "VAR")]
     env: Vec<String>,
-    #[serde(rename = "WorkingDir")]
-    working_dir: String,
+    /// File of KEY=VALUE lines, applied before --env
+    #[arg(long, value_name = "PATH")]
+    env_file: Vec<PathBuf>,
+    #[arg(long, value_parser = parse_hostname)]
+    hostname: Option<String>,
+    /// always, missing or never
+    #[arg(long, value_name = "POLICY", default_value = "missing")]
+    pull: PullPolicy,
+    /// Record the container's image by the digest its tag resolved to, and tag it by that
+    #[arg(long)]
+    pin: bool,
+    /// Print the image's layers, the mounts, limits, command and env the container would
+    /// get, without pulling layers or starting anything
+    #[arg(long)]
+    dry_run: bool,
+    /// auto, enabled or disabled, auto only requires a cgroup for resource limits
+    #[arg(long, value_name = "MODE", default_value = "auto")]
+    cgroup: CgroupMode,
+    /// CPUs worth of time the container may use, 1.5 is 150% of one core
+    #[arg(long, value_name = "CPUS", value_parser = parse_cpus)]
+    cpus: Option<f64>,
+    /// Memory limit, bytes or with a k, m, g or t suffix, powers of 1024 like docker
+    #[arg(short, long, value_name = "SIZE", value_parser = units::parse_size)]
+    memory: Option<u64>,
+    /// Memory plus swap limit like --memory, -1 for unlimited swap
+    #[arg(long, value_name = "SIZE", value_parser = parse_memory_swap, allow_hyphen_values = true)]
+    memory_swap: Option<i64>,
+    /// Size of /dev/shm, like --memory, 64m by default
+    #[arg(long, alias = "dev-shm-size", value_name = "SIZE", value_parser = parse_shm_size)]
+    shm_size: Option<u64>,
+    /// Replaces the image's entrypoint and drops its Cmd, "" clears it
+    #[arg(long, value_name = "PATH")]
+    entrypoint: Option<String>,
+    /// Run in this directory instead of an image, everything after it is the command
+    #[arg(long, value_name = "DIR", value_parser = parse_rootfs, conflicts_with_all = ["pin", "dry_run"])]
+    rootfs: Option<PathBuf>,
+    /// image[:tag] or image@sha256:<digest>
+    #[arg(required_unless_present_any = ["config", "rootfs"])]
+    image: Option<String>,
+    /// Replaces the image's Cmd
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = std::env::args().collect::<Vec<String>>();
-
-    if args.len() < 2 {
-        eprintln!("Usage: {} <image:tag>", args[0]);
+    let cli = Cli::parse();
+    init_logging(cli.verbose);
 
-        return Ok(());
+    match cli.root {
+        Some(root) => paths::set_root(root)?,
+        None if Path::new(LEGACY_ROOT).is_dir() => {
+            warn!("Using {}, {} from before is ignored, pass --root {} to keep using it", paths::root().display(), LEGACY_ROOT, LEGACY_ROOT);
+        }
+        None => {}
     }
 
-    let image_ref = &args[1];
-    println!("-> Pulling image: {}", image_ref);
-
-    let container_id = "image-container";
-    let base_path = PathBuf::from(format!("./woody-image/{}", container_id));
-    // idempotency WOW
-    if base_path.exists() {
-        fs::remove_dir_all(&base_path)?;
+    let mut pull = PullOptions::new(paths::images_dir());
+    pull.timeout = Duration::from_secs(cli.timeout);
+    pull.max_retries = cli.max_retries;
+    pull.platform = cli.platform;
+    pull.proxy = cli.proxy;
+    pull.tls = TlsOptions { ca_certs: cli.ca_cert, insecure_hosts: cli.insecure_registry };
+    pull.mirrors = cli.registry_mirror;
+    if let (Some(username), Some(password)) = (cli.username, cli.password) {
+        pull.credentials = Some(Credentials::Basic { username, password });
     }
-    fs::create_dir_all(format!("./woody-image/{}", container_id))?;
-
-    // SECTION image name parsing / token acquisition
-
-    let (image_name, tag) = parse_image_name(image_ref);
 
-    let client = reqwest::Client::new();
-
-    let auth_url = format!(
-        "https://auth.docker.io/token?service=registry.docker.io&scope=repository:{}:pull",
-        image_name
-    );
-
-    let token = client
-        .get(&auth_url)
-        .send().await?
-        .json::<AuthResponse>()
-        .await?
-        .token;
-
-    // SECTION
+    match cli.command {
+        Command::Run(args) => {
+            let (policy, pin, dry_run) = (args.pull, args.pin, args.dry_run);
+            let rootfs = args.rootfs.clone();
+            let (image_ref, mut opts) = run_options(*args)?;
+
+            let status = match (rootfs, image_ref) {
+                (Some(rootfs), _) => {
+                    create_container(&mut opts)?;
+                    run::run_rootfs(&rootfs, &opts)?
+                }
+                (None, Some(image_ref)) => {
+                    if dry_run {
+                        let details = images::inspect(&image_ref, policy, &pull).await?;
+                        return plan::print_plan(&details, &pull.root, &opts);
+                    }
+
+                    let mut progress = PullProgress::new(cli.quiet);
+                    let mut image = images::get(&image_ref, policy, &pull, &mut |event| progress.handle(event)).await?;
+                    progress.finish();
+                    report_resolved(&mut image, pin)?;
+
+                    create_container(&mut opts)?;
+                    woody::run(&image, &opts)?
+                }
+                (None, None) => bail!("No image given on the command line or in the config"),
+            };
+
+            std::process::exit(status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0)));
+        }
+        Command::Pull { pin, image } => {
+            let mut progress = PullProgress::new(cli.quiet);
+            let mut image = woody::pull_image(&image, &pull, &mut |event| progress.handle(event)).await?;
+            progress.finish();
+            report_resolved(&mut image, pin)?;
+            Ok(())
+        }
+        Command::Ps => print_containers(),
+        Command::Stop { id, time } => control::stop_container(&id, Duration::from_secs(time)),
+        Command::Kill { id, signal } => control::kill_container(&id, signal),
+        Command::Logs { follow, id } => logs::print_logs(&id, follow),
+        Command::Exec { interactive, tty, id, command } => exec::exec_in_container(&id, &command, interactive, tty),
+        Command::Commit { id, image } => {
+            let image_id = commit::commit_container(&id, &image, &paths::images_dir())?;
+            println!("sha256:{}", image_id);
+            Ok(())
+        }
+        Command::Rmi { images } => {
+            let mut freed = 0;
+            for image in images {
+                freed += images::remove(&paths::images_dir(), &image)?;
+            }
+            println!("Total reclaimed space: {}", images::format_size(freed));
+            Ok(())
+        }
+        Command::Image { command: ImageCommand::Prune } => {
+            let freed = images::prune(&paths::images_dir())?;
+            println!("Total reclaimed space: {}", images::format_size(freed));
+            Ok(())
+        }
+        Command::Export { id, output } => export::export_container(&id, &output),
+        Command::Load { input } => {
+            for image in load::load_archive(&input, &paths::images_dir())? {
+                if image.names.is_empty() {
+                    println!("Loaded image ID: sha256:{}", image.id);
+                }
+                for name in image.names {
+                    println!("Loaded image: {}", name);
+                }
+            }
+            Ok(())
+        }
+        Command::Inspect { image } => {
+            let details = images::inspect(&image, PullPolicy::Missing, &pull).await?;
+            println!("{}", serde_json::to_string_pretty(&details)?);
+            Ok(())
+        }
+        Command::Unpack { image, dest } => {
+            let mut progress = PullProgress::new(cli.quiet);
+            let image = images::get(&image, PullPolicy::Missing, &pull, &mut |event| progress.handle(event)).await?;
+            progress.finish();
+            let config = export::unpack_image(&image, &dest)?;
+            info!("Unpacked {} into {}, config in {}", image, dest.display(), config.display());
+            Ok(())
+        }
+        Command::Stats { no_stream, ids } => stats::print_stats(&ids, no_stream),
+        Command::Rm { force, ids } => {
+            for id in ids {
+                control::remove_container(&id, force)?;
+            }
+            Ok(())
+        }
+    }
+}
 
+/// Log the digest a tag resolved to, which is what to pin for a reproducible run, and
+/// with `pin` refer to the image by it from now on
+fn report_resolved(image: &mut LocalImage, pin: bool) -> anyhow::Result<()> {
+    let Some(digest) = image.digest.clone().filter(|_| image.is_mutable()) else {
+        return Ok(());
+    };
+    info!("Resolved {}@{}", image, digest);
 
-    // Get image specification / options before downloading the containers
-    let (manifest, config) = fetch_image_manifest(&image_name, &tag, &token, &client).await?;
+    if pin {
+        images::pin(&paths::images_dir(), image)?;
+        info!("Pinned {}", image);
+    } else if image.reference == "latest" {
+        warn!("{} can move to another image, use {}@{} or --pin for a reproducible run", image, image.name, digest);
+    }
 
-    let rootfs_path = format!("./woody-image/{}/rootfs", container_id);
-    fs::create_dir_all(&rootfs_path)?;
+    Ok(())
+}
 
-    println!("-> Assembling rootfs at: {}", &rootfs_path);
-    download_and_unpack_layers(&image_name, &token, &manifest.layers, &rootfs_path, &client).await?;
+fn init_logging(verbose: u8) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(match verbose {
+            0 => "woody=info",
+            1 => "woody=debug",
+            _ => "woody=trace,debug",
+        })
+    });
+
+    // stderr, stdout is left for output meant to be consumed like `run -d`'s container id
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .init();
+}
 
-    run_container(container_id, config)?;
+/// Allocate the container's directory up front, so the run doesn't pick a different name
+fn create_container(opts: &mut RunOptions) -> anyhow::Result<()> {
+    let container_id = run::create_container(opts.name.as_deref())?;
+    info!("Container ID: {}", container_id);
+    opts.name = Some(container_id);
 
     Ok(())
 }
 
-fn parse_image_name(image_ref: &str) -> (String, String) {
-    // Image / Tag split parsing
-    let (image, tag) = image_ref.split_once(':').unwrap_or((image_ref, "latest"));
-    let image_name = if image.contains('/') { image.to_string() } else { format!("library/{}", image) };
+/// The image reference and options for `run`, flags layered over the --config spec.
+/// With --rootfs there is no image, what clap took for one starts the command.
+fn run_options(mut args: RunArgs) -> anyhow::Result<(Option<String>, RunOptions)> {
+    let spec = match &args.config {
+        Some(path) => spec::load(path)?,
+        None => RunSpec::default(),
+    };
+    let image = match args.rootfs {
+        Some(_) => {
+            args.command.splice(0..0, args.image.take());
+            None
+        }
+        None => args.image.or(spec.image),
+    };
 
-    (image_name.to_owned(), tag.to_owned())
-}
+    let network = match (args.network, spec.network) {
+        (Some(network), _) => network,
+        (None, Some(network)) => network.parse()?,
+        (None, None) => NetworkMode::default(),
+    };
 
-async fn fetch_image_manifest(
-    image_name: &str,
-    tag: &str,
-    token: &String,
-    client: &reqwest::Client
-) -> anyhow::Result<(Manifest, ImageConfig)> {
-    // Manifest get
-    let manifest_url = format!("https://registry-1.docker.io/v2/{}/manifests/{}", image_name, tag);
-
-    let generic_manifest: GenericManifest = client
-        .get(&manifest_url)
-        .header("Accept", "application/vnd.docker.distribution.manifest.v2+json")
-        .bearer_auth(&token)
-        .send().await?
-        .json().await
-        .context("Failed to deserialize generic manifest")?;
-
-    let final_manifest_digest;
-    let final_manifest: Manifest;
-
-    match generic_manifest {
-        GenericManifest::ImageManifest(manifest) => {
-            println!("-> Found single-architecture manifest.");
-            final_manifest = manifest;
-        }
-        GenericManifest::ManifestList(list) => {
-            println!("-> Found manifest list. Searching for linux/amd64.");
-
-            let amd64_manifest = list.manifests.iter()
-            .find(|m| m.platform.os == "linux" && m.platform.architecture == "amd64")
-            .context("Could not find linux/amd64 manifest in the list")?;
-
-            #[cfg(feature = "debug-reqs")]
-            dbg!(amd64_manifest);
-
-            final_manifest_digest = amd64_manifest.digest.clone();
-            let manifest_url = format!("https://registry-1.docker.io/v2/{}/manifests/{}", image_name, final_manifest_digest);
-            final_manifest = client
-                .get(&manifest_url)
-                .header("Accept", "application/vnd.docker.distribution.manifest.v2+json")
-                .bearer_auth(&token)
-                .send().await?
-                .json().await
-                .context("Failed to deserialize final image manifest")?;
-        }
+    let mut ports = spec.ports.iter().map(|port| port.parse()).collect::<anyhow::Result<Vec<PortMapping>>>()?;
+    ports.extend(args.ports);
+    let publish_all = args.publish_all || spec.publish_all;
+    if (!ports.is_empty() || publish_all) && network != NetworkMode::Bridge {
+        bail!("Publishing ports requires --network bridge");
     }
 
-    // Config get
-    let config_url = format!("https://registry-1.docker.io/v2/{}/blobs/{}", image_name, final_manifest.config.digest);
-    let config: ImageConfig = client
-        .get(&config_url)
-        .bearer_auth(&token)
-        .send().await?
-        .json().await?;
-
-    #[cfg(feature = "debug-reqs")]
-    dbg!(config);
+    let mut volumes = spec.volumes.iter().map(|volume| VolumeMount::parse(volume)).collect::<anyhow::Result<Vec<_>>>()?;
+    volumes.extend(args.volumes);
 
-    Ok((final_manifest, config))
-}
+    let mut tmpfs = spec.tmpfs.iter().map(|tmpfs| TmpfsMount::parse(tmpfs)).collect::<anyhow::Result<Vec<_>>>()?;
+    tmpfs.extend(args.tmpfs);
 
-async fn download_and_unpack_layers(
-    image_name: &str,
-    token: &String,
-    layers: &[Digest],
-    rootfs_path: &str,
-    client: &reqwest::Client
-) -> anyhow::Result<()> {
-    for layer in layers {
-        println!("   - Downloading layer {}", &layer.digest[..12]);
-        let layer_url = format!("https://registry-1.docker.io/v2/{}/blobs/{}", image_name, layer.digest);
-        let response_bytes = client
-            .get(&layer_url)
-            .bearer_auth(&token)
-            .send().await?
-            .bytes().await?;
-
-        println!("   - Unpacking layer {}", &layer.digest[..12]);
-        let tar = flate2::read::GzDecoder::new(&response_bytes[..]);
-        let mut archive = tar::Archive::new(tar);
-
-        archive.unpack(rootfs_path)?;
+    // The spec, then files, so an explicit -e wins
+    let mut env = Vec::new();
+    for var in &spec.env {
+        env.extend(environment::parse_var(var)?);
     }
-
-    Ok(())
-}
-
-fn run_container(container_id: &str, config: ImageConfig) -> anyhow::Result<()> {
-    if !nix::unistd::geteuid().is_root() {
-        bail!("You must run this program as root. Try with sudo.");
+    for path in &args.env_file {
+        env.extend(environment::parse_env_file(path)?);
+    }
+    for var in &args.env {
+        env.extend(environment::parse_var(var)?);
     }
 
-    match unsafe { fork() } {
-        Ok(ForkResult::Parent { child, .. }) => {
-            println!("-> Container PID from Parent: {}", child);
+    // Flags apply on top of the spec's set, so a spec drop can't undo a --cap-add
+    let capabilities = capabilities::resolve(&spec.cap_add, &spec.cap_drop)?;
+    let capabilities = capabilities::apply(capabilities, &args.cap_add, &args.cap_drop)?;
 
-            let pid = child.to_string();
-            println!("[PARENT] Waiting for child {}...", pid);
+    let ipc = match (args.ipc, spec.ipc) {
+        (Some(ipc), _) => ipc,
+        (None, Some(ipc)) => ipc.parse()?,
+        (None, None) => NamespaceMode::default(),
+    };
+    let uts = match (args.uts, spec.uts) {
+        (Some(uts), _) => uts,
+        (None, Some(uts)) => uts.parse()?,
+        (None, None) => NamespaceMode::default(),
+    };
 
-            let status = waitpid(child, None)?;
-            println!("-> Container exited with status: {:?}", status);
+    let hostname = args.hostname.or(spec.hostname);
+    if let Some(hostname) = &hostname {
+        run::validate_hostname(hostname)?;
+        if uts == NamespaceMode::Host {
+            bail!("--hostname can't be set with --uts host, the container keeps the host's");
         }
-        Ok(ForkResult::Child) => {
-            let flags = CloneFlags::CLONE_NEWNS |
-                        CloneFlags::CLONE_NEWUTS |
-                        CloneFlags::CLONE_NEWIPC |
-                        CloneFlags::CLONE_NEWNET;
+    }
+    let workdir = match (args.workdir, spec.workdir) {
+        (Some(workdir), _) => Some(workdir),
+        (None, Some(workdir)) => Some(parse_workdir(&workdir)?),
+        (None, None) => None,
+    };
 
-            unshare(flags).context("Failed to unshare namespaces")?;
+    let mut dns = spec.dns;
+    dns.extend(args.dns);
 
-            mount_fs(container_id, &config).context("Could not mount fs.")?;
+    let mut extra_hosts = spec.add_hosts.iter().map(|host| host.parse()).collect::<anyhow::Result<Vec<HostEntry>>>()?;
+    extra_hosts.extend(args.add_hosts);
 
-            sethostname("woody-image").context("Failed to set hostname.")?;
+    let shm_size = match (args.shm_size, spec.shm_size) {
+        (Some(size), _) => Some(size),
+        (None, Some(size)) => Some(parse_shm_size(&size)?),
+        (None, None) => None,
+    };
+    let restart = match (args.restart, spec.restart) {
+        (Some(restart), _) => restart,
+        (None, Some(restart)) => restart.parse()?,
+        (None, None) => RestartPolicy::default(),
+    };
+    if restart != RestartPolicy::No {
+        if !args.detach {
+            bail!("--restart {} needs --detach, only a detached container has a supervisor to restart it", restart);
+        }
+        if args.rm {
+            bail!("--restart {} and --rm conflict, the container is gone once it exits", restart);
+        }
+    }
 
-            exec_command(config).context("Failed to exec command.")?;
+    if shm_size.is_some() && ipc == NamespaceMode::Host {
+        bail!("--shm-size can't be set with --ipc host, the container uses the host's /dev/shm");
+    }
 
+    let mut resources = spec.resources;
+    if let Some(cpus) = args.cpus {
+        let online = lrng_cgroup::online_cpus();
+        if cpus > online as f64 {
+            warn!("--cpus {} is more than the {} CPUs online", cpus, online);
         }
-        Err(e) => {
-            bail!("Fork failed: {}", e);
+        resources.set_cpus(cpus);
+    }
+    if let Some(memory) = args.memory {
+        resources.memory = Some(memory);
+    }
+    if let Some(memory_swap) = args.memory_swap {
+        resources.memory_swap = Some(memory_swap);
+    }
+    match (resources.memory, resources.memory_swap) {
+        (None, Some(_)) => bail!("--memory-swap needs a memory limit"),
+        (Some(memory), Some(memory_swap)) if memory_swap >= 0 && (memory_swap as u64) < memory => {
+            bail!("--memory-swap must be at least --memory, it's memory plus swap");
         }
+        _ => {}
     }
 
-    Ok(())
-}
+    let opts = RunOptions {
+        name: args.name,
+        volumes,
+        tmpfs,
+        network,
+        subnet: args.subnet.unwrap_or_default(),
+        ipc,
+        uts,
+        dns,
+        extra_hosts,
+        ports,
+        publish_all,
+        capabilities,
+        seccomp: args.seccomp.unwrap_or_default(),
+        ulimits: args.ulimits,
+        tty: args.tty,
+        interactive: args.interactive,
+        log_path: args.log_path,
+        log_format: args.log_format,
+        detach: args.detach,
+        quiet: false,
+        remove: args.rm,
+        bind_host_bins: args.bind_host_bins,
+        workdir,
+        env,
+        hostname,
+        entrypoint: args.entrypoint.or(spec.entrypoint),
+        command: if args.command.is_empty() { spec.command } else { args.command },
+        resources,
+        cgroup: args.cgroup,
+        shm_size,
+        restart,
+        health: HealthOptions {
+            cmd: args.health_cmd,
+            interval: args.health_interval.filter(|interval| !interval.is_zero()),
+            timeout: args.health_timeout.filter(|timeout| !timeout.is_zero()),
+            retries: args.health_retries.filter(|retries| *retries > 0),
+            disabled: args.no_healthcheck,
+        },
+        user: args.user.or(spec.user),
+        stop_signal: match (args.stop_signal, spec.stop_signal) {
+            (Some(signal), _) => Some(signal),
+            (None, Some(signal)) => Some(control::parse_signal(&signal)?),
+            (None, None) => None,
+        },
+    };
 
+    Ok((image, opts))
+}
 
-fn mount_fs(container_id: &str, config: &ImageConfig) -> anyhow::Result<()> {
-    // OverlayFS integration
-    let container_root = PathBuf::from(format!("./woody-image/{}", container_id));
-    let rootfs = container_root.join("rootfs");
-    let upperdir = container_root.join("upper");
-    let workdir = container_root.join("work");
-    let merged = container_root.join("merged");
-    fs::create_dir_all(&upperdir)?;
-    fs::create_dir_all(&workdir)?;
-    fs::create_dir_all(&merged)?;
-    println!("[Container] Created overlayFS dirs.");
-
-    
-    std::env::set_current_dir(&rootfs)?;
-    println!("[Container] Initializing container on: {:?}", std::env::current_dir().unwrap());
-
-    // mount(
-    //     None::<&str>,
-    //     "/",
-    //     None::<&str>,
-    //     MsFlags::MS_REC | MsFlags::MS_PRIVATE,
-    //     None::<&str>,
-    // ).context("Failed to make root mount private")?;
-
-    let mount_opts = format!(
-        "lowerdir={},upperdir={},workdir={}",
-        rootfs.to_str().unwrap(),
-        upperdir.to_str().unwrap(),
-        workdir.to_str().unwrap(),
-    );
-
-    // Use merge dir as hub for upper and lower dirs
-    mount(
-        Some("overlay"),
-        &merged,
-        Some("overlay"),
-        MsFlags::empty(),
-        Some(mount_opts.as_str())
-    ).context("Failed to mount overlayfs")?;
-
-    nix::unistd::chroot(".")?;
-    println!("[Container] Root changed.");
-
-    let work_dir = &config.config.working_dir;
-    if !work_dir.is_empty() {
-        env::set_current_dir(work_dir).context(format!("Failed to change to working directory: {}", work_dir))?;
+fn print_containers() -> anyhow::Result<()> {
+    let mut states = ContainerState::list()?;
+    states.sort_by(|a, b| a.id.cmp(&b.id));
+
+    println!("{:<16} {:<32} {:<8} {:<10} {:<10} IP", "CONTAINER ID", "IMAGE", "PID", "STATUS", "HEALTH");
+    for state in states {
+        // A supervisor that died without recording the exit leaves a stale "running"
+        let status = match state.status {
+            Status::Running if !woody::is_alive(nix::unistd::Pid::from_raw(state.pid)) => Status::Exited,
+            status => status,
+        };
+        let ip = state.ip_address.map(|ip| ip.to_string()).unwrap_or_default();
+        let health = state.health.map(|health| health.to_string()).unwrap_or_default();
+
+        println!("{:<16} {:<32} {:<8} {:<10} {:<10} {}", state.id, state.image, state.pid, status.to_string(), health, ip);
     }
 
     Ok(())
 }
 
-fn exec_command(config: ImageConfig) -> anyhow::Result<()> {
-    let cmd = config.config.cmd.unwrap_or_default();
-    let entrypoint = config.config.entrypoint.unwrap_or_default();
+fn parse_name(name: &str) -> anyhow::Result<String> {
+    run::validate_container_name(name)?;
+    Ok(name.to_string())
+}
 
-    let (command, args) = if !entrypoint.is_empty() {
-        (entrypoint[0].clone(), entrypoint)
-    } else if !cmd.is_empty() {
-        (cmd[0].clone(), cmd)
-    } else {
-        bail!("Image has no entrypoint or command specified");
-    };
+fn parse_hostname(hostname: &str) -> anyhow::Result<String> {
+    run::validate_hostname(hostname)?;
+    Ok(hostname.to_string())
+}
 
-    let command_c = CString::new(command)?;
-    let args_c: Vec<CString> = args.iter()
-        .map(|s| CString::new(s.as_bytes()).unwrap())
-        .collect();
-    let env_c: Vec<CString> = config.config.env.iter()
-        .map(|s| CString::new(s.as_bytes()).unwrap())
-        .collect();
+fn parse_cpus(cpus: &str) -> anyhow::Result<f64> {
+    let cpus: f64 = cpus.parse().with_context(|| format!("expected a number of CPUs, got {:?}", cpus))?;
+    // The kernel's minimum quota is 1ms per 100ms period
+    if !cpus.is_finite() || cpus < 0.01 {
+        bail!("must be at least 0.01, got {}", cpus);
+    }
+    Ok(cpus)
+}
 
-    dbg!(&command_c);
-    dbg!(&args_c);
-    dbg!(&env_c);
+fn parse_memory_swap(size: &str) -> anyhow::Result<i64> {
+    match size {
+        "-1" => Ok(-1),
+        size => Ok(i64::try_from(units::parse_size(size)?)?),
+    }
+}
 
-    println!("-> Executing command: {:?}", &args);
-    execve(&command_c, &args_c, &env_c)
-        .expect("execve failed.");
+fn parse_shm_size(size: &str) -> anyhow::Result<u64> {
+    // tmpfs takes size=0 as unlimited
+    match units::parse_size(size)? {
+        0 => bail!("must be more than 0"),
+        size => Ok(size),
+    }
+}
 
-    Ok(())
+fn parse_registry_mirror(url: &str) -> anyhow::Result<String> {
+    let host = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"))
+        .with_context(|| format!("expected an http:// or https:// URL, got {:?}", url))?;
+    if host.trim_end_matches('/').is_empty() {
+        bail!("has no host: {:?}", url);
+    }
+    Ok(url.trim_end_matches('/').to_string())
 }
 
-//
-//
-// pub type ActionResult = std::result::Result<(), Box<dyn std::error::Error>>;
-//
-// fn main() {
-//     let config = ContainerConfig {
-//         command: vec!["/bin/bash".to_string()],
-//         args: vec![],
-//         rootfs: "./container/".to_string(),
-//     };
-//
-//     let container = Container::new(config);
-//     container.run();
-// }
+/// Checked up front, before a container directory is created for it
+fn parse_rootfs(dir: &str) -> anyhow::Result<PathBuf> {
+    if !Path::new(dir).is_dir() {
+        bail!("{} is not a directory", dir);
+    }
+    Ok(PathBuf::from(dir))
+}
 
+fn parse_workdir(dir: &str) -> anyhow::Result<String> {
+    if !dir.starts_with('/') {
+        bail!("must be an absolute path, got {:?}", dir);
+    }
+    Ok(dir.to_string())
+}
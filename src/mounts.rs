@@ -0,0 +1,392 @@
+use std::{env, fs, os::unix::fs::{symlink, PermissionsExt}, path::{Path, PathBuf}};
+
+use anyhow::{bail, Context};
+use nix::{
+    errno::Errno,
+    mount::{mount, umount2, MntFlags, MsFlags},
+    sys::stat::{makedev, mknod, Mode, SFlag},
+};
+use tracing::debug;
+
+use crate::volumes::{self, TmpfsMount, VolumeMount};
+
+/// Size of /dev/shm unless configured
+pub const DEFAULT_SHM_SIZE: u64 = 64 << 20;
+
+/// What goes into a container's root on top of the essential filesystems
+#[derive(Debug, Default)]
+pub struct RootfsOptions<'a> {
+    pub volumes: &'a [VolumeMount],
+    pub tmpfs: &'a [TmpfsMount],
+    pub bind_host_bins: bool,
+    /// Bind the host's /dev/shm, where the shared memory of its IPC namespace lives
+    pub host_ipc: bool,
+    /// Bytes, [`DEFAULT_SHM_SIZE`] if unset
+    pub shm_size: Option<u64>,
+}
+
+/// Mount everything the container needs inside `root`, then chroot into it.
+///
+/// Shared by the image and the rootfs code paths, anything written into the root
+/// from the host side has to happen before this.
+pub fn setup_rootfs(root: &Path, opts: &RootfsOptions) -> anyhow::Result<()> {
+    mount_rootfs(root, opts)?;
+
+    nix::unistd::chroot(root).with_context(|| format!("Failed to chroot into {}", root.display()))?;
+    env::set_current_dir("/")?;
+    debug!("Root changed");
+
+    Ok(())
+}
+
+/// Everything [`setup_rootfs`] mounts, without changing the root
+fn mount_rootfs(root: &Path, opts: &RootfsOptions) -> anyhow::Result<()> {
+    mount_essential(root, opts.shm_size.unwrap_or(DEFAULT_SHM_SIZE))?;
+
+    if opts.bind_host_bins {
+        bind_host_bins(root)?;
+    }
+    if opts.host_ipc {
+        debug!("Binding the host's /dev/shm");
+        mount(Some("/dev/shm"), &root.join("dev/shm"), None::<&str>, MsFlags::MS_BIND, None::<&str>)
+            .context("Failed to bind mount the host's /dev/shm")?;
+    }
+    // Before the volumes, so one below a tmpfs isn't hidden by it
+    volumes::mount_tmpfs(root, opts.tmpfs)?;
+    volumes::mount_volumes(root, opts.volumes)
+}
+
+/// Stop mount events propagating between the new mount namespace and the host's.
+///
+/// A fresh namespace copies the host's propagation, and where `/` is shared, as
+/// under systemd, the container's mounts would otherwise show up on the host.
+pub fn make_root_private() -> anyhow::Result<()> {
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>
+    ).context("Failed to make root mount private")
+}
+
+/// Mount points at or below `root`, in the order they were mounted
+pub fn mounts_under(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").context("Failed to read mountinfo")?;
+
+    Ok(mountinfo.lines()
+        // <id> <parent> <major:minor> <root> <mount point> ...
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|point| PathBuf::from(unescape(point)))
+        .filter(|point| point.starts_with(root))
+        .collect())
+}
+
+/// Unmount everything mounted at or below `root`, children before their parents.
+///
+/// Mounts that are still busy are detached lazily, so this never blocks on a
+/// process still holding a file open in there.
+pub fn unmount_all(root: &Path) -> anyhow::Result<()> {
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(_) => return Ok(()),
+    };
+
+    for point in mounts_under(&root)?.iter().rev() {
+        debug!("Unmounting {}", point.display());
+        match umount2(point, MntFlags::empty()) {
+            Ok(()) => {}
+            Err(Errno::EBUSY) => umount2(point, MntFlags::MNT_DETACH)
+                .with_context(|| format!("Failed to detach {}", point.display()))?,
+            // Already gone along with a lazily detached parent
+            Err(Errno::EINVAL) | Err(Errno::ENOENT) => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to unmount {}", point.display())),
+        }
+    }
+
+    Ok(())
+}
+
+/// `remove_dir_all` that unmounts first, so it neither fails with EBUSY nor
+/// recurses into a host directory that is still bind mounted inside
+pub fn remove_dir_all(path: &Path) -> anyhow::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    unmount_all(path)?;
+
+    let leftover = mounts_under(&path.canonicalize()?)?;
+    if let Some(point) = leftover.first() {
+        bail!("Refusing to remove {}, {} is still mounted", path.display(), point.display());
+    }
+
+    fs::remove_dir_all(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+/// Mount /proc, /sys and a /dev with the usual device nodes and a `shm_size` /dev/shm inside `root`.
+///
+/// Called with the container's root before chrooting into it, so nothing is
+/// created or mounted relative to the host's working directory.
+pub fn mount_essential(root: &Path, shm_size: u64) -> anyhow::Result<()> {
+    for dir in ["proc", "sys", "dev", "tmp"] {
+        fs::create_dir_all(root.join(dir))
+            .with_context(|| format!("Could not create essential dir [{}]", dir))?;
+    }
+
+    debug!("Mounting /proc");
+    mount(
+        None::<&str>,
+        &root.join("proc"),
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>
+    ).context("mounting /proc failed")?;
+
+    debug!("Mounting /sys");
+    mount(
+        None::<&str>,
+        &root.join("sys"),
+        Some("sysfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>
+    ).context("mounting /sys failed")?;
+
+    // Only device nodes and symlinks live directly in /dev, shared memory gets its own mount
+    debug!("Mounting /dev");
+    mount(
+        None::<&str>,
+        &root.join("dev"),
+        Some("tmpfs"),
+        MsFlags::empty(),
+        Some("mode=0755,size=65536k")
+    ).context("mounting /dev failed")?;
+
+    create_device_nodes(&root.join("dev"))?;
+
+    debug!("Mounting /dev/shm");
+    let shm = root.join("dev/shm");
+    fs::create_dir(&shm).context("Could not create essential dir [dev/shm]")?;
+    mount(
+        None::<&str>,
+        &shm,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Some(format!("mode=1777,size={}", shm_size).as_str())
+    ).context("mounting /dev/shm failed")
+}
+
+/// Character devices most programs expect, plus the /proc/self/fd symlinks
+fn create_device_nodes(dev: &Path) -> anyhow::Result<()> {
+    /* name, major, minor */
+    let devices = [
+        ("null", 1, 3),
+        ("zero", 1, 5),
+        ("full", 1, 7),
+        ("random", 1, 8),
+        ("urandom", 1, 9),
+        ("tty", 5, 0),
+    ];
+
+    for (name, major, minor) in devices {
+        let path = dev.join(name);
+
+        match mknod(&path, SFlag::S_IFCHR, Mode::empty(), makedev(major, minor)) {
+            // mknod is subject to the umask, so the mode is set afterwards
+            Ok(()) => fs::set_permissions(&path, fs::Permissions::from_mode(0o666))
+                .with_context(|| format!("chmod {} failed", path.display()))?,
+            // Denied inside a user namespace, borrow the host's node instead
+            Err(Errno::EPERM) => {
+                fs::File::create(&path).with_context(|| format!("creating {} failed", path.display()))?;
+                mount(
+                    Some(format!("/dev/{}", name).as_str()),
+                    &path,
+                    None::<&str>,
+                    MsFlags::MS_BIND,
+                    None::<&str>
+                ).with_context(|| format!("bind mounting /dev/{} failed", name))?;
+            }
+            Err(e) => return Err(e).with_context(|| format!("creating /dev/{} failed", name)),
+        }
+    }
+
+    let links = [
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+    ];
+
+    for (name, target) in links {
+        symlink(target, dev.join(name))
+            .with_context(|| format!("linking /dev/{} failed", name))?;
+    }
+
+    Ok(())
+}
+
+/// Debugging fallback for a rootfs without its own userland: borrow the host's binaries and libraries
+fn bind_host_bins(root: &Path) -> anyhow::Result<()> {
+    for dir in ["/bin", "/usr/bin", "/lib", "/lib64", "/usr/lib", "/usr/lib64"] {
+        // Not every host has all of them, lib64 in particular
+        if !Path::new(dir).exists() {
+            continue;
+        }
+
+        let target = root.join(dir.trim_start_matches('/'));
+        fs::create_dir_all(&target)
+            .with_context(|| format!("Could not create essential dir [{}]", target.display()))?;
+
+        debug!("Binding host {}", dir);
+        mount(
+            Some(dir),
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>
+        ).with_context(|| format!("mounting {} failed", dir))?;
+    }
+
+    Ok(())
+}
+
+/// mountinfo escapes space, tab, newline and backslash as octal
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let digits: String = chars.clone().take(3).collect();
+            if let Ok(byte) = u8::from_str_radix(&digits, 8) {
+                out.push(byte as char);
+                chars.nth(2);
+                continue;
+            }
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use anyhow::ensure;
+    use nix::{
+        mount::{mount, MsFlags},
+        sched::{unshare, CloneFlags},
+        sys::wait::{waitpid, WaitStatus},
+        unistd::{fork, ForkResult},
+    };
+
+    use crate::{layers, volumes::TmpfsMount};
+
+    /// Exit code of a child that couldn't create a mount namespace
+    const NO_NAMESPACE: i32 = 77;
+
+    /// Run `f` in a forked child with a private mount namespace, so whatever it mounts
+    /// dies with the child even if it fails half way. `false` if the test lacks the
+    /// privileges for a mount namespace, panics if `f` fails.
+    fn in_mount_namespace(f: impl FnOnce() -> anyhow::Result<()>) -> bool {
+        // unshare(CLONE_NEWNS) is refused to a multithreaded process, like the test runner
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = match unshare(CloneFlags::CLONE_NEWNS) {
+                    Err(_) => NO_NAMESPACE,
+                    Ok(()) => match catch_unwind(AssertUnwindSafe(|| make_root_private().and_then(|()| f()))) {
+                        Ok(Ok(())) => 0,
+                        Ok(Err(e)) => {
+                            eprintln!("{:#}", e);
+                            1
+                        }
+                        Err(_) => 1,
+                    },
+                };
+                unsafe { libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => match waitpid(child, None).unwrap() {
+                WaitStatus::Exited(_, 0) => true,
+                WaitStatus::Exited(_, NO_NAMESPACE) => false,
+                status => panic!("mount namespace child failed: {:?}", status),
+            },
+        }
+    }
+
+    #[test]
+    fn container_mounts_exist_during_the_run_and_none_survive_teardown() {
+        let tmp = std::env::temp_dir().join(format!("woody-mountns-{}", std::process::id()));
+        let layers_root = tmp.join("layers");
+        let (upper, work, merged, host) = (tmp.join("upper"), tmp.join("work"), tmp.join("merged"), tmp.join("host"));
+        for dir in [layers::layer_dir(&layers_root, 0), upper.clone(), work.clone(), merged.clone(), host.clone()] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(layers::layer_dir(&layers_root, 0).join("base"), "lower").unwrap();
+        fs::write(host.join("precious"), "keep me").unwrap();
+
+        let ran = in_mount_namespace(|| {
+            layers::mount_overlay(&layers_root, &upper, &work, &merged)?;
+            let tmpfs = [TmpfsMount::parse("/run:size=1m")?];
+            let volumes = [VolumeMount::parse(&format!("{}:/data:ro", host.display()))?];
+            mount_rootfs(&merged, &RootfsOptions { volumes: &volumes, tmpfs: &tmpfs, ..Default::default() })?;
+
+            let root = merged.canonicalize()?;
+            let mounted = mounts_under(&root)?;
+            for expected in ["", "proc", "sys", "dev", "dev/shm", "run", "data"] {
+                ensure!(mounted.contains(&root.join(expected)), "/{} is not mounted, got {:?}", expected, mounted);
+            }
+            ensure!(fs::read_to_string(root.join("data/precious"))? == "keep me", "the volume isn't visible");
+
+            unmount_all(&root)?;
+            let left = mounts_under(&root)?;
+            ensure!(left.is_empty(), "still mounted after teardown: {:?}", left);
+            Ok(())
+        });
+
+        // Nothing may have propagated out of the child's namespace either
+        let leaked = mounts_under(&tmp.canonicalize().unwrap()).unwrap();
+        fs::remove_dir_all(&tmp).unwrap();
+        if !ran {
+            eprintln!("skipping, mount namespaces need CAP_SYS_ADMIN");
+            return;
+        }
+        assert!(leaked.is_empty(), "leaked into the host: {:?}", leaked);
+    }
+
+    #[test]
+    fn unescapes_mountinfo_fields() {
+        assert_eq!(unescape("/tmp/with\\040space"), "/tmp/with space");
+        assert_eq!(unescape("/tmp/back\\134slash"), "/tmp/back\\slash");
+        assert_eq!(unescape("/plain"), "/plain");
+    }
+
+    #[test]
+    fn cleanup_does_not_traverse_bind_mounts() {
+        let tmp = std::env::temp_dir().join(format!("woody-mounts-{}", std::process::id()));
+        let host = tmp.join("host");
+        let base = tmp.join("base");
+        let target = base.join("rootfs/bin");
+        fs::create_dir_all(&host).unwrap();
+        fs::create_dir_all(&target).unwrap();
+        fs::write(host.join("precious"), "keep me").unwrap();
+
+        let ran = in_mount_namespace(|| {
+            mount(Some(&host), &target, None::<&str>, MsFlags::MS_BIND, None::<&str>)?;
+            ensure!(target.join("precious").exists(), "the bind mount isn't visible");
+
+            remove_dir_all(&base)?;
+            ensure!(!base.exists(), "{} is still there", base.display());
+            Ok(())
+        });
+
+        let precious = fs::read_to_string(host.join("precious"));
+        fs::remove_dir_all(&tmp).unwrap();
+        if !ran {
+            eprintln!("skipping, bind mounts need CAP_SYS_ADMIN");
+            return;
+        }
+        assert_eq!(precious.unwrap(), "keep me");
+    }
+}
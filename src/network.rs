@@ -0,0 +1,456 @@
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    net::{Ipv4Addr, TcpListener, UdpSocket},
+    os::unix::{io::AsRawFd, process::CommandExt},
+    path::PathBuf,
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+use nix::{fcntl::{flock, FlockArg}, sched::{setns, CloneFlags}, unistd::Pid};
+use tracing::{info, warn};
+
+use crate::{control::is_alive, paths, state::{ContainerState, Status}};
+
+const BRIDGE_NAME: &str = "woody0";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// veth pair attached to the woody0 bridge, NATed out through the host
+    #[default]
+    Bridge,
+    /// Isolated namespace with only the loopback interface up
+    Loopback,
+    /// Empty network namespace, nothing configured
+    None,
+    /// No network namespace, the host's interfaces as they are
+    Host,
+    /// The network namespace of another running container, by id
+    Container(String),
+}
+
+impl FromStr for NetworkMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "bridge" => Ok(NetworkMode::Bridge),
+            "loopback" => Ok(NetworkMode::Loopback),
+            "none" => Ok(NetworkMode::None),
+            "host" => Ok(NetworkMode::Host),
+            other => match other.strip_prefix("container:") {
+                Some(id) if !id.is_empty() => Ok(NetworkMode::Container(id.to_string())),
+                _ => bail!("Unknown network mode {:?}, expected bridge, loopback, none, host or container:<id>", other),
+            },
+        }
+    }
+}
+
+impl fmt::Display for NetworkMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkMode::Bridge => write!(f, "bridge"),
+            NetworkMode::Loopback => write!(f, "loopback"),
+            NetworkMode::None => write!(f, "none"),
+            NetworkMode::Host => write!(f, "host"),
+            NetworkMode::Container(id) => write!(f, "container:{}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Subnet {
+    pub network: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl FromStr for Subnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = s.split_once('/')
+            .with_context(|| format!("Invalid subnet {:?}, expected CIDR like 10.42.0.0/24", s))?;
+
+        let addr: Ipv4Addr = addr.parse().with_context(|| format!("Invalid subnet address {:?}", addr))?;
+        let prefix: u8 = prefix.parse().with_context(|| format!("Invalid subnet prefix {:?}", prefix))?;
+
+        // Need room for the network, gateway, at least one container and broadcast
+        if !(8..=30).contains(&prefix) {
+            bail!("Subnet prefix must be between /8 and /30, got /{}", prefix);
+        }
+
+        let mask = u32::MAX << (32 - prefix);
+        Ok(Subnet { network: Ipv4Addr::from(u32::from(addr) & mask), prefix })
+    }
+}
+
+impl Default for Subnet {
+    fn default() -> Self {
+        Subnet { network: Ipv4Addr::new(10, 42, 0, 0), prefix: 24 }
+    }
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Subnet {
+    fn host(&self, index: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + index)
+    }
+
+    fn size(&self) -> u32 {
+        1 << (32 - self.prefix)
+    }
+
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.host(1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+/// A `-p host:container[/proto]` mapping
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+impl FromStr for PortMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (ports, protocol) = match s.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some((_, proto)) => bail!("Unsupported protocol {:?} in {:?}, expected tcp or udp", proto, s),
+            None => (s, Protocol::Tcp),
+        };
+
+        let (host, container) = ports.split_once(':')
+            .with_context(|| format!("Invalid port mapping {:?}, expected host:container[/proto]", s))?;
+
+        let parse_port = |port: &str| -> anyhow::Result<u16> {
+            match port.parse::<u16>() {
+                Ok(0) | Err(_) => bail!("Invalid port {:?} in {:?}", port, s),
+                Ok(port) => Ok(port),
+            }
+        };
+
+        Ok(PortMapping {
+            host_port: parse_port(host)?,
+            container_port: parse_port(container)?,
+            protocol,
+        })
+    }
+}
+
+impl PortMapping {
+    /// `-P` for an image's `ExposedPorts` key like `80/tcp`, on a host port that is free now
+    pub fn for_exposed(spec: &str) -> anyhow::Result<Self> {
+        let (port, protocol) = match spec.split_once('/') {
+            Some((port, "tcp")) => (port, Protocol::Tcp),
+            Some((port, "udp")) => (port, Protocol::Udp),
+            Some(_) => bail!("Unsupported exposed port {:?}, expected port[/tcp|udp]", spec),
+            None => (spec, Protocol::Tcp),
+        };
+        let container_port = match port.parse::<u16>() {
+            Ok(0) | Err(_) => bail!("Invalid exposed port {:?}", spec),
+            Ok(port) => port,
+        };
+
+        // The kernel picks an unused ephemeral port, released again right away
+        let host_port = match protocol {
+            Protocol::Tcp => TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))?.local_addr()?.port(),
+            Protocol::Udp => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?.local_addr()?.port(),
+        };
+
+        Ok(PortMapping { host_port, container_port, protocol })
+    }
+}
+
+/// DNAT published host ports to the container
+pub fn publish_ports(container_ip: Ipv4Addr, ports: &[PortMapping]) -> anyhow::Result<()> {
+    for port in ports {
+        for (table, rule) in port_rules(container_ip, port) {
+            let rule: Vec<&str> = rule.iter().map(String::as_str).collect();
+            ensure_iptables_rule(table, &rule)?;
+        }
+
+        info!(
+            "Publishing host port {} -> {}:{}/{}",
+            port.host_port, container_ip, port.container_port, port.protocol.as_str()
+        );
+    }
+
+    Ok(())
+}
+
+/// Best effort removal of the rules installed by `publish_ports`
+pub fn unpublish_ports(container_ip: Ipv4Addr, ports: &[PortMapping]) {
+    for port in ports {
+        for (table, rule) in port_rules(container_ip, port) {
+            let mut args = vec!["-t", table, "-D"];
+            args.extend(rule.iter().map(String::as_str));
+
+            if let Err(e) = run("iptables", &args) {
+                warn!("Failed to remove port rule: {:#}", e);
+            }
+        }
+    }
+}
+
+fn port_rules(container_ip: Ipv4Addr, port: &PortMapping) -> Vec<(&'static str, Vec<String>)> {
+    let proto = port.protocol.as_str().to_string();
+    let host_port = port.host_port.to_string();
+    let container_port = port.container_port.to_string();
+    let destination = format!("{}:{}", container_ip, port.container_port);
+
+    let rule = |parts: &[&str]| parts.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+    vec![
+        // Traffic arriving from outside the host for one of its addresses, forwarded traffic
+        // to the same port elsewhere, like another container's, must pass untouched
+        ("nat", rule(&["PREROUTING", "-p", &proto, "--dport", &host_port, "-m", "addrtype", "--dst-type", "LOCAL", "-j", "DNAT", "--to-destination", &destination])),
+        // Connections made from the host itself to one of its own addresses
+        ("nat", rule(&["OUTPUT", "-p", &proto, "--dport", &host_port, "-m", "addrtype", "--dst-type", "LOCAL", "-j", "DNAT", "--to-destination", &destination])),
+        // Hosts with a DROP forward policy would swallow the translated packets
+        ("filter", rule(&["FORWARD", "-d", &container_ip.to_string(), "-p", &proto, "--dport", &container_port, "-j", "ACCEPT"])),
+    ]
+}
+
+/// Runs on the host once the child has unshared its network namespace and
+/// returns the address handed to the container.
+///
+/// The container end of the veth pair lives in the child's namespace, so it
+/// is cleaned up by the kernel when the container exits.
+pub fn setup_bridge_network(pid: Pid, subnet: &Subnet) -> anyhow::Result<Ipv4Addr> {
+    ensure_bridge(subnet)?;
+
+    let container_ip = allocate_ip(subnet)?;
+
+    // IFNAMSIZ is 16, pid keeps the names unique and short enough
+    let host_veth = format!("wv{}", pid);
+    if let Err(e) = connect_veth(pid, subnet, container_ip, &host_veth) {
+        // The host end outlives the container unless deleted, which takes its peer along
+        run("ip", &["link", "delete", &host_veth]).ok();
+        release_ip(container_ip);
+        return Err(e);
+    }
+
+    Ok(container_ip)
+}
+
+/// Create the veth pair of container `pid`, `host_veth` on the bridge and `eth0` with
+/// `container_ip` in its namespace
+fn connect_veth(pid: Pid, subnet: &Subnet, container_ip: Ipv4Addr, host_veth: &str) -> anyhow::Result<()> {
+    let peer_veth = format!("wc{}", pid);
+
+    run("ip", &["link", "add", host_veth, "type", "veth", "peer", "name", &peer_veth])?;
+    run("ip", &["link", "set", &peer_veth, "netns", &pid.to_string()])?;
+    run("ip", &["link", "set", host_veth, "master", BRIDGE_NAME])?;
+    run("ip", &["link", "set", host_veth, "up"])?;
+
+    let cidr = format!("{}/{}", container_ip, subnet.prefix);
+    let gateway = subnet.gateway().to_string();
+
+    run_in_netns(pid, &["link", "set", &peer_veth, "name", "eth0"])?;
+    run_in_netns(pid, &["addr", "add", &cidr, "dev", "eth0"])?;
+    run_in_netns(pid, &["link", "set", "eth0", "up"])?;
+    run_in_netns(pid, &["route", "add", "default", "via", &gateway])?;
+
+    info!("Container network: {} via {}", cidr, BRIDGE_NAME);
+
+    Ok(())
+}
+
+/// Runs inside the container's fresh network namespace, where `lo` starts out down.
+///
+/// Uses the SIOCSIFFLAGS ioctl directly so it doesn't depend on `ip` being available.
+pub fn bring_up_loopback() -> anyhow::Result<()> {
+    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if sock < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to open control socket");
+    }
+
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(b"lo\0") {
+        *dst = *src as libc::c_char;
+    }
+
+    let result = unsafe {
+        if libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req) < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+
+            if libc::ioctl(sock, libc::SIOCSIFFLAGS, &req) < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+    };
+
+    unsafe { libc::close(sock) };
+
+    result.context("Failed to bring up loopback interface")
+}
+
+/// Create the shared bridge and the masquerade rule the first time around
+fn ensure_bridge(subnet: &Subnet) -> anyhow::Result<()> {
+    let exists = Command::new("ip")
+        .args(["link", "show", BRIDGE_NAME])
+        .output()
+        .context("Failed to run `ip`, is iproute2 installed?")?
+        .status
+        .success();
+
+    if !exists {
+        let gateway = format!("{}/{}", subnet.gateway(), subnet.prefix);
+
+        run("ip", &["link", "add", BRIDGE_NAME, "type", "bridge"])?;
+        run("ip", &["addr", "add", &gateway, "dev", BRIDGE_NAME])?;
+        run("ip", &["link", "set", BRIDGE_NAME, "up"])?;
+    }
+
+    fs::write("/proc/sys/net/ipv4/ip_forward", "1").context("Failed to enable IP forwarding")?;
+
+    let subnet = subnet.to_string();
+    let rule = ["POSTROUTING", "-s", &subnet, "!", "-o", BRIDGE_NAME, "-j", "MASQUERADE"];
+    ensure_iptables_rule("nat", &rule)
+}
+
+/// Append an iptables rule unless an identical one is already installed
+pub fn ensure_iptables_rule(table: &str, rule: &[&str]) -> anyhow::Result<()> {
+    let mut check = vec!["-t", table, "-C"];
+    check.extend_from_slice(rule);
+
+    let present = Command::new("iptables")
+        .args(&check)
+        .output()
+        .context("Failed to run `iptables`")?
+        .status
+        .success();
+
+    if !present {
+        let mut append = vec!["-t", table, "-A"];
+        append.extend_from_slice(rule);
+        run("iptables", &append)?;
+    }
+
+    Ok(())
+}
+
+/// The network namespace of container `id` for `--network container:<id>`, which
+/// has to be running: a stopped one's namespace is gone.
+pub fn container_netns(id: &str) -> anyhow::Result<fs::File> {
+    let state = ContainerState::load(id)?;
+    if state.status != Status::Running || !is_alive(Pid::from_raw(state.pid)) {
+        bail!("Container {} is not running, its network can't be joined", id);
+    }
+
+    fs::File::open(format!("/proc/{}/ns/net", state.pid))
+        .with_context(|| format!("Failed to open network namespace of container {}", id))
+}
+
+/// Leases of the bridge addresses handed out, `<ip>` files holding the pid of the woody
+/// process that set the container's network up and releases the address again
+fn leases_dir() -> PathBuf {
+    paths::root().join("ip-leases")
+}
+
+/// Lease the lowest address no other container holds. The lease file is created with
+/// O_EXCL, so two containers starting at once can't get the same one.
+fn allocate_ip(subnet: &Subnet) -> anyhow::Result<Ipv4Addr> {
+    let dir = leases_dir();
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    // Reclaiming a stale lease is a check and a delete, only one process may do that at a time
+    let lock = fs::File::create(dir.join(".lock")).context("Failed to open the IP lease lock")?;
+    flock(lock.as_raw_fd(), FlockArg::LockExclusive).context("Failed to lock the IP leases")?;
+
+    // .0 is the network, .1 the gateway and the last one broadcast
+    for ip in (2..subnet.size() - 1).map(|i| subnet.host(i)) {
+        let lease = dir.join(ip.to_string());
+        match fs::OpenOptions::new().write(true).create_new(true).open(&lease) {
+            Ok(mut file) => {
+                file.write_all(std::process::id().to_string().as_bytes())?;
+                return Ok(ip);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to create {}", lease.display())),
+        }
+
+        // Left behind by a woody that was killed before it could release it
+        let holder = fs::read_to_string(&lease).ok().and_then(|pid| pid.trim().parse().ok());
+        if holder.is_none_or(|pid| !is_alive(Pid::from_raw(pid))) {
+            fs::write(&lease, std::process::id().to_string())
+                .with_context(|| format!("Failed to take over {}", lease.display()))?;
+            return Ok(ip);
+        }
+    }
+
+    bail!("No free addresses left in {}", subnet)
+}
+
+/// Hand a container's address back once its network is gone
+pub fn release_ip(ip: Ipv4Addr) {
+    let lease = leases_dir().join(ip.to_string());
+    if let Err(e) = fs::remove_file(&lease) {
+        warn!("Failed to release {}: {}", lease.display(), e);
+    }
+}
+
+fn run_in_netns(pid: Pid, args: &[&str]) -> anyhow::Result<()> {
+    let netns = fs::File::open(format!("/proc/{}/ns/net", pid))
+        .context("Failed to open container network namespace")?;
+    let fd = netns.as_raw_fd();
+
+    let mut cmd = Command::new("ip");
+    cmd.args(args);
+
+    // Only the spawned `ip` process joins the container's namespace
+    unsafe {
+        cmd.pre_exec(move || setns(fd, CloneFlags::CLONE_NEWNET).map_err(std::io::Error::from));
+    }
+
+    exec(&mut cmd, "ip", args)
+}
+
+fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    exec(Command::new(program).args(args), program, args)
+}
+
+fn exec(cmd: &mut Command, program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let output = cmd.output().with_context(|| format!("Failed to run `{}`", program))?;
+
+    if !output.status.success() {
+        bail!(
+            "`{} {}` failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
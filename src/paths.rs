@@ -0,0 +1,46 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::bail;
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// `/var/lib/woody` for root, `$XDG_DATA_HOME/woody` otherwise
+pub fn default_root() -> PathBuf {
+    if nix::unistd::geteuid().is_root() {
+        return PathBuf::from("/var/lib/woody");
+    }
+
+    match env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(data_home) => PathBuf::from(data_home).join("woody"),
+        None => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".local/share/woody"),
+    }
+}
+
+/// Keep everything under `root` from now on, before any container or image is touched
+pub fn set_root(root: PathBuf) -> anyhow::Result<()> {
+    if ROOT.set(root).is_err() {
+        bail!("The woody root is already set to {}", self::root().display());
+    }
+    Ok(())
+}
+
+/// Directory all of woody's state lives in, [`default_root`] unless [`set_root`] said otherwise.
+///
+/// Containers are kept in directories named after their ids, images under `images/`.
+pub fn root() -> &'static Path {
+    ROOT.get_or_init(default_root)
+}
+
+/// Directory of container `id`, with its state, logs and overlay dirs
+pub fn container_dir(id: &str) -> PathBuf {
+    root().join(id)
+}
+
+/// Where pulled images are kept, see [`images`](crate::images)
+pub fn images_dir() -> PathBuf {
+    root().join("images")
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use nix::sched::CloneFlags;
+
+use crate::{
+    cgroups::DEFAULT_CPU_PERIOD,
+    command, environment,
+    images::{self, ImageDetails},
+    layers, paths, registry,
+    run::RunOptions,
+};
+
+/// Print what `woody run` would do with `image` and `opts`, the `--dry-run` output.
+///
+/// Paths are the ones a run would use, with `<container>` for the id it would allocate.
+pub fn print_plan(image: &ImageDetails, root: &Path, opts: &RunOptions) -> anyhow::Result<()> {
+    let separator = if registry::is_digest(&image.reference) { '@' } else { ':' };
+    let id = image.manifest.config.digest.trim_start_matches("sha256:");
+    let state = if image.id.is_some() { "stored" } else { "not stored, would be pulled" };
+    println!("{:<12}{}{}{} (sha256:{}, {})", "Image", image.name, separator, image.reference, id, state);
+
+    let mut download = 0;
+    println!("Layers");
+    for layer in &image.manifest.layers {
+        let size = layer.size.map_or_else(|| "?".to_string(), images::format_size);
+        let action = if image.id.is_some() || layers::shared_layer_dir(root, &layer.digest).is_dir() {
+            "stored"
+        } else {
+            download += layer.size.unwrap_or(0);
+            "download"
+        };
+        println!("  {} {:>8} {}", layer.digest, size, action);
+    }
+    if download > 0 {
+        println!("  {} to download", images::format_size(download));
+    }
+
+    let layers_path = images::image_path(root, id).join("layers");
+    let count = match image.id {
+        Some(_) => layers::count_layers(&layers_path),
+        None => image.manifest.layers.len(),
+    };
+    let lowerdir = (0..count).rev()
+        .map(|index| layers::layer_dir(&layers_path, index).display().to_string())
+        .collect::<Vec<_>>()
+        .join(":");
+    let container_root = paths::container_dir("<container>");
+    println!(
+        "{:<12}mount -t overlay overlay -o lowerdir={},upperdir={},workdir={} {}",
+        "Overlay",
+        lowerdir,
+        container_root.join("upper").display(),
+        container_root.join("work").display(),
+        container_root.join("merged").display(),
+    );
+
+    let namespaces: Vec<&str> = NAMESPACE_NAMES.iter()
+        .filter(|(flag, _)| opts.namespaces().contains(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    println!("{:<12}{}", "Namespaces", namespaces.join(" "));
+    println!("{:<12}{}", "Network", opts.network);
+    println!("{:<12}{}", "Cgroup", cgroup_summary(opts));
+
+    let argv = command::resolve(&image.config.config, opts.entrypoint.as_deref(), &opts.command)?;
+    println!("{:<12}{:?}", "Command", argv);
+
+    let env = environment::merge(&image.config.config.env, &opts.env);
+    for (i, var) in env.iter().enumerate() {
+        println!("{:<12}{}", if i == 0 { "Env" } else { "" }, var);
+    }
+
+    let workdir = opts.workdir.as_deref().unwrap_or(&image.config.config.working_dir);
+    println!("{:<12}{}", "Workdir", if workdir.is_empty() { "/" } else { workdir });
+    let user = opts.user.as_deref().unwrap_or(&image.config.config.user);
+    println!("{:<12}{}", "User", if user.is_empty() { "root" } else { user });
+    println!("{:<12}{}", "On exit", if opts.remove { "remove the container" } else { "keep the container until woody rm" });
+
+    Ok(())
+}
+
+/// How `lsns` calls them
+const NAMESPACE_NAMES: [(CloneFlags, &str); 6] = [
+    (CloneFlags::CLONE_NEWNS, "mount"),
+    (CloneFlags::CLONE_NEWUTS, "uts"),
+    (CloneFlags::CLONE_NEWIPC, "ipc"),
+    (CloneFlags::CLONE_NEWNET, "net"),
+    (CloneFlags::CLONE_NEWPID, "pid"),
+    (CloneFlags::CLONE_NEWUSER, "user"),
+];
+
+/// The cgroup mode and whichever limits are set
+fn cgroup_summary(opts: &RunOptions) -> String {
+    let limits = &opts.resources;
+    let mut parts = vec![format!("{:?}", opts.cgroup).to_lowercase()];
+
+    if let Some(memory) = limits.memory {
+        parts.push(format!("memory={}", memory));
+    }
+    if let Some(swap) = limits.memory_swap {
+        parts.push(format!("memory-swap={}", swap));
+    }
+    if let Some(pids) = limits.pids {
+        parts.push(format!("pids={}", pids));
+    }
+    if let Some(shares) = limits.cpu_shares {
+        parts.push(format!("cpu-shares={}", shares));
+    }
+    if let Some(quota) = limits.cpu_quota {
+        parts.push(format!("cpu-quota={}/{}", quota, limits.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD)));
+    }
+
+    parts.join(" ")
+}
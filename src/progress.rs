@@ -0,0 +1,197 @@
+use std::{collections::HashMap, time::Duration};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use nix::unistd::isatty;
+use tracing::debug;
+
+use crate::registry::PullEvent;
+
+/// How a pull reports layer downloads
+enum Mode {
+    /// indicatif bars, one per layer plus an overall one
+    Bars,
+    /// Percentage lines, for logs that can't redraw a bar
+    Plain,
+    /// `--quiet`
+    Quiet,
+}
+
+/// Renders the [`PullEvent`]s of a pull on stderr
+pub struct PullProgress {
+    mode: Mode,
+    multi: MultiProgress,
+    overall: Option<ProgressBar>,
+    /// Layers in the manifest, the overall bar is only shown once one of them is pulled
+    total_layers: usize,
+    layers: HashMap<String, LayerProgress>,
+}
+
+impl PullProgress {
+    pub fn new(quiet: bool) -> Self {
+        let mode = if quiet {
+            Mode::Quiet
+        } else if isatty(libc::STDERR_FILENO).unwrap_or(false) {
+            Mode::Bars
+        } else {
+            Mode::Plain
+        };
+
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+        PullProgress { mode, multi, overall: None, total_layers: 0, layers: HashMap::new() }
+    }
+
+    pub fn handle(&mut self, event: PullEvent) {
+        match event {
+            PullEvent::ManifestResolved { layers, .. } => self.total_layers = layers,
+            PullEvent::ConfigFetched { .. } => {}
+            PullEvent::LayerExists { digest } => {
+                self.start();
+                self.layer_exists(short_digest(&digest));
+            }
+            PullEvent::LayerStarted { digest, size } => {
+                self.start();
+                let layer = self.layer(short_digest(&digest), size);
+                self.layers.insert(digest, layer);
+            }
+            PullEvent::LayerProgress { digest, downloaded } => {
+                if let Some(layer) = self.layers.get_mut(&digest) {
+                    layer.inc(downloaded.saturating_sub(layer.downloaded));
+                }
+            }
+            PullEvent::LayerUnpacking { digest } => {
+                if let Some(layer) = self.layers.get(&digest) {
+                    layer.unpacking();
+                }
+            }
+            PullEvent::LayerDone { digest } => {
+                if let Some(layer) = self.layers.remove(&digest) {
+                    layer.finish();
+                }
+            }
+        }
+    }
+
+    /// Add the overall bar if it isn't there yet
+    fn start(&mut self) {
+        if self.overall.is_some() || !matches!(self.mode, Mode::Bars) {
+            return;
+        }
+
+        let bar = self.multi.add(ProgressBar::new(self.total_layers as u64));
+        bar.set_style(ProgressStyle::with_template("{prefix:>12} [{bar:30}] {pos}/{len} layers")
+            .expect("static template")
+            .progress_chars("=> "));
+        bar.set_prefix("Pulling");
+        self.overall = Some(bar);
+    }
+
+    /// Progress of one layer download, `size` comes from Content-Length when the registry sends it
+    fn layer(&self, name: &str, size: Option<u64>) -> LayerProgress {
+        let bar = match self.mode {
+            Mode::Bars => {
+                let bar = self.multi.add(match size {
+                    Some(size) => ProgressBar::new(size),
+                    None => ProgressBar::new_spinner(),
+                });
+                let template = match size {
+                    Some(_) => "{prefix:>12} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} {msg}",
+                    None => "{prefix:>12} {spinner} {bytes} {bytes_per_sec} {msg}",
+                };
+                bar.set_style(ProgressStyle::with_template(template)
+                    .expect("static template")
+                    .progress_chars("=> "));
+                bar.set_prefix(name.to_string());
+                bar.enable_steady_tick(Duration::from_millis(120));
+                Some(bar)
+            }
+            Mode::Plain => {
+                debug!("Downloading layer {}", name);
+                None
+            }
+            Mode::Quiet => None,
+        };
+
+        LayerProgress {
+            name: name.to_string(),
+            bar,
+            overall: self.overall.clone(),
+            plain: matches!(self.mode, Mode::Plain),
+            size,
+            downloaded: 0,
+            reported_percent: 0,
+        }
+    }
+
+    /// A layer the store already has, it is neither downloaded nor unpacked again
+    fn layer_exists(&self, name: &str) {
+        match self.mode {
+            Mode::Bars => {
+                self.multi.println(format!("{:>12} already exists", name)).ok();
+            }
+            Mode::Plain => debug!("Layer {} already exists", name),
+            Mode::Quiet => {}
+        }
+        if let Some(overall) = &self.overall {
+            overall.inc(1);
+        }
+    }
+
+    /// Once the pull returned, whether or not it got to any layers
+    pub fn finish(&self) {
+        if let Some(overall) = &self.overall {
+            overall.finish();
+        }
+    }
+}
+
+struct LayerProgress {
+    name: String,
+    bar: Option<ProgressBar>,
+    overall: Option<ProgressBar>,
+    plain: bool,
+    size: Option<u64>,
+    downloaded: u64,
+    reported_percent: u64,
+}
+
+impl LayerProgress {
+    fn inc(&mut self, bytes: u64) {
+        self.downloaded += bytes;
+
+        if let Some(bar) = &self.bar {
+            bar.inc(bytes);
+        }
+
+        // Every 10%, without a size there is nothing meaningful to print until it's done
+        if let (true, Some(size)) = (self.plain, self.size.filter(|&size| size > 0)) {
+            let percent = (self.downloaded * 100 / size).min(100) / 10 * 10;
+            if percent > self.reported_percent {
+                self.reported_percent = percent;
+                debug!("Layer {}: {}%", self.name, percent);
+            }
+        }
+    }
+
+    fn unpacking(&self) {
+        match &self.bar {
+            Some(bar) => bar.set_message("unpacking"),
+            None if self.plain => debug!("Unpacking layer {}", self.name),
+            None => {}
+        }
+    }
+
+    fn finish(self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_with_message("done");
+        }
+        if let Some(overall) = &self.overall {
+            overall.inc(1);
+        }
+    }
+}
+
+/// digest is "sha256:<hex>", show the start of the hex like docker does
+fn short_digest(digest: &str) -> &str {
+    let hex = digest.split_once(':').map_or(digest, |(_, hex)| hex);
+    &hex[..hex.len().min(12)]
+}
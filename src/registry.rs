@@ -0,0 +1,1034 @@
+use std::{collections::BTreeMap, fmt, fs, io::Write, os::unix::io::AsRawFd, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context};
+use futures_util::StreamExt;
+use nix::{errno::Errno, fcntl::{flock, FlockArg}};
+use reqwest::{header::{CONTENT_RANGE, ETAG, IF_NONE_MATCH, RANGE}, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{auth::{self, Credentials}, cache::{ManifestCache, TagEntry}, http::{self, HttpClient, TlsOptions}, images, layers};
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum GenericManifest {
+    ManifestList(ManifestList),
+    ImageManifest(Manifest),
+    SchemaV1(ManifestV1)
+}
+
+/// Accepted manifest types, registries that predate schema 2 answer with schema 1
+const MANIFEST_ACCEPT: &str = "application/vnd.docker.distribution.manifest.v2+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v1+prettyjws, \
+    application/vnd.docker.distribution.manifest.v1+json";
+
+const SCHEMA_V1_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v1+json";
+
+/// Registry API of Docker Hub, where every image comes from unless a mirror has it
+const DOCKER_HUB: &str = "https://registry-1.docker.io";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[allow(dead_code)]
+struct ManifestList {
+    schema_version: u32,
+    media_type: String,
+    manifests: Vec<ManifestListItem>
+}
+
+#[derive(Deserialize, Debug)]
+struct ManifestListItem {
+    digest: String,
+    platform: Platform
+}
+
+/// `os/architecture[/variant]` in the names images use, e.g. `linux/arm64/v8`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// The platform woody itself was built for
+    pub fn host() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" => "ppc64le",
+            other => other,
+        };
+
+        Platform { os: std::env::consts::OS.to_string(), architecture: architecture.to_string(), variant: None }
+    }
+
+    /// A variant is only compared when both sides name one
+    pub fn matches(&self, os: &str, architecture: &str, variant: Option<&str>) -> bool {
+        self.os == os
+            && self.architecture == architecture
+            && match (self.variant.as_deref(), variant) {
+                (Some(wanted), Some(variant)) => wanted == variant,
+                _ => true,
+            }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            bail!("Invalid platform {:?}, expected os/architecture[/variant]", s);
+        }
+
+        match parts.as_slice() {
+            [os, architecture] => Ok(Platform { os: os.to_string(), architecture: architecture.to_string(), variant: None }),
+            [os, architecture, variant] => Ok(Platform {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: Some(variant.to_string()),
+            }),
+            _ => bail!("Invalid platform {:?}, expected os/architecture[/variant]", s),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// Legacy schema 1 manifest: layers topmost first, and no config blob, the
+/// newest `history` entry carries the image config as a JSON string instead
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ManifestV1 {
+    schema_version: u32,
+    fs_layers: Vec<FsLayer>,
+    history: Vec<V1History>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FsLayer {
+    #[serde(rename = "blobSum")]
+    blob_sum: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct V1History {
+    #[serde(rename = "v1Compatibility")]
+    v1_compatibility: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct AuthResponse {
+    // The OAuth2 endpoint answers with `access_token` instead
+    #[serde(alias = "access_token")]
+    token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub schema_version: u32,
+    pub media_type: String,
+    pub config: Digest,
+    pub layers: Vec<Digest>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Digest {
+    pub digest: String,
+    /// Bytes of the blob, unknown for schema 1 images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageConfig {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    pub config: ConfigDetails
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct ConfigDetails {
+    // Can be null, thats why option
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub entrypoint: Option<Vec<String>>,
+    pub env: Vec<String>,
+    #[serde(rename = "WorkingDir", default)]
+    pub working_dir: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<Healthcheck>,
+    /// `user`, `uid`, `user:group` or `uid:gid`, empty for root
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub user: String,
+    /// Signal name like `SIGQUIT`, `None` for SIGTERM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+    /// Keys like `80/tcp`, the values are always empty objects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exposed_ports: Option<BTreeMap<String, Value>>,
+    /// Container paths the image declares as volumes, keys like ExposedPorts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<BTreeMap<String, Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>,
+}
+
+/// An image's HEALTHCHECK, durations are in nanoseconds and 0 means the default
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct Healthcheck {
+    /// `["CMD", argv...]`, `["CMD-SHELL", line]` or `["NONE"]`, empty inherits
+    #[serde(default)]
+    pub test: Vec<String>,
+    #[serde(default)]
+    pub interval: u64,
+    #[serde(default)]
+    pub timeout: u64,
+    #[serde(default)]
+    pub retries: u32,
+}
+
+/// Shell form is sometimes stored as a bare string rather than a one element list
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match Option::<StringOrList>::deserialize(deserializer)? {
+        Some(StringOrList::String(line)) => Some(vec![line]),
+        Some(StringOrList::List(argv)) => Some(argv),
+        None => None,
+    })
+}
+
+/// Options for [`pull_image`]
+#[derive(Debug, Clone)]
+pub struct PullOptions {
+    /// Image store the image is saved into, usually [`paths::images_dir`](crate::paths::images_dir)
+    pub root: PathBuf,
+    /// Per request, covering the whole body of a layer download
+    pub timeout: Duration,
+    pub max_retries: u32,
+    /// `--username`/`--password`, otherwise `docker login`'s are used and then anonymous access
+    pub credentials: Option<Credentials>,
+    /// `--platform`, the host's when unset
+    pub platform: Option<Platform>,
+    /// `--proxy`, see [`HttpClient::new`] for the environment variables used otherwise
+    pub proxy: Option<String>,
+    /// `--ca-cert` and `--insecure-registry`
+    pub tls: TlsOptions,
+    /// `--registry-mirror` base URLs, tried in order before Docker Hub
+    pub mirrors: Vec<String>,
+}
+
+impl PullOptions {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        PullOptions {
+            root: root.into(),
+            timeout: http::DEFAULT_TIMEOUT,
+            max_retries: http::DEFAULT_MAX_RETRIES,
+            credentials: None,
+            platform: None,
+            proxy: None,
+            tls: TlsOptions::default(),
+            mirrors: Vec::new(),
+        }
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform.clone().unwrap_or_else(Platform::host)
+    }
+}
+
+impl ImageConfig {
+    pub fn platform(&self) -> Platform {
+        Platform { os: self.os.clone(), architecture: self.architecture.clone(), variant: self.variant.clone() }
+    }
+
+    /// Whether this image can run where `opts` asks for it
+    pub fn matches_platform(&self, opts: &PullOptions) -> bool {
+        opts.platform().matches(&self.os, &self.architecture, self.variant.as_deref())
+    }
+}
+
+/// Fail on an image built for another platform than `opts` asks for.
+///
+/// Exec'ing it would only end in a cryptic ENOEXEC. An explicit `--platform` is
+/// taken as the user knowing better, e.g. with binfmt emulation, and only warns.
+pub(crate) fn check_platform(image_ref: &str, config: &ImageConfig, opts: &PullOptions) -> anyhow::Result<()> {
+    if config.matches_platform(opts) {
+        return Ok(());
+    }
+
+    match &opts.platform {
+        Some(platform) => warn!("Image {} is {}, running it as {} as requested", image_ref, config.platform(), platform),
+        None => bail!(
+            "Image {} is {} but this host is {}, pass --platform {} to use it anyway",
+            image_ref, config.platform(), opts.platform(), config.platform()
+        ),
+    }
+
+    Ok(())
+}
+
+/// An image whose layers are extracted on disk, ready to [`run`](crate::run)
+#[derive(Debug, Clone)]
+pub struct LocalImage {
+    /// Repository, e.g. `library/alpine`
+    pub name: String,
+    /// Tag or digest it was pulled by
+    pub reference: String,
+    /// Digest of the manifest, what `name@digest` pins the image as. `None` for a
+    /// schema 1 manifest, which woody only stores converted
+    pub digest: Option<String>,
+    pub manifest: Manifest,
+    pub config: ImageConfig,
+    pub layers_path: PathBuf,
+}
+
+impl LocalImage {
+    /// Hex of the config digest, which names the image in the store
+    pub fn id(&self) -> &str {
+        self.manifest.config.digest.trim_start_matches("sha256:")
+    }
+
+    /// Whether the reference is a tag that can be moved to another image, rather than a digest
+    pub fn is_mutable(&self) -> bool {
+        !is_digest(&self.reference)
+    }
+}
+
+/// `sha256:<hex>` of the stored manifest bytes, see [`LocalImage::digest`]
+pub(crate) fn manifest_digest(manifest: &Manifest, manifest_raw: &[u8]) -> Option<String> {
+    (manifest.media_type != SCHEMA_V1_MEDIA_TYPE).then(|| format!("sha256:{:x}", Sha256::digest(manifest_raw)))
+}
+
+impl fmt::Display for LocalImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = if is_digest(&self.reference) { '@' } else { ':' };
+        write!(f, "{}{}{}", self.name, separator, self.reference)
+    }
+}
+
+/// What a pull is doing, for [`pull_image`]'s callers to render.
+///
+/// Digests are `sha256:<hex>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PullEvent {
+    /// The reference resolved to the manifest with this digest
+    ManifestResolved { digest: String, layers: usize },
+    /// The image config, its digest is the image id
+    ConfigFetched { digest: String },
+    /// The store has the layer from another image already, nothing is downloaded for it
+    LayerExists { digest: String },
+    /// `size` comes from Content-Length when the registry sends it
+    LayerStarted { digest: String, size: Option<u64> },
+    /// Bytes of the layer downloaded so far
+    LayerProgress { digest: String, downloaded: u64 },
+    /// Downloaded and verified, now being extracted
+    LayerUnpacking { digest: String },
+    LayerDone { digest: String },
+}
+
+/// Resolve `image_ref` on Docker Hub and save it into the image store at `opts.root`,
+/// reporting progress to `on_event`.
+///
+/// Layers are only downloaded if the store doesn't already have the image the
+/// reference currently resolves to.
+#[instrument(name = "pull", skip_all, fields(image = image_ref))]
+pub async fn pull_image(image_ref: &str, opts: &PullOptions, on_event: &mut dyn FnMut(PullEvent)) -> anyhow::Result<LocalImage> {
+    info!("Pulling image {}", image_ref);
+
+    // SECTION image name parsing / token acquisition
+
+    let (image_name, reference) = parse_image_name(image_ref)?;
+
+    let client = HttpClient::new(opts.timeout, opts.max_retries, opts.proxy.as_deref(), &opts.tls)?;
+
+    let token = fetch_token(&image_name, credentials(opts), &client).await?;
+
+    // SECTION
+
+
+    // Get image specification / options before downloading the containers
+    let cache = ManifestCache::new(&opts.root.join("cache"));
+    let platform = opts.platform();
+    let fetched = fetch_image_manifest(&image_name, &reference, &platform, &token, &client, &opts.mirrors, &cache).await?;
+    check_platform(image_ref, &fetched.config, opts)?;
+    let digest = manifest_digest(&fetched.manifest, &fetched.manifest_raw);
+    on_event(PullEvent::ManifestResolved {
+        digest: digest.clone().unwrap_or_else(|| format!("sha256:{:x}", Sha256::digest(&fetched.manifest_raw))),
+        layers: fetched.manifest.layers.len(),
+    });
+    on_event(PullEvent::ConfigFetched { digest: fetched.manifest.config.digest.clone() });
+
+    // Images are keyed by their config digest, like docker's image ids
+    let id = fetched.manifest.config.digest.trim_start_matches("sha256:").to_string();
+    let image_path = images::image_path(&opts.root, &id);
+
+    let root = opts.root.clone();
+    let _lock = tokio::task::spawn_blocking(move || images::lock_store(&root, false)).await??;
+    if image_path.exists() {
+        info!("Image is up to date: {}", id);
+    } else {
+        // Built next to its final place and renamed in, so an interrupted pull leaves no half image.
+        // One directory per process, a concurrent pull of the same image has its own.
+        let partial = opts.root.join(format!("{}.{}.partial", id, std::process::id()));
+        if partial.exists() {
+            fs::remove_dir_all(&partial)
+                .with_context(|| format!("Failed to clear {}", partial.display()))?;
+        }
+        let layers_path = partial.join("layers");
+        fs::create_dir_all(&layers_path)?;
+
+        info!("Extracting layers into {}", image_path.display());
+        download_and_unpack_layers(&image_name, &token, &fetched.manifest.layers, &layers_path, &client, opts, on_event).await?;
+
+        fs::write(partial.join("manifest.json"), &fetched.manifest_raw)?;
+        fs::write(partial.join("config.json"), &fetched.config_raw)?;
+        if let Err(e) = fs::rename(&partial, &image_path) {
+            // The other pull got there first, its copy is as good as this one
+            if !image_path.is_dir() {
+                return Err(e).with_context(|| format!("Failed to move image into {}", image_path.display()));
+            }
+            fs::remove_dir_all(&partial)
+                .with_context(|| format!("Failed to clear {}", partial.display()))?;
+        }
+    }
+
+    images::tag(&opts.root, &image_name, &reference, &id)?;
+
+    Ok(LocalImage {
+        name: image_name,
+        reference,
+        digest,
+        manifest: fetched.manifest,
+        config: fetched.config,
+        layers_path: image_path.join("layers"),
+    })
+}
+
+/// Resolve `image_ref` on Docker Hub and fetch its manifest and config, without any layers
+#[instrument(name = "inspect", skip_all, fields(image = image_ref))]
+pub async fn fetch_metadata(image_ref: &str, opts: &PullOptions) -> anyhow::Result<(Manifest, ImageConfig)> {
+    let (image_name, reference) = parse_image_name(image_ref)?;
+    let client = HttpClient::new(opts.timeout, opts.max_retries, opts.proxy.as_deref(), &opts.tls)?;
+    let token = fetch_token(&image_name, credentials(opts), &client).await?;
+
+    let cache = ManifestCache::new(&opts.root.join("cache"));
+    let fetched = fetch_image_manifest(&image_name, &reference, &opts.platform(), &token, &client, &opts.mirrors, &cache).await?;
+
+    Ok((fetched.manifest, fetched.config))
+}
+
+/// Pull token for `image_name`, anonymous unless there are credentials
+async fn fetch_token(image_name: &str, credentials: Option<Credentials>, client: &HttpClient) -> anyhow::Result<String> {
+    let scope = format!("repository:{}:pull", image_name);
+    let auth_url = format!("https://auth.docker.io/token?service=registry.docker.io&scope={}", scope);
+
+    let request = match credentials {
+        None => client.get(&auth_url),
+        Some(Credentials::Basic { username, password }) => {
+            debug!("Authenticating as {}", username);
+            client.get(&auth_url).basic_auth(username, Some(password))
+        }
+        // Exchanged for an access token through the OAuth2 endpoint
+        Some(Credentials::IdentityToken(refresh_token)) => {
+            debug!("Authenticating with an identity token");
+            client.post("https://auth.docker.io/token").form(&[
+                ("grant_type", "refresh_token"),
+                ("service", "registry.docker.io"),
+                ("client_id", "woody"),
+                ("scope", scope.as_str()),
+                ("refresh_token", refresh_token.as_str()),
+            ])
+        }
+    };
+
+    let response = client.send(request).await?;
+    let token = http::check_status(response).await
+        .with_context(|| format!("Failed to get a pull token for {}", image_name))?
+        .json::<AuthResponse>()
+        .await
+        .context("Failed to deserialize auth response")?
+        .token;
+
+    Ok(token)
+}
+
+/// Explicit credentials first, then `docker login`'s, `None` for an anonymous pull
+fn credentials(opts: &PullOptions) -> Option<Credentials> {
+    if opts.credentials.is_some() {
+        return opts.credentials.clone();
+    }
+
+    auth::from_docker_config().unwrap_or_else(|e| {
+        warn!("Ignoring docker credentials: {:#}", e);
+        None
+    })
+}
+
+/// Split `image[:tag]` or `image[:tag]@sha256:<hex>` into the repository and the
+/// reference to request its manifest by, a digest taking precedence over any tag.
+///
+/// The reference has to follow the distribution reference grammar, a Docker Hub
+/// host in front of the repository is dropped and any other host refused.
+pub(crate) fn parse_image_name(image_ref: &str) -> anyhow::Result<(String, String)> {
+    split_reference(image_ref).with_context(|| format!("Invalid image reference {:?}", image_ref))
+}
+
+fn split_reference(image_ref: &str) -> anyhow::Result<(String, String)> {
+    let (image_ref, digest) = match image_ref.split_once('@') {
+        Some((image, digest)) => {
+            validate_digest(digest)?;
+            (image, Some(digest))
+        }
+        None => (image_ref, None),
+    };
+
+    // A tag can't contain a slash, the colon of a registry port has one after it
+    let (image, tag) = match image_ref.rsplit_once(':') {
+        Some((image, tag)) if !tag.contains('/') => {
+            validate_tag(tag)?;
+            (image, tag)
+        }
+        _ => (image_ref, "latest"),
+    };
+
+    let image = strip_docker_hub(image)?;
+    validate_repository(image)?;
+    let image_name = if image.contains('/') { image.to_string() } else { format!("library/{}", image) };
+
+    Ok((image_name, digest.unwrap_or(tag).to_owned()))
+}
+
+/// Hosts Docker Hub images can be referred to by
+const DOCKER_HUB_HOSTS: [&str; 3] = ["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+/// `docker.io/library/alpine` is `library/alpine`, a first component with a dot or a
+/// port, or `localhost`, names a registry
+fn strip_docker_hub(image: &str) -> anyhow::Result<&str> {
+    let Some((host, rest)) = image.split_once('/') else {
+        return Ok(image);
+    };
+    if !host.contains(['.', ':']) && host != "localhost" {
+        return Ok(image);
+    }
+
+    if !DOCKER_HUB_HOSTS.contains(&host) {
+        bail!("registry {:?} is not supported, woody only pulls from Docker Hub", host);
+    }
+    Ok(rest)
+}
+
+/// Lowercase components of letters and digits, joined by `.`, `_`, `__` or dashes
+fn validate_repository(repository: &str) -> anyhow::Result<()> {
+    if repository.is_empty() {
+        bail!("the repository name is empty");
+    }
+    if repository.len() > 255 {
+        bail!("the repository name is longer than 255 characters");
+    }
+
+    for component in repository.split('/') {
+        let is_alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+        if component.is_empty() {
+            bail!("{:?} has an empty path component", repository);
+        }
+        if component.chars().any(|c| c.is_ascii_uppercase()) {
+            bail!("repository name component {:?} must be lowercase", component);
+        }
+
+        let separators_valid = component.split(is_alphanumeric)
+            .filter(|separator| !separator.is_empty())
+            .all(|separator| matches!(separator, "." | "_" | "__") || separator.chars().all(|c| c == '-'));
+        if !separators_valid || !component.starts_with(is_alphanumeric) || !component.ends_with(is_alphanumeric) {
+            bail!(
+                "repository name component {:?} may only contain lowercase letters and digits, \
+                 separated by '.', '_', '__' or '-'",
+                component
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Up to 128 letters, digits, `_`, `.` and `-`, not starting with `.` or `-`
+fn validate_tag(tag: &str) -> anyhow::Result<()> {
+    if tag.is_empty() {
+        bail!("the tag after ':' is empty");
+    }
+    if tag.len() > 128 {
+        bail!("tag {:?} is longer than 128 characters", tag);
+    }
+    if tag.starts_with(['.', '-']) || !tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')) {
+        bail!("tag {:?} may only contain letters, digits, '_', '.' and '-', and not start with '.' or '-'", tag);
+    }
+
+    Ok(())
+}
+
+fn validate_digest(digest: &str) -> anyhow::Result<()> {
+    let hex = digest.strip_prefix("sha256:")
+        .with_context(|| format!("Unsupported digest {:?}, expected sha256:<hex>", digest))?;
+
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) {
+        bail!("Invalid digest {:?}, expected 64 lowercase hex characters", digest);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn is_digest(reference: &str) -> bool {
+    reference.starts_with("sha256:")
+}
+
+/// A resolved image manifest and its config, along with the exact bytes the store keeps
+struct FetchedImage {
+    manifest: Manifest,
+    manifest_raw: Vec<u8>,
+    config: ImageConfig,
+    config_raw: Vec<u8>,
+}
+
+async fn fetch_image_manifest(
+    image_name: &str,
+    reference: &str,
+    platform: &Platform,
+    token: &str,
+    client: &HttpClient,
+    mirrors: &[String],
+    cache: &ManifestCache
+) -> anyhow::Result<FetchedImage> {
+    // `reference` is a tag or a digest
+    let manifest_raw = fetch_manifest(image_name, reference, token, client, mirrors, cache).await?;
+    let generic_manifest: GenericManifest = serde_json::from_slice(&manifest_raw)
+        .context("Failed to deserialize generic manifest")?;
+
+    let final_manifest_digest;
+    let final_manifest: Manifest;
+    let final_manifest_raw;
+
+    match generic_manifest {
+        GenericManifest::ImageManifest(manifest) => {
+            debug!("Found single-architecture manifest");
+            final_manifest = manifest;
+            final_manifest_raw = manifest_raw;
+        }
+        GenericManifest::SchemaV1(v1) => {
+            debug!("Found schema 1 manifest");
+            return convert_schema_v1(v1);
+        }
+        GenericManifest::ManifestList(list) => {
+            // A pinned index can't change either, the digests it lists were verified with it
+            debug!("Found manifest list, searching for {}", platform);
+
+            let platform_manifest = list.manifests.iter()
+                .find(|m| platform.matches(&m.platform.os, &m.platform.architecture, m.platform.variant.as_deref()))
+                .with_context(|| {
+                    let available: Vec<String> = list.manifests.iter().map(|m| m.platform.to_string()).collect();
+                    format!("Could not find a {} manifest in the list, available: {}", platform, available.join(", "))
+                })?;
+
+            debug!(?platform_manifest);
+
+            final_manifest_digest = platform_manifest.digest.clone();
+            final_manifest_raw = fetch_manifest(image_name, &final_manifest_digest, token, client, mirrors, cache).await?;
+            final_manifest = serde_json::from_slice(&final_manifest_raw)
+                .context("Failed to deserialize final image manifest")?;
+        }
+    }
+
+    // Config get, content addressed so a cached copy needs no revalidation
+    let config_raw = match cache.get(image_name, &final_manifest.config.digest) {
+        Some(config_raw) => {
+            debug!("Using cached config {}", final_manifest.config.digest);
+            config_raw
+        }
+        None => {
+            let config_path = format!("{}/blobs/{}", image_name, final_manifest.config.digest);
+            let response = registry_get(client, mirrors, &config_path, token, |request| request).await?;
+            let config_raw = http::check_status(response).await
+                .with_context(|| format!("Failed to fetch config {}", final_manifest.config.digest))?
+                .bytes().await?
+                .to_vec();
+
+            // The digest names the image in the store, so it has to be the real one
+            let actual = cache.put(image_name, &config_raw)?;
+            if actual != final_manifest.config.digest {
+                bail!("Config digest mismatch: expected {}, got {}", final_manifest.config.digest, actual);
+            }
+            config_raw
+        }
+    };
+    let config: ImageConfig = serde_json::from_slice(&config_raw).context("Failed to deserialize image config")?;
+
+    debug!(?config);
+
+    Ok(FetchedImage { manifest: final_manifest, manifest_raw: final_manifest_raw, config, config_raw })
+}
+
+/// Turn a schema 1 manifest into the schema 2 shape the rest of woody works with.
+///
+/// The newest history entry becomes the config blob, which gives the image an id
+/// like any other, and the layers are reversed into base first order.
+fn convert_schema_v1(v1: ManifestV1) -> anyhow::Result<FetchedImage> {
+    if v1.schema_version != 1 {
+        bail!("Unsupported manifest schema version {}", v1.schema_version);
+    }
+
+    let config_raw = v1.history.first()
+        .context("Schema 1 manifest has no history to take the image config from")?
+        .v1_compatibility
+        .clone()
+        .into_bytes();
+    let config: ImageConfig = serde_json::from_slice(&config_raw)
+        .context("Failed to deserialize schema 1 image config")?;
+
+    let manifest = Manifest {
+        schema_version: 1,
+        media_type: SCHEMA_V1_MEDIA_TYPE.to_string(),
+        config: Digest { digest: format!("sha256:{:x}", Sha256::digest(&config_raw)), size: Some(config_raw.len() as u64) },
+        layers: v1.fs_layers.into_iter().rev().map(|layer| Digest { digest: layer.blob_sum, size: None }).collect(),
+    };
+    // Stored in converted form, so loading an image never has to care about the schema
+    let manifest_raw = serde_json::to_vec(&manifest)?;
+
+    Ok(FetchedImage { manifest, manifest_raw, config, config_raw })
+}
+
+/// GET `/v2/<path>` from the first of `mirrors` that has it, Docker Hub last.
+///
+/// A mirror that answers 404 or 5xx, or can't be reached, is skipped. `request` adds
+/// the headers. Only Docker Hub gets the bearer `token`: it may have been issued for the
+/// user's credentials, and a mirror, possibly on plain http, must not see those.
+async fn registry_get(
+    client: &HttpClient,
+    mirrors: &[String],
+    path: &str,
+    token: &str,
+    request: impl Fn(RequestBuilder) -> RequestBuilder
+) -> anyhow::Result<Response> {
+    for mirror in mirrors {
+        let url = format!("{}/v2/{}", mirror, path);
+        match client.send(request(client.get(&url))).await {
+            Ok(response) if response.status() == StatusCode::NOT_FOUND || response.status().is_server_error() => {
+                warn!("Mirror {} returned {} for {}, trying the next registry", mirror, response.status(), path);
+            }
+            Ok(response) => return Ok(response),
+            Err(e) => warn!("Mirror {} failed: {:#}, trying the next registry", mirror, e),
+        }
+    }
+
+    let url = format!("{}/v2/{}", DOCKER_HUB, path);
+    client.send(request(client.get(&url)).bearer_auth(token)).await
+}
+
+/// Manifest bytes for `reference`, going through the cache.
+///
+/// A digest is served straight from the cache when present, a tag is revalidated with
+/// the ETag it was last fetched with and only downloaded again if it moved.
+async fn fetch_manifest(
+    image_name: &str,
+    reference: &str,
+    token: &str,
+    client: &HttpClient,
+    mirrors: &[String],
+    cache: &ManifestCache
+) -> anyhow::Result<Vec<u8>> {
+    let cached = if is_digest(reference) {
+        if let Some(raw) = cache.get(image_name, reference) {
+            debug!("Using cached manifest {}@{}", image_name, reference);
+            return Ok(raw);
+        }
+        None
+    } else {
+        cache.tag(image_name, reference)
+            .and_then(|entry| cache.get(image_name, &entry.digest).map(|raw| (entry.etag, raw)))
+    };
+
+    let manifest_path = format!("{}/manifests/{}", image_name, reference);
+    let response = registry_get(client, mirrors, &manifest_path, token, |request| {
+        let request = request.header("Accept", MANIFEST_ACCEPT);
+        match &cached {
+            Some((etag, _)) => request.header(IF_NONE_MATCH, etag),
+            None => request,
+        }
+    }).await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some((_, raw)) = cached {
+            debug!("Manifest for {}:{} not modified", image_name, reference);
+            return Ok(raw);
+        }
+    }
+    let status = response.status();
+    let response = http::check_status(response).await.with_context(|| {
+        let separator = if is_digest(reference) { '@' } else { ':' };
+        match status {
+            StatusCode::NOT_FOUND => format!("Image {}{}{} not found", image_name, separator, reference),
+            _ => format!("Failed to fetch manifest for {}{}{}", image_name, separator, reference),
+        }
+    })?;
+
+    let etag = response.headers().get(ETAG).and_then(|etag| etag.to_str().ok()).map(str::to_string);
+    let raw = response.bytes().await?.to_vec();
+    let digest = cache.put(image_name, &raw)?;
+
+    if is_digest(reference) && digest != reference {
+        bail!("Manifest digest mismatch: expected {}, got {}", reference, digest);
+    }
+    if let (false, Some(etag)) = (is_digest(reference), etag) {
+        cache.set_tag(image_name, reference, &TagEntry { etag, digest })?;
+    }
+
+    Ok(raw)
+}
+
+/// Link each layer into `layers_path`, downloading and extracting only those the
+/// store at `opts.root` doesn't have from another image yet
+async fn download_and_unpack_layers(
+    image_name: &str,
+    token: &str,
+    layers: &[Digest],
+    layers_path: &Path,
+    client: &HttpClient,
+    opts: &PullOptions,
+    on_event: &mut dyn FnMut(PullEvent)
+) -> anyhow::Result<()> {
+    for (index, layer) in layers.iter().enumerate() {
+        if layers::link_shared_layer(&opts.root, &layer.digest, layers_path, index)? {
+            on_event(PullEvent::LayerExists { digest: layer.digest.clone() });
+            continue;
+        }
+
+        let download_path = download_path(&opts.root, &layer.digest);
+        let _lock = lock_download(&download_path, &layer.digest).await?;
+        // A concurrent pull held the lock until it had stored the layer
+        if layers::link_shared_layer(&opts.root, &layer.digest, layers_path, index)? {
+            on_event(PullEvent::LayerExists { digest: layer.digest.clone() });
+            continue;
+        }
+        download_blob(image_name, token, layer, &download_path, client, opts, on_event).await?;
+
+        on_event(PullEvent::LayerUnpacking { digest: layer.digest.clone() });
+        layers::store_shared_layer(&opts.root, &layer.digest, &download_path, layers_path, index)?;
+        fs::remove_file(&download_path)?;
+        on_event(PullEvent::LayerDone { digest: layer.digest.clone() });
+    }
+
+    Ok(())
+}
+
+/// Where a blob is spooled to while it downloads, kept across pulls so an interrupted
+/// download can be resumed. Layers can be larger than memory.
+fn download_path(root: &Path, digest: &str) -> PathBuf {
+    root.join("downloads").join(format!("{}.partial", digest.trim_start_matches("sha256:")))
+}
+
+/// Lock the download of a blob to `path` against other pulls, which would append to and
+/// truncate the same file. Held until the blob is stored, waiting if another pull has it.
+async fn lock_download(path: &Path, digest: &str) -> anyhow::Result<fs::File> {
+    let lock_path = path.with_extension("lock");
+    fs::create_dir_all(lock_path.parent().context("Download path has no parent")?)?;
+    let lock = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+        .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+
+    match flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => return Ok(lock),
+        Err(Errno::EWOULDBLOCK) => info!("Waiting for another pull of layer {}", digest),
+        Err(e) => return Err(e).with_context(|| format!("Failed to lock {}", lock_path.display())),
+    }
+
+    tokio::task::spawn_blocking(move || {
+        flock(lock.as_raw_fd(), FlockArg::LockExclusive)
+            .with_context(|| format!("Failed to lock {}", lock_path.display()))?;
+        Ok(lock)
+    }).await?
+}
+
+/// Download `layer` to `dest` and check its digest, continuing whatever `dest` already has.
+///
+/// A dropped connection is retried as often as [`HttpClient::send`] would, each attempt
+/// asking only for the bytes that are still missing.
+async fn download_blob(
+    image_name: &str,
+    token: &str,
+    layer: &Digest,
+    dest: &Path,
+    client: &HttpClient,
+    opts: &PullOptions,
+    on_event: &mut dyn FnMut(PullEvent)
+) -> anyhow::Result<()> {
+    fs::create_dir_all(dest.parent().context("Download path has no parent")?)?;
+
+    let mut started = false;
+    let mut attempt = 0;
+    loop {
+        match download_range(image_name, token, layer, dest, client, opts, &mut started, on_event).await {
+            Ok(()) => break,
+            // Only a failed transfer is worth resuming, not e.g. a 404
+            Err(e) if attempt < client.max_retries() && e.chain().any(|cause| cause.is::<reqwest::Error>()) => {
+                let delay = http::backoff(attempt);
+                attempt += 1;
+                warn!("Download of layer {} was interrupted: {:#}, resuming in {:?}", layer.digest, e, delay);
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let actual = layers::blob_digest(dest)?;
+    if actual != layer.digest {
+        fs::remove_file(dest).ok();
+        bail!("Layer digest mismatch: expected {}, got {}", layer.digest, actual);
+    }
+
+    Ok(())
+}
+
+/// One attempt of [`download_blob`], a `Range` request from the end of `dest`.
+/// A registry that doesn't do ranges answers with the whole blob, which replaces `dest`.
+#[allow(clippy::too_many_arguments)]
+async fn download_range(
+    image_name: &str,
+    token: &str,
+    layer: &Digest,
+    dest: &Path,
+    client: &HttpClient,
+    opts: &PullOptions,
+    started: &mut bool,
+    on_event: &mut dyn FnMut(PullEvent)
+) -> anyhow::Result<()> {
+    let mut offset = fs::metadata(dest).map(|metadata| metadata.len()).unwrap_or(0);
+    match layer.size {
+        // Finished before, only the digest check is missing
+        Some(size) if offset == size => return Ok(()),
+        Some(size) if offset > size => {
+            fs::remove_file(dest)?;
+            offset = 0;
+        }
+        _ => {}
+    }
+
+    let blob_path = format!("{}/blobs/{}", image_name, layer.digest);
+    let response = registry_get(client, &opts.mirrors, &blob_path, token, |request| {
+        if offset > 0 { request.header(RANGE, format!("bytes={}-", offset)) } else { request }
+    }).await?;
+
+    let resumed = response.status() == StatusCode::PARTIAL_CONTENT && range_start(&response) == Some(offset);
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // Whatever is on disk doesn't fit this blob, the next pull starts over
+        fs::remove_file(dest).ok();
+    }
+    let response = http::check_status(response).await
+        .with_context(|| format!("Failed to download layer {}", layer.digest))?;
+
+    let mut file = if resumed {
+        debug!("Resuming layer {} at byte {}", layer.digest, offset);
+        fs::OpenOptions::new().append(true).open(dest)
+    } else {
+        if offset > 0 {
+            debug!("Registry ignored the range, downloading layer {} from the start", layer.digest);
+        }
+        offset = 0;
+        fs::File::create(dest)
+    }.with_context(|| format!("Failed to open {}", dest.display()))?;
+
+    if !*started {
+        *started = true;
+        let size = layer.size.or(response.content_length().map(|length| length + offset));
+        on_event(PullEvent::LayerStarted { digest: layer.digest.clone(), size });
+    }
+
+    let mut downloaded = offset;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.with_context(|| format!("Failed to download layer {}", layer.digest))?;
+        file.write_all(&chunk)?;
+        downloaded += chunk.len() as u64;
+        on_event(PullEvent::LayerProgress { digest: layer.digest.clone(), downloaded });
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+/// First byte of a 206 response, from `Content-Range: bytes <start>-<end>/<size>`
+fn range_start(response: &Response) -> Option<u64> {
+    response.headers()
+        .get(CONTENT_RANGE)?
+        .to_str().ok()?
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn parse(image_ref: &str) -> (String, String) {
+        parse_image_name(image_ref).unwrap()
+    }
+
+    #[test]
+    fn valid_references_are_split() {
+        assert_eq!(parse("alpine"), ("library/alpine".to_string(), "latest".to_string()));
+        assert_eq!(parse("alpine:3.19"), ("library/alpine".to_string(), "3.19".to_string()));
+        assert_eq!(parse("bitnami/redis:7.2_debian-12"), ("bitnami/redis".to_string(), "7.2_debian-12".to_string()));
+        assert_eq!(parse("my-org/my__app.v2:Latest"), ("my-org/my__app.v2".to_string(), "Latest".to_string()));
+        assert_eq!(parse("docker.io/library/alpine:edge"), ("library/alpine".to_string(), "edge".to_string()));
+        assert_eq!(parse(&format!("alpine:3.19@{}", DIGEST)), ("library/alpine".to_string(), DIGEST.to_string()));
+        assert_eq!(parse(&format!("alpine@{}", DIGEST)).1, DIGEST);
+    }
+
+    #[test]
+    fn invalid_references_name_the_offending_part() {
+        let error = |image_ref: &str| format!("{:#}", parse_image_name(image_ref).unwrap_err());
+
+        assert!(error("").contains("repository name is empty"));
+        assert!(error("Alpine").contains("\"Alpine\" must be lowercase"));
+        assert!(error("library/Alpine:3").contains("\"Alpine\" must be lowercase"));
+        assert!(error(":::").contains("tag after ':' is empty"));
+        assert!(error("alpine::3").contains("component \"alpine:\""));
+        assert!(error("alpine:").contains("tag after ':' is empty"));
+        assert!(error("alpine:-rc").contains("tag \"-rc\""));
+        assert!(error("alpine:3.19!").contains("tag \"3.19!\""));
+        assert!(error("my_-app").contains("component \"my_-app\""));
+        assert!(error("-app").contains("component \"-app\""));
+        assert!(error("library//alpine").contains("empty path component"));
+        assert!(error("alpine@sha256:abc").contains("64 lowercase hex"));
+        assert!(error("alpine@md5:0123").contains("Unsupported digest"));
+        assert!(error("ghcr.io/owner/app").contains("only pulls from Docker Hub"));
+        assert!(error("localhost:5000/app:1").contains("\"localhost:5000\""));
+    }
+}
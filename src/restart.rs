@@ -0,0 +1,71 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context};
+
+/// First delay before restarting a container, doubled for every restart in a row
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest delay between two restarts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A run at least this long counts as up, the next crash starts the backoff over
+pub const RESET_BACKOFF_AFTER: Duration = Duration::from_secs(10);
+
+/// What the supervisor of a detached container does once it exits, like docker's `--restart`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    /// Restart on a non-zero exit, at most `max` times if set
+    OnFailure { max: Option<u32> },
+    Always,
+    /// The same as always without a daemon to restart, kept for docker compatibility
+    UnlessStopped,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once(':') {
+            None if s == "no" => Ok(RestartPolicy::No),
+            None if s == "on-failure" => Ok(RestartPolicy::OnFailure { max: None }),
+            None if s == "always" => Ok(RestartPolicy::Always),
+            None if s == "unless-stopped" => Ok(RestartPolicy::UnlessStopped),
+            Some(("on-failure", max)) => {
+                let max = max.parse().with_context(|| format!("Invalid restart count {:?} in {:?}", max, s))?;
+                Ok(RestartPolicy::OnFailure { max: Some(max) })
+            }
+            _ => bail!("Unknown restart policy {:?}, expected no, on-failure[:max], always or unless-stopped", s),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestartPolicy::No => write!(f, "no"),
+            RestartPolicy::OnFailure { max: None } => write!(f, "on-failure"),
+            RestartPolicy::OnFailure { max: Some(max) } => write!(f, "on-failure:{}", max),
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::UnlessStopped => write!(f, "unless-stopped"),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Whether a container that exited with `exit_code` after `restarts` restarts goes again.
+    /// One stopped with `woody stop` or `woody kill` never does.
+    pub fn should_restart(&self, exit_code: i32, restarts: u32) -> bool {
+        match self {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure { max } => exit_code != 0 && max.is_none_or(|max| restarts < max),
+            RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+        }
+    }
+}
+
+/// Delay before the restart following `crashes` quick exits in a row
+pub fn backoff(crashes: u32) -> Duration {
+    INITIAL_BACKOFF.saturating_mul(1 << crashes.min(16)).min(MAX_BACKOFF)
+}
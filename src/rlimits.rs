@@ -0,0 +1,72 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use nix::sys::resource::{setrlimit, Resource};
+
+/// A `--ulimit name=soft[:hard]` entry, `None` stands for unlimited
+#[derive(Debug, Clone)]
+pub struct Ulimit {
+    pub resource: Resource,
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+impl FromStr for Ulimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, limits) = s.split_once('=')
+            .with_context(|| format!("Invalid ulimit {:?}, expected name=soft[:hard]", s))?;
+
+        let resource = match name {
+            "nofile" => Resource::RLIMIT_NOFILE,
+            "nproc" => Resource::RLIMIT_NPROC,
+            "core" => Resource::RLIMIT_CORE,
+            "fsize" => Resource::RLIMIT_FSIZE,
+            "as" => Resource::RLIMIT_AS,
+            "cpu" => Resource::RLIMIT_CPU,
+            "data" => Resource::RLIMIT_DATA,
+            "memlock" => Resource::RLIMIT_MEMLOCK,
+            "stack" => Resource::RLIMIT_STACK,
+            "sigpending" => Resource::RLIMIT_SIGPENDING,
+            "msgqueue" => Resource::RLIMIT_MSGQUEUE,
+            other => bail!("Unsupported ulimit {:?}", other),
+        };
+
+        let parse_limit = |limit: &str| -> anyhow::Result<Option<u64>> {
+            match limit {
+                "unlimited" | "-1" => Ok(None),
+                value => value.parse::<u64>()
+                    .map(Some)
+                    .with_context(|| format!("Invalid ulimit value {:?} in {:?}", value, s)),
+            }
+        };
+
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (parse_limit(soft)?, parse_limit(hard)?),
+            None => {
+                let limit = parse_limit(limits)?;
+                (limit, limit)
+            }
+        };
+
+        // None is infinity, so only a finite hard limit can be exceeded
+        if let (soft, Some(hard)) = (soft, hard) {
+            if soft.is_none_or(|soft| soft > hard) {
+                bail!("Soft limit exceeds hard limit in {:?}", s);
+            }
+        }
+
+        Ok(Ulimit { resource, soft, hard })
+    }
+}
+
+/// Raising a hard limit needs CAP_SYS_RESOURCE, so this runs before capabilities are dropped
+pub fn apply_ulimits(ulimits: &[Ulimit]) -> anyhow::Result<()> {
+    for ulimit in ulimits {
+        setrlimit(ulimit.resource, ulimit.soft, ulimit.hard)
+            .with_context(|| format!("Failed to set {:?}", ulimit.resource))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,963 @@
+use std::{borrow::Cow, convert::Infallible, env, ffi::CString, fs, io::Read, net::{IpAddr, Ipv4Addr}, os::unix::{io::{AsRawFd, RawFd}, process::ExitStatusExt}, path::{Path, PathBuf}, process::ExitStatus, str::FromStr, thread, time::{Duration, Instant}};
+
+use anyhow::{bail, Context};
+use caps::CapsHashSet;
+use nix::{errno::Errno, fcntl::OFlag, sched::{setns, unshare, CloneFlags}, sys::{signal::{kill, Signal}, stat::Mode, wait::{waitpid, WaitPidFlag, WaitStatus}}, unistd::{close, dup2, execve, fork, pipe, read, sethostname, setsid, write, ForkResult, Pid}};
+use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM}, iterator::Signals};
+use tracing::{debug, error, info, info_span, warn};
+
+use crate::{
+    capabilities, cgroups::{self, CgroupError, CgroupManager, CgroupMode, ResourceLimits}, command, control::{exit_code, is_alive, parse_signal, send_signal}, elf::{self, Interpreter}, environment, etc::{self, HostEntry}, health::{self, Health, HealthOptions}, idmap, layers,
+    logs::{self, LogFormat, LogWriter, Stream}, mounts, network::{self, NetworkMode, PortMapping, Subnet}, paths,
+    registry::{ConfigDetails, LocalImage}, restart::{self, RestartPolicy}, rlimits::{self, Ulimit}, seccomp::{self, SeccompMode},
+    state::{ContainerState, Status}, tty, users::{self, ExecUser}, volumes::{TmpfsMount, VolumeMount},
+};
+
+/// Exit code of a container whose command couldn't be executed, like a shell's
+const EXEC_FAILED: i32 = 127;
+
+/// Namespaces a container gets of its own unless told to share the host's, pid and
+/// user ones are always shared
+const NAMESPACES: CloneFlags = CloneFlags::CLONE_NEWNS
+    .union(CloneFlags::CLONE_NEWUTS)
+    .union(CloneFlags::CLONE_NEWIPC)
+    .union(CloneFlags::CLONE_NEWNET);
+
+/// Whether a container gets a namespace of its own, like docker's `--ipc` and `--uts`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NamespaceMode {
+    #[default]
+    Private,
+    /// The host's, whatever is set up in it is seen by the container and the other way around
+    Host,
+}
+
+impl FromStr for NamespaceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "private" => Ok(NamespaceMode::Private),
+            "host" => Ok(NamespaceMode::Host),
+            other => bail!("Unknown namespace mode {:?}, expected private or host", other),
+        }
+    }
+}
+
+/// Everything about a container that isn't the image itself
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    pub name: Option<String>,
+    pub volumes: Vec<VolumeMount>,
+    pub tmpfs: Vec<TmpfsMount>,
+    pub network: NetworkMode,
+    pub subnet: Subnet,
+    pub ipc: NamespaceMode,
+    pub uts: NamespaceMode,
+    pub dns: Vec<IpAddr>,
+    /// Extra /etc/hosts entries
+    pub extra_hosts: Vec<HostEntry>,
+    pub ports: Vec<PortMapping>,
+    /// `-P`, publish the image's exposed ports `ports` doesn't map on free host ports
+    pub publish_all: bool,
+    pub capabilities: CapsHashSet,
+    pub seccomp: SeccompMode,
+    pub ulimits: Vec<Ulimit>,
+    pub tty: bool,
+    pub interactive: bool,
+    pub log_path: Option<PathBuf>,
+    pub log_format: LogFormat,
+    pub detach: bool,
+    /// Keep the container's output to its log, even when not detached
+    pub quiet: bool,
+    /// Delete the container's directory once it exits, otherwise it stays until `woody rm`
+    pub remove: bool,
+    /// `--bind-host-bins`: bind the host's /bin, /lib, ... over the container's own
+    pub bind_host_bins: bool,
+    pub workdir: Option<String>,
+    /// `KEY=VALUE` from -e and --env-file, in command line order
+    pub env: Vec<String>,
+    pub hostname: Option<String>,
+    /// `--entrypoint`, `Some("")` clears the image's
+    pub entrypoint: Option<String>,
+    /// Arguments after the image name, replacing the image's Cmd
+    pub command: Vec<String>,
+    pub resources: ResourceLimits,
+    pub cgroup: CgroupMode,
+    /// Bytes, the default size if unset
+    pub shm_size: Option<u64>,
+    /// Only honoured with `detach`, the supervisor does the restarting
+    pub restart: RestartPolicy,
+    /// Overrides of the image's health check
+    pub health: HealthOptions,
+    /// `user[:group]`, overrides the image's User
+    pub user: Option<String>,
+    /// What `woody stop` sends, overrides the image's StopSignal
+    pub stop_signal: Option<Signal>,
+}
+
+impl RunOptions {
+    /// What to unshare, [`NAMESPACES`] less the ones shared with the host
+    pub fn namespaces(&self) -> CloneFlags {
+        let mut namespaces = NAMESPACES;
+        if matches!(self.network, NetworkMode::Host | NetworkMode::Container(_)) {
+            namespaces.remove(CloneFlags::CLONE_NEWNET);
+        }
+        if self.ipc == NamespaceMode::Host {
+            namespaces.remove(CloneFlags::CLONE_NEWIPC);
+        }
+        if self.uts == NamespaceMode::Host {
+            namespaces.remove(CloneFlags::CLONE_NEWUTS);
+        }
+        namespaces
+    }
+}
+
+/// Allocate a container directory under the woody root, named `name` or a random id.
+///
+/// An existing container of the same name is an error, stopped ones are kept
+/// for inspection until `woody rm` deletes them.
+pub fn create_container(name: Option<&str>) -> anyhow::Result<String> {
+    let container_id = match name {
+        Some(name) => {
+            validate_container_name(name)?;
+            name.to_string()
+        }
+        None => generate_container_id()?,
+    };
+    ensure_not_running(&container_id)?;
+
+    let base_path = paths::container_dir(&container_id);
+    if base_path.exists() {
+        bail!("Container name {} is already in use, remove it with woody rm {}", container_id, container_id);
+    }
+    fs::create_dir_all(&base_path)?;
+
+    Ok(container_id)
+}
+
+/// What the container's overlay is stacked on
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Image(&'a LocalImage),
+    /// `woody run --rootfs`, a directory standing in for the image's layers
+    Rootfs(&'a Path),
+}
+
+impl Source<'_> {
+    /// The image's config, a rootfs only comes with the default PATH
+    fn config(&self) -> Cow<'_, ConfigDetails> {
+        match self {
+            Source::Image(image) => Cow::Borrowed(&image.config.config),
+            Source::Rootfs(_) => Cow::Owned(ConfigDetails {
+                env: vec![format!("PATH={}", environment::DEFAULT_PATH)],
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Run `image` until it exits.
+///
+/// `opts.name` picks an existing directory from [`create_container`], without
+/// it a fresh one is created. A detached run returns success as soon as the
+/// container is up, its supervisor records the real exit later.
+pub fn run(image: &LocalImage, opts: &RunOptions) -> anyhow::Result<ExitStatus> {
+    start(Source::Image(image), opts)
+}
+
+/// Like [`run`] with an existing directory as the root filesystem instead of an image.
+///
+/// The directory is mounted as the overlay's only lower layer, so it's never
+/// written to, the container's changes end up in its upper dir as usual.
+pub fn run_rootfs(rootfs: &Path, opts: &RunOptions) -> anyhow::Result<ExitStatus> {
+    if !rootfs.is_dir() {
+        bail!("Root filesystem {} is not a directory", rootfs.display());
+    }
+
+    start(Source::Rootfs(rootfs), opts)
+}
+
+fn start(source: Source, opts: &RunOptions) -> anyhow::Result<ExitStatus> {
+    let container_id = match &opts.name {
+        Some(name) => {
+            validate_container_name(name)?;
+            ensure_not_running(name)?;
+            fs::create_dir_all(paths::container_dir(name))?;
+            name.clone()
+        }
+        None => create_container(None)?,
+    };
+
+    if opts.detach {
+        run_detached(&container_id, opts, source)?;
+        return Ok(ExitStatus::from_raw(0));
+    }
+
+    let status = run_container(&container_id, opts, source, 0);
+    if opts.remove {
+        remove_container_dir(&container_id)?;
+    }
+
+    // Raw wait status layout: exit code in the second byte, or the signal in the low bits
+    Ok(match status? {
+        WaitStatus::Exited(_, code) => ExitStatus::from_raw(code << 8),
+        WaitStatus::Signaled(_, signal, _) => ExitStatus::from_raw(signal as i32),
+        _ => ExitStatus::from_raw(1 << 8),
+    })
+}
+
+fn ensure_not_running(container_id: &str) -> anyhow::Result<()> {
+    if let Ok(state) = ContainerState::load(container_id) {
+        if state.status == Status::Running && is_alive(Pid::from_raw(state.pid)) {
+            bail!("Container name {} is already in use by a running container", container_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Names end up as directory names, so keep them to a safe charset
+pub fn validate_container_name(name: &str) -> anyhow::Result<()> {
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+
+    if !valid {
+        bail!("Invalid container name {:?}: must match [a-zA-Z0-9][a-zA-Z0-9_.-]*", name);
+    }
+
+    // Shares the woody root with the container directories
+    if name == "images" {
+        bail!("Container name {:?} is reserved", name);
+    }
+
+    Ok(())
+}
+
+/// From <bits/local_lim.h>, the libc crate doesn't export it
+const HOST_NAME_MAX: usize = 64;
+
+/// RFC 1123: dot separated labels of letters, digits and inner hyphens
+pub fn validate_hostname(hostname: &str) -> anyhow::Result<()> {
+    if hostname.is_empty() || hostname.len() > HOST_NAME_MAX {
+        bail!("Invalid hostname {:?}: must be 1 to {} characters", hostname, HOST_NAME_MAX);
+    }
+
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if !hostname.split('.').all(valid_label) {
+        bail!("Invalid hostname {:?}: only letters, digits, '-' and '.' separated labels are allowed", hostname);
+    }
+
+    Ok(())
+}
+
+/// Short form of the id, with the characters a name allows but a hostname doesn't replaced
+fn default_hostname(container_id: &str) -> String {
+    let hostname: String = container_id.chars()
+        .take(12)
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+
+    match hostname.trim_matches('-') {
+        "" => "woody".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// 12 hex chars, same short form docker shows
+fn generate_container_id() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 6];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .context("Failed to read /dev/urandom")?;
+
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Runs the container to completion and returns how it exited
+fn run_container(container_id: &str, opts: &RunOptions, source: Source, restart_count: u32) -> anyhow::Result<WaitStatus> {
+    let _span = info_span!("container", id = container_id).entered();
+
+    if !nix::unistd::geteuid().is_root() {
+        bail!("You must run this program as root. Try with sudo.");
+    }
+
+    // Compiled up front so a bad profile fails before anything is forked
+    let seccomp_filters = seccomp::compile(&opts.seccomp, &opts.capabilities)?;
+    let config = source.config();
+    let argv = command::resolve(&config, opts.entrypoint.as_deref(), &opts.command)?;
+    let health_check = health::resolve(config.healthcheck.as_ref(), &opts.health);
+    // Checked now, a bad one would only show up once the container is to be stopped
+    let stop_signal = match (opts.stop_signal, config.stop_signal.as_deref()) {
+        (Some(signal), _) => Some(signal),
+        (None, Some(name)) => match parse_signal(name) {
+            Ok(signal) => Some(signal),
+            Err(e) => {
+                warn!("Ignoring the image's StopSignal, stopping with SIGTERM: {:#}", e);
+                None
+            }
+        },
+        (None, None) => None,
+    };
+
+    let mut ports = opts.ports.clone();
+    if opts.publish_all {
+        for exposed in config.exposed_ports.iter().flat_map(|exposed| exposed.keys()) {
+            let mapping = PortMapping::for_exposed(exposed)?;
+            if !ports.iter().any(|port| port.container_port == mapping.container_port && port.protocol == mapping.protocol) {
+                ports.push(mapping);
+            }
+        }
+    }
+
+    // Opened up front so a target that isn't running fails before anything is forked
+    let joined_netns = match &opts.network {
+        NetworkMode::Container(target) => Some(network::container_netns(target)?),
+        _ => None,
+    };
+
+    // The child waits on `go` until the parent has set up its network namespace
+    let (ready_rx, ready_tx) = pipe()?;
+    let (go_rx, go_tx) = pipe()?;
+
+    let pty = if opts.tty { Some(tty::open_pty()?) } else { None };
+
+    let log_path = opts.log_path.clone().unwrap_or_else(|| logs::default_log_path(container_id));
+    let log = LogWriter::create(&log_path, opts.log_format, !opts.detach && !opts.quiet)?;
+
+    // With a tty both streams share the pty, otherwise each gets its own pipe
+    let output_pipes = if pty.is_none() { Some((pipe()?, pipe()?)) } else { None };
+
+    match unsafe { fork() } {
+        Ok(ForkResult::Parent { child, .. }) => {
+            debug!("Container PID from parent: {}", child);
+            close(ready_tx)?;
+            close(go_rx)?;
+            if let Some(pty) = &pty {
+                close(pty.slave)?;
+            }
+
+            let mut pumps = Vec::new();
+            match (&pty, output_pipes) {
+                (Some(pty), _) => pumps.push(log.clone().pump(pty.master, Stream::Stdout)),
+                (None, Some(((stdout_rx, stdout_tx), (stderr_rx, stderr_tx)))) => {
+                    close(stdout_tx)?;
+                    close(stderr_tx)?;
+                    pumps.push(log.clone().pump(stdout_rx, Stream::Stdout));
+                    pumps.push(log.clone().pump(stderr_rx, Stream::Stderr));
+                }
+                (None, None) => {}
+            }
+
+            let forwarder = forward_signals(child)?;
+
+            wait_for(ready_rx).context("Container exited before setting up namespaces")?;
+
+            let cgroup = match setup_cgroup(container_id, child, opts) {
+                Ok(cgroup) => cgroup,
+                Err(e) => {
+                    kill(child, Signal::SIGKILL).ok();
+                    waitpid(child, None).ok();
+                    return Err(e);
+                }
+            };
+
+            let container_ip = match opts.network {
+                NetworkMode::Bridge => match network::setup_bridge_network(child, &opts.subnet)
+                    .and_then(|ip| match network::publish_ports(ip, &ports) {
+                        Ok(()) => Ok(ip),
+                        Err(e) => {
+                            network::release_ip(ip);
+                            Err(e)
+                        }
+                    })
+                {
+                    Ok(ip) => Some(ip),
+                    Err(e) => {
+                        kill(child, Signal::SIGKILL).ok();
+                        waitpid(child, None).ok();
+                        if let Some(cgroup) = &cgroup {
+                            cgroup.destroy().ok();
+                        }
+                        return Err(e.context("Failed to set up container network"));
+                    }
+                },
+                NetworkMode::Loopback | NetworkMode::None | NetworkMode::Host | NetworkMode::Container(_) => None,
+            };
+
+            ContainerState {
+                id: container_id.to_string(),
+                image: match source {
+                    Source::Image(image) => image.to_string(),
+                    Source::Rootfs(rootfs) => rootfs.display().to_string(),
+                },
+                image_id: match source {
+                    Source::Image(image) => Some(image.id().to_string()),
+                    Source::Rootfs(_) => None,
+                },
+                pid: child.as_raw(),
+                status: Status::Running,
+                ip_address: container_ip,
+                log_path: Some(log_path.clone()),
+                log_format: opts.log_format,
+                cgroup: cgroup.as_ref().map(|_| cgroups::cgroup_name(container_id)),
+                restart_count,
+                stop_requested: false,
+                health: health_check.as_ref().map(|_| Health::Starting),
+                stop_signal: stop_signal.map(|signal| signal.as_str().to_string()),
+                user: Some(opts.user.as_deref().unwrap_or(&config.user)).filter(|user| !user.is_empty()).map(str::to_string),
+                capabilities: Some(opts.capabilities.iter().map(ToString::to_string).collect()),
+                seccomp: Some(opts.seccomp.to_string()),
+            }.save()?;
+
+            notify(go_tx)?;
+            let monitor = health_check.map(|check| health::Monitor::start(container_id, child, check));
+
+            debug!("Waiting for child {}", child);
+
+            // Restored when the guard drops, whichever way this function returns
+            let mut raw_mode = None;
+            if let Some(pty) = &pty {
+                raw_mode = Some(tty::RawModeGuard::enable()?);
+                tty::forward_input(pty.master, opts.interactive)?;
+            }
+
+            let status = loop {
+                match waitpid(child, None) {
+                    Err(Errno::EINTR) => continue,
+                    result => break result?,
+                }
+            };
+            forwarder.close();
+            if let Some(monitor) = monitor {
+                monitor.stop();
+            }
+
+            // Drain whatever the container wrote last before restoring the terminal
+            for pump in pumps {
+                pump.join().ok();
+            }
+            drop(raw_mode);
+            info!("Container exited with status: {:?}", status);
+
+            if let Some(ip) = container_ip {
+                network::unpublish_ports(ip, &ports);
+                network::release_ip(ip);
+            }
+
+            teardown_mounts(container_id)?;
+            if let Some(cgroup) = cgroup {
+                cgroup.destroy()?;
+            }
+
+            // stop / kill may have already recorded why the container went down
+            let mut state = ContainerState::load(container_id)?;
+            if state.status == Status::Running {
+                state.status = Status::Exited;
+                state.save()?;
+            }
+
+            Ok(status)
+        }
+        Ok(ForkResult::Child) => {
+            // Never return from here, the forked copy of the CLI would carry on as a
+            // second parent and hang on a runtime whose threads didn't come along
+            let setup = || -> anyhow::Result<Option<ExecUser>> {
+                close(ready_rx)?;
+                close(go_tx)?;
+
+                if let Some(((stdout_rx, stdout_tx), (stderr_rx, stderr_tx))) = output_pipes {
+                    close(stdout_rx)?;
+                    close(stderr_rx)?;
+                    dup2(stdout_tx, libc::STDOUT_FILENO)?;
+                    dup2(stderr_tx, libc::STDERR_FILENO)?;
+                    close(stdout_tx)?;
+                    close(stderr_tx)?;
+                }
+
+                match &pty {
+                    Some(pty) => tty::attach_to_slave(pty)?,
+                    // Like docker, stdin is only kept open with -i
+                    None if !opts.interactive => {
+                        let null = nix::fcntl::open("/dev/null", OFlag::O_RDONLY, Mode::empty())?;
+                        dup2(null, libc::STDIN_FILENO)?;
+                        close(null)?;
+                    }
+                    None => {}
+                }
+
+                if let Some(netns) = &joined_netns {
+                    setns(netns.as_raw_fd(), CloneFlags::CLONE_NEWNET).context("Failed to join the container's network")?;
+                }
+                unshare(opts.namespaces()).context("Failed to unshare namespaces")?;
+                mounts::make_root_private()?;
+
+                // `none` means truly nothing, not even loopback, and the host's is up already
+                if matches!(opts.network, NetworkMode::Bridge | NetworkMode::Loopback) {
+                    network::bring_up_loopback()?;
+                }
+
+                notify(ready_tx)?;
+                wait_for(go_rx).context("Parent exited before the container was set up")?;
+
+                // A shared UTS namespace keeps the host's name, setting it would rename the host
+                let hostname = match opts.uts {
+                    NamespaceMode::Private => opts.hostname.clone().unwrap_or_else(|| default_hostname(container_id)),
+                    NamespaceMode::Host => fs::read_to_string("/proc/sys/kernel/hostname")?.trim().to_string(),
+                };
+                let hostname = hostname.as_str();
+                // The address is the joined container's, so the hostname resolves to it
+                let container_ip = match &opts.network {
+                    NetworkMode::Container(target) => ContainerState::load(target)?.ip_address,
+                    _ => ContainerState::load(container_id)?.ip_address,
+                };
+
+                // Everything the container's root gets, directories included, is created
+                // here while woody still holds all its capabilities
+                mount_fs(container_id, source, opts, hostname, container_ip).context("Could not mount fs.")?;
+
+                if opts.uts == NamespaceMode::Private {
+                    sethostname(hostname).context("Failed to set hostname.")?;
+                }
+
+                rlimits::apply_ulimits(&opts.ulimits)?;
+
+                // Looked up in the container's own passwd and group files
+                let user = match opts.user.as_deref().unwrap_or(&config.user) {
+                    "" => None,
+                    spec => Some(users::resolve(spec, Path::new("/"))?),
+                };
+
+                capabilities::drop_capabilities(&opts.capabilities, user.as_ref())?;
+
+                // Last step before exec, the filters may deny syscalls the setup needs
+                seccomp::apply(&seccomp_filters)?;
+                Ok(user)
+            };
+            let user = match setup() {
+                Ok(user) => user,
+                Err(e) => {
+                    error!("{:#}", e);
+                    unsafe { libc::_exit(1) }
+                }
+            };
+
+            let mut env = environment::merge(&config.env, &opts.env);
+            // Like docker, HOME follows the user unless set
+            if let Some(user) = user.filter(|_| !env.iter().any(|var| var.starts_with("HOME="))) {
+                env.push(format!("HOME={}", user.home.as_deref().unwrap_or("/")));
+            }
+            let Err(e) = exec_command(&argv, env);
+            error!("{:#}", e);
+            unsafe { libc::_exit(EXEC_FAILED) }
+        }
+        Err(e) => {
+            bail!("Fork failed: {}", e);
+        }
+    }
+}
+
+/// Put `child` into a cgroup of its own as `opts.cgroup` says.
+///
+/// Every container gets one for `woody stats`, but plenty of hosts don't delegate
+/// cgroups to root in a container, so in auto mode it's only fatal for limits.
+fn setup_cgroup(container_id: &str, child: Pid, opts: &RunOptions) -> anyhow::Result<Option<CgroupManager>> {
+    if opts.cgroup == CgroupMode::Disabled {
+        if !opts.resources.is_empty() {
+            warn!("Ignoring resource limits, cgroups are disabled");
+        }
+        return Ok(None);
+    }
+
+    match CgroupManager::setup(container_id, child, &opts.resources) {
+        Ok(cgroup) => Ok(Some(cgroup)),
+        Err(e) => {
+            CgroupManager::new(container_id).destroy().ok();
+
+            if opts.cgroup == CgroupMode::Auto && opts.resources.is_empty() {
+                warn!("Running without a cgroup, woody stats won't be available: {:#}", e);
+                Ok(None)
+            } else if CgroupError::is_unavailable(&e) {
+                Err(e.context("Resource limits need cgroups, pass --cgroup=disabled to run without them"))
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Hand the container to a background supervisor and return once it's running.
+///
+/// The supervisor is what waits on the container, records its exit and drains
+/// its output into the log file, so it has to outlive this CLI invocation.
+fn run_detached(container_id: &str, opts: &RunOptions, source: Source) -> anyhow::Result<()> {
+    match unsafe { fork() }.context("Failed to fork supervisor")? {
+        ForkResult::Child => {
+            // Detach from the terminal so closing it doesn't SIGHUP the container
+            setsid().ok();
+
+            let supervisor_log = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(paths::container_dir(container_id).join("supervisor.log"))?;
+            let null = nix::fcntl::open("/dev/null", OFlag::O_RDONLY, Mode::empty())?;
+            dup2(null, libc::STDIN_FILENO)?;
+            dup2(supervisor_log.as_raw_fd(), libc::STDOUT_FILENO)?;
+            dup2(supervisor_log.as_raw_fd(), libc::STDERR_FILENO)?;
+            close(null)?;
+
+            let code = supervise(container_id, opts, source);
+            if opts.remove {
+                if let Err(e) = remove_container_dir(container_id) {
+                    error!("{:?}", e);
+                }
+            }
+
+            // Skip the runtime teardown, its worker threads only exist in the parent
+            std::process::exit(code);
+        }
+        ForkResult::Parent { child } => {
+            // Wait for the supervisor to record the container, or to give up. By then it
+            // may have exited or be restarting already, it did start either way.
+            loop {
+                if ContainerState::load(container_id).is_ok() {
+                    println!("{}", container_id);
+                    return Ok(());
+                }
+
+                if let WaitStatus::Exited(..) | WaitStatus::Signaled(..) = waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+                    bail!(
+                        "Container failed to start, see {}",
+                        paths::container_dir(container_id).join("supervisor.log").display()
+                    );
+                }
+
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+/// Run the container and restart it as `opts.restart` says, returning the last exit code.
+///
+/// Between runs the container is `restarting`, `woody stop` during the backoff
+/// ends it for good, as does `woody rm`.
+fn supervise(container_id: &str, opts: &RunOptions, source: Source) -> i32 {
+    let mut restarts = 0;
+    let mut crashes = 0;
+
+    loop {
+        let started = Instant::now();
+        let code = match run_container(container_id, opts, source, restarts) {
+            Ok(status) => exit_code(status),
+            Err(e) => {
+                error!("{:?}", e);
+                1
+            }
+        };
+
+        let wants_restart = |state: &ContainerState| !state.stop_requested && opts.restart.should_restart(code, restarts);
+        match ContainerState::load(container_id) {
+            Ok(mut state) if wants_restart(&state) => {
+                state.status = Status::Restarting;
+                state.save().ok();
+            }
+            _ => return code,
+        }
+
+        crashes = if started.elapsed() >= restart::RESET_BACKOFF_AFTER { 0 } else { crashes + 1 };
+        let delay = restart::backoff(crashes);
+        info!("Container exited with code {}, restarting in {:?}", code, delay);
+        thread::sleep(delay);
+
+        match ContainerState::load(container_id) {
+            Ok(state) if !state.stop_requested => restarts += 1,
+            _ => return code,
+        }
+    }
+}
+
+/// Relay termination signals sent to woody on to the container.
+///
+/// signal-hook only records the signal in its handler, the actual `kill` runs
+/// on a regular thread so nothing async-signal-unsafe happens in the handler.
+fn forward_signals(child: Pid) -> anyhow::Result<signal_hook::iterator::Handle> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])
+        .context("Failed to install signal handlers")?;
+    let handle = signals.handle();
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if let Ok(signal) = Signal::try_from(signal) {
+                info!("Forwarding {} to container", signal);
+                send_signal(child, signal).ok();
+            }
+        }
+    });
+
+    Ok(handle)
+}
+
+/// One-shot handshake over a pipe, the write end is closed after signalling
+fn notify(fd: RawFd) -> anyhow::Result<()> {
+    write(fd, &[1])?;
+    close(fd)?;
+
+    Ok(())
+}
+
+fn wait_for(fd: RawFd) -> anyhow::Result<()> {
+    let mut buf = [0u8; 1];
+    let n = read(fd, &mut buf)?;
+    close(fd)?;
+
+    if n == 0 {
+        bail!("Pipe closed without notification");
+    }
+
+    Ok(())
+}
+
+/// Mounts made in the container's namespace can propagate back to the host, so sweep them up
+fn teardown_mounts(container_id: &str) -> anyhow::Result<()> {
+    let container_root = paths::container_dir(container_id);
+    mounts::unmount_all(&container_root)?;
+
+    // Only the mount scaffolding goes, upper keeps the container's changes
+    for dir in ["merged", "work"] {
+        let path = container_root.join(dir);
+        if path.exists() {
+            fs::remove_dir_all(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `--rm`, nothing of the container is left once it exited
+fn remove_container_dir(container_id: &str) -> anyhow::Result<()> {
+    debug!("Removing container {}", container_id);
+    mounts::remove_dir_all(&paths::container_dir(container_id))
+}
+
+/// Mount the container's root and chroot into it, ending in its working directory.
+///
+/// The order matters: files written from the host side go in before the chroot,
+/// and every directory it creates, the working directory and mount points, is
+/// created by woody as the root of its user namespace and owned by it. Only that
+/// root may chown them, so this runs before capabilities are dropped.
+fn mount_fs(
+    container_id: &str,
+    source: Source,
+    opts: &RunOptions,
+    hostname: &str,
+    container_ip: Option<Ipv4Addr>
+) -> anyhow::Result<()> {
+    // OverlayFS integration
+    let container_root = paths::container_dir(container_id);
+    let upperdir = container_root.join("upper");
+    let workdir = container_root.join("work");
+    let merged = container_root.join("merged");
+    fs::create_dir_all(&upperdir)?;
+    fs::create_dir_all(&workdir)?;
+    fs::create_dir_all(&merged)?;
+    debug!("Created overlayfs dirs");
+
+    // Use merge dir as hub for upper and lower dirs
+    match source {
+        Source::Image(image) => layers::mount_overlay(&image.layers_path, &upperdir, &workdir, &merged)?,
+        Source::Rootfs(rootfs) => layers::mount_overlay_dir(rootfs, &upperdir, &workdir, &merged)?,
+    }
+    debug!("Initializing container on: {:?}", merged.canonicalize()?);
+
+    // Written after the overlay is up so they land in the upper dir
+    etc::write_resolv_conf(&merged, &opts.dns, opts.network == NetworkMode::Host)?;
+    etc::write_hostname(&merged, hostname)?;
+    etc::write_hosts(&merged, hostname, container_ip.map(IpAddr::V4), &opts.extra_hosts)?;
+
+    // The merged view, not a lower layer, so writes are copied up into upper
+    mounts::setup_rootfs(&merged, &mounts::RootfsOptions {
+        volumes: &opts.volumes,
+        tmpfs: &opts.tmpfs,
+        bind_host_bins: opts.bind_host_bins,
+        host_ipc: opts.ipc == NamespaceMode::Host,
+        shm_size: opts.shm_size,
+    })?;
+
+    // -w wins over the image, and like docker a missing directory is created rather than fatal
+    let config = source.config();
+    let work_dir = opts.workdir.as_deref().unwrap_or(&config.working_dir);
+    if !work_dir.is_empty() {
+        let work_dir = Path::new("/").join(work_dir);
+        idmap::create_dir_all_owned(&work_dir)
+            .with_context(|| format!("Failed to create working directory: {}", work_dir.display()))?;
+        env::set_current_dir(&work_dir)
+            .with_context(|| format!("Failed to change to working directory: {}", work_dir.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Only returns if the exec failed, saying what most likely went wrong
+fn exec_command(argv: &[String], env: Vec<String>) -> anyhow::Result<Infallible> {
+    let command_c = CString::new(argv[0].as_str())?;
+    let args_c: Vec<CString> = argv.iter()
+        .map(|s| CString::new(s.as_bytes()))
+        .collect::<Result<_, _>>()?;
+    let env_c = environment::to_exec(&env);
+
+    debug!(command = ?command_c, args = ?args_c, env = ?env_c, "Executing command");
+    let Err(errno) = execve(&command_c, &args_c, &env_c);
+
+    match exec_hint(&argv[0], errno) {
+        Some(hint) => bail!("Failed to exec {}: {}, {}", argv[0], errno, hint),
+        None => bail!("Failed to exec {}: {}", argv[0], errno),
+    }
+}
+
+/// Dynamic linkers the container's root has, like `/lib/ld-musl-x86_64.so.1`
+fn dynamic_linkers() -> Vec<PathBuf> {
+    let mut loaders: Vec<PathBuf> = ["/lib", "/lib64"].iter()
+        .flat_map(|dir| fs::read_dir(dir).into_iter().flatten().flatten())
+        .map(|entry| entry.path())
+        .filter(|path| path.file_name().is_some_and(|name| {
+            let name = name.to_string_lossy();
+            name.starts_with("ld-") && name.contains(".so")
+        }))
+        .collect();
+    loaders.sort();
+    loaders
+}
+
+/// Likely cause of an execve failure, checked from inside the container's root
+fn exec_hint(program: &str, errno: Errno) -> Option<String> {
+    let path = Path::new(program);
+
+    match errno {
+        Errno::ENOENT if !program.contains('/') => {
+            Some("the command isn't looked up in PATH, give its absolute path".to_string())
+        }
+        Errno::ENOENT if !path.exists() => Some(format!("{} does not exist in the container", program)),
+        // The file is there, so what the kernel has to load along with it isn't
+        Errno::ENOENT => match elf::interpreter(path) {
+            Ok(Some(Interpreter::DynamicLinker(linker))) if !linker.exists() => {
+                let linkers = dynamic_linkers();
+                Some(match linkers.is_empty() {
+                    true => format!("it needs {} which is missing in the image", linker.display()),
+                    false => format!(
+                        "it needs {} which is missing in the image, it only has {}, so the binary was built for another libc",
+                        linker.display(),
+                        linkers.iter().map(|linker| linker.display().to_string()).collect::<Vec<_>>().join(", ")
+                    ),
+                })
+            }
+            Ok(Some(Interpreter::Shebang(program))) if !program.exists() => {
+                Some(format!("its #! line needs {} which is missing in the image", program.display()))
+            }
+            _ => Some("it exists, so its interpreter is missing: a binary's dynamic linker or a script's #! program".to_string()),
+        },
+        Errno::EACCES if path.is_dir() => Some("it is a directory".to_string()),
+        Errno::EACCES => Some("it isn't executable or is on a noexec mount".to_string()),
+        Errno::ENOEXEC => Some("it isn't a binary for this architecture, or a script without a #! line".to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const MESSAGE: &str = "hello from the fixture\n";
+    const EXIT_CODE: i32 = 3;
+
+    /// A static x86_64 ELF writing [`MESSAGE`] to stdout and exiting with [`EXIT_CODE`],
+    /// so the fixture rootfs needs neither a libc nor a loader
+    #[cfg(target_arch = "x86_64")]
+    fn fixture_binary() -> Vec<u8> {
+        const BASE: u64 = 0x400000;
+        const HEADERS: u64 = 64 + 56;
+
+        let mut code = vec![
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (write)
+            0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+            0x48, 0x8d, 0x35, 0x13, 0x00, 0x00, 0x00, // lea rsi, [rip + 19], the message
+            0xba, // mov edx, len
+        ];
+        code.extend((MESSAGE.len() as u32).to_le_bytes());
+        code.extend([
+            0x0f, 0x05, // syscall
+            0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+            0xbf, // mov edi, code
+        ]);
+        code.extend((EXIT_CODE as u32).to_le_bytes());
+        code.extend([0x0f, 0x05]);
+        code.extend(MESSAGE.as_bytes());
+        let size = HEADERS + code.len() as u64;
+
+        let mut elf = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0".to_vec();
+        elf.extend(2u16.to_le_bytes()); // executable
+        elf.extend(0x3eu16.to_le_bytes()); // x86_64
+        elf.extend(1u32.to_le_bytes());
+        elf.extend((BASE + HEADERS).to_le_bytes()); // entry point, right after the headers
+        elf.extend(64u64.to_le_bytes()); // program headers
+        elf.extend(0u64.to_le_bytes()); // no section headers
+        elf.extend(0u32.to_le_bytes());
+        for field in [64u16, 56, 1, 0, 0, 0] {
+            elf.extend(field.to_le_bytes());
+        }
+
+        // One read + execute segment mapping the whole file
+        elf.extend(1u32.to_le_bytes());
+        elf.extend(5u32.to_le_bytes());
+        for field in [0, BASE, BASE, size, size, 0x1000] {
+            elf.extend(field.to_le_bytes());
+        }
+
+        elf.extend(code);
+        elf
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn runs_a_static_binary_in_a_fixture_rootfs() {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("skipping, running a container needs root");
+            return;
+        }
+
+        let tmp = std::env::temp_dir().join(format!("woody-run-{}", std::process::id()));
+        let rootfs = tmp.join("rootfs");
+        fs::create_dir_all(rootfs.join("bin")).unwrap();
+        fs::write(rootfs.join("bin/hello"), fixture_binary()).unwrap();
+        fs::set_permissions(rootfs.join("bin/hello"), fs::Permissions::from_mode(0o755)).unwrap();
+        paths::set_root(tmp.join("state")).unwrap();
+
+        let log_path = tmp.join("output.log");
+        let opts = RunOptions {
+            network: NetworkMode::None,
+            cgroup: CgroupMode::Disabled,
+            log_path: Some(log_path.clone()),
+            quiet: true,
+            command: vec!["/bin/hello".to_string()],
+            remove: true,
+            ..Default::default()
+        };
+        let status = run_rootfs(&rootfs, &opts);
+        let output = fs::read_to_string(&log_path);
+        let leftovers = fs::read_dir(tmp.join("state")).map(|dir| dir.count());
+        let untouched = fs::read_dir(&rootfs).unwrap().count() == 1;
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert_eq!(status.unwrap().code(), Some(EXIT_CODE));
+        assert_eq!(output.unwrap(), MESSAGE);
+        assert_eq!(leftovers.unwrap(), 0, "--rm left the container directory behind");
+        assert!(untouched, "the rootfs was written to");
+    }
+}
@@ -0,0 +1,258 @@
+use std::{fs, net::IpAddr, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::{capabilities, cgroups::ResourceLimits};
+
+/// `woody run --config` file, anything set on the command line wins over it.
+///
+/// Values are kept in their command line form and go through the same parsers
+/// as the flags, so a spec can say everything `woody run` can and nothing more.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RunSpec {
+    pub image: Option<String>,
+    #[serde(default)]
+    pub command: Vec<String>,
+    pub entrypoint: Option<String>,
+    /// `KEY=VALUE` or a bare `KEY`, like -e
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// `host:container[:ro|rw]`, relative host paths are relative to the spec file
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    /// `container_path[:options]`, like --tmpfs
+    #[serde(default)]
+    pub tmpfs: Vec<String>,
+    pub network: Option<String>,
+    /// private or host, like --ipc
+    pub ipc: Option<String>,
+    /// private or host, like --uts
+    pub uts: Option<String>,
+    #[serde(default)]
+    pub ports: Vec<String>,
+    /// Like -P
+    #[serde(default)]
+    pub publish_all: bool,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// `name:ip`, like --add-host
+    #[serde(default)]
+    pub add_hosts: Vec<String>,
+    #[serde(default)]
+    pub cap_add: Vec<String>,
+    #[serde(default)]
+    pub cap_drop: Vec<String>,
+    pub hostname: Option<String>,
+    /// `user[:group]`, like --user
+    pub user: Option<String>,
+    pub workdir: Option<String>,
+    /// Like --shm-size, e.g. `256m`
+    pub shm_size: Option<String>,
+    /// Like --restart, e.g. `on-failure:3`
+    pub restart: Option<String>,
+    /// Like --stop-signal, e.g. `SIGQUIT`
+    pub stop_signal: Option<String>,
+    #[serde(default)]
+    pub resources: ResourceLimits,
+}
+
+/// Load a spec, a `.json` file is read as an OCI runtime `config.json`, anything else as TOML
+pub fn load(path: &Path) -> anyhow::Result<RunSpec> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut spec = if path.extension().is_some_and(|ext| ext == "json") {
+        let oci: OciSpec = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse OCI runtime spec {}", path.display()))?;
+        from_oci(oci)?
+    } else {
+        toml::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?
+    };
+
+    let base = path.parent().unwrap_or(Path::new("."));
+    for volume in &mut spec.volumes {
+        if !volume.starts_with('/') {
+            *volume = base.join(&*volume).to_string_lossy().into_owned();
+        }
+    }
+
+    Ok(spec)
+}
+
+/// The parts of an OCI runtime spec woody has an equivalent for
+#[derive(Deserialize, Debug, Default)]
+struct OciSpec {
+    #[serde(default)]
+    process: Option<OciProcess>,
+    #[serde(default)]
+    root: Option<OciRoot>,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    mounts: Vec<OciMount>,
+    #[serde(default)]
+    linux: Option<OciLinux>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OciProcess {
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: Vec<String>,
+    #[serde(default)]
+    cwd: Option<String>,
+    #[serde(default)]
+    capabilities: Option<OciCapabilities>,
+    #[serde(default)]
+    user: Option<OciUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OciUser {
+    uid: u32,
+    gid: u32,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OciCapabilities {
+    #[serde(default)]
+    bounding: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OciRoot {
+    path: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OciMount {
+    destination: String,
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OciLinux {
+    #[serde(default)]
+    resources: Option<OciResources>,
+    #[serde(default)]
+    namespaces: Vec<OciNamespace>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OciResources {
+    #[serde(default)]
+    memory: Option<OciLimit>,
+    #[serde(default)]
+    pids: Option<OciLimit>,
+    #[serde(default)]
+    cpu: Option<OciCpu>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OciCpu {
+    #[serde(default)]
+    shares: Option<u64>,
+    #[serde(default)]
+    quota: Option<i64>,
+    #[serde(default)]
+    period: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OciLimit {
+    limit: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct OciNamespace {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// Filesystems woody mounts in every container by itself
+const RUNTIME_MOUNTS: [&str; 6] = ["/proc", "/sys", "/dev", "/dev/pts", "/dev/shm", "/dev/mqueue"];
+
+fn from_oci(oci: OciSpec) -> anyhow::Result<RunSpec> {
+    let mut spec = RunSpec { hostname: oci.hostname, ..Default::default() };
+
+    if let Some(root) = oci.root {
+        warn!("Ignoring the bundle root {}, the image comes from the command line", root.path);
+    }
+
+    if let Some(process) = oci.process {
+        // args is the complete argv, so the image's entrypoint must not be prepended
+        if !process.args.is_empty() {
+            spec.entrypoint = Some(String::new());
+            spec.command = process.args;
+        }
+        spec.env = process.env;
+        spec.workdir = process.cwd.filter(|cwd| cwd != "/");
+        spec.user = process.user
+            .filter(|user| user.uid != 0 || user.gid != 0)
+            .map(|user| format!("{}:{}", user.uid, user.gid));
+
+        if let Some(capabilities) = process.capabilities {
+            spec.cap_drop = capabilities::DEFAULT_CAPS.iter()
+                .map(|cap| cap.to_string())
+                .filter(|name| !capabilities.bounding.contains(name))
+                .collect();
+            spec.cap_add = capabilities.bounding;
+        }
+    }
+
+    for mount in oci.mounts {
+        let is_bind = mount.kind.as_deref() == Some("bind")
+            || mount.options.iter().any(|option| option == "bind" || option == "rbind");
+
+        match mount.source {
+            Some(source) if is_bind => {
+                let mode = if mount.options.iter().any(|option| option == "ro") { "ro" } else { "rw" };
+                spec.volumes.push(format!("{}:{}:{}", source, mount.destination, mode));
+            }
+            _ if RUNTIME_MOUNTS.contains(&mount.destination.as_str()) => {
+                debug!("Skipping mount {}, woody sets it up itself", mount.destination);
+            }
+            _ if mount.kind.as_deref() == Some("tmpfs") => {
+                spec.tmpfs.push(format!("{}:{}", mount.destination, mount.options.join(",")));
+            }
+            _ => warn!("Ignoring unsupported {} mount at {}", mount.kind.as_deref().unwrap_or("unknown"), mount.destination),
+        }
+    }
+
+    if let Some(linux) = oci.linux {
+        // A namespace the spec doesn't list is the host's
+        let shares = |kind: &str| !linux.namespaces.iter().any(|namespace| namespace.kind == kind);
+        if shares("network") {
+            spec.network = Some("host".to_string());
+        }
+        if shares("ipc") {
+            spec.ipc = Some("host".to_string());
+        }
+        if shares("uts") {
+            spec.uts = Some("host".to_string());
+            spec.hostname = None;
+        }
+
+        if let Some(resources) = linux.resources {
+            // Negative limits mean unlimited
+            spec.resources.memory = resources.memory.and_then(|memory| u64::try_from(memory.limit).ok());
+            spec.resources.pids = resources.pids.and_then(|pids| u32::try_from(pids.limit).ok());
+            if let Some(cpu) = resources.cpu {
+                spec.resources.cpu_shares = cpu.shares;
+                spec.resources.cpu_quota = cpu.quota.filter(|quota| *quota > 0);
+                spec.resources.cpu_period = cpu.period.filter(|_| spec.resources.cpu_quota.is_some());
+            }
+        }
+    }
+
+    Ok(spec)
+}
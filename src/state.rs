@@ -0,0 +1,138 @@
+use std::{fmt, fs, net::Ipv4Addr, path::PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{health::Health, logs::LogFormat, paths};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Running,
+    /// Exited, the supervisor starts it again after a backoff
+    Restarting,
+    Exited,
+    Stopped,
+    Killed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Status::Running => "running",
+            Status::Restarting => "restarting",
+            Status::Exited => "exited",
+            Status::Stopped => "stopped",
+            Status::Killed => "killed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Persisted view of a container, stored at `<root>/<id>/state.json`, see [`paths::root`]
+///
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContainerState {
+    pub id: String,
+    pub image: String,
+    /// Store id of the image, see [`LocalImage::id`](crate::LocalImage::id)
+    #[serde(default)]
+    pub image_id: Option<String>,
+    pub pid: i32,
+    pub status: Status,
+    #[serde(default)]
+    pub ip_address: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub log_path: Option<PathBuf>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Relative to the cgroup root, `None` if the container runs without one
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    /// How often the supervisor restarted it, see [`RestartPolicy`](crate::restart::RestartPolicy)
+    #[serde(default)]
+    pub restart_count: u32,
+    /// Set by `woody stop` and `woody kill` before signalling, so the container isn't restarted
+    #[serde(default)]
+    pub stop_requested: bool,
+    /// `None` without a health check
+    #[serde(default)]
+    pub health: Option<Health>,
+    /// What `woody stop` sends first, SIGTERM if unset
+    #[serde(default)]
+    pub stop_signal: Option<String>,
+    /// `--user` or the image's User the container runs as, `None` for root
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Capabilities the container kept, the defaults if unset
+    #[serde(default)]
+    pub capabilities: Option<Vec<String>>,
+    /// `--seccomp` as given, the default profile if unset
+    #[serde(default)]
+    pub seccomp: Option<String>,
+}
+
+impl ContainerState {
+    pub fn path(id: &str) -> PathBuf {
+        paths::container_dir(id).join("state.json")
+    }
+
+    pub fn load(id: &str) -> anyhow::Result<Self> {
+        let path = Self::path(id);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("No such container: {}", id))?;
+
+        serde_json::from_str(&content)
+            .with_context(|| format!("Corrupted state file: {}", path.display()))
+    }
+
+    /// Write to a temporary file first so readers never see a half-written state
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = Self::path(&self.id);
+        let tmp_path = path.with_extension("json.tmp");
+
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, &path).context("Failed to persist container state")?;
+
+        Ok(())
+    }
+
+    /// Every container with a state file, in no particular order
+    pub fn list() -> anyhow::Result<Vec<Self>> {
+        let mut states = Vec::new();
+
+        let entries = match fs::read_dir(paths::root()) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(states),
+        };
+        for entry in entries {
+            let id = entry?.file_name();
+            if let Some(id) = id.to_str() {
+                if Self::path(id).exists() {
+                    states.push(Self::load(id)?);
+                }
+            }
+        }
+
+        Ok(states)
+    }
+
+    /// Tell the supervisor the container is going down on purpose
+    pub fn request_stop(id: &str) -> anyhow::Result<()> {
+        let mut state = Self::load(id)?;
+        state.stop_requested = true;
+        state.save()
+    }
+
+    pub fn set_status(id: &str, status: Status) -> anyhow::Result<()> {
+        let mut state = Self::load(id)?;
+        state.status = status;
+        state.save()
+    }
+
+    pub fn set_health(id: &str, health: Health) -> anyhow::Result<()> {
+        let mut state = Self::load(id)?;
+        state.health = Some(health);
+        state.save()
+    }
+}
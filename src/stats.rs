@@ -0,0 +1,134 @@
+use std::{io::{self, Write}, thread, time::{Duration, Instant}};
+
+use anyhow::{bail, Context};
+use nix::unistd::{isatty, Pid};
+
+use crate::{
+    is_alive,
+    lrng_cgroup::{self, Cgroup, CgroupManager, Controller, PressureResource},
+    state::ContainerState,
+};
+
+/// Time between the usage samples a CPU percentage is computed from, and between updates
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Target {
+    id: String,
+    pid: Pid,
+    cgroup: Cgroup,
+}
+
+/// Cumulative CPU time at a point in time
+#[derive(Clone, Copy)]
+struct CpuSample {
+    usage_ns: u64,
+    at: Instant,
+}
+
+/// Print a `docker stats` like line per container, once with `no_stream`, otherwise
+/// every [`SAMPLE_INTERVAL`] until all of them exited. CPU is a share of all CPUs.
+pub fn print_stats(ids: &[String], no_stream: bool) -> anyhow::Result<()> {
+    let manager = CgroupManager::new().context("Failed to open the cgroup hierarchy")?;
+    let targets = ids.iter().map(|id| open(&manager, id)).collect::<anyhow::Result<Vec<_>>>()?;
+    let host_memory = nix::sys::sysinfo::sysinfo()?.ram_total();
+    let clear_screen = !no_stream && isatty(libc::STDOUT_FILENO).unwrap_or(false);
+
+    let mut previous = targets.iter().map(sample_cpu).collect::<anyhow::Result<Vec<_>>>()?;
+    loop {
+        thread::sleep(SAMPLE_INTERVAL);
+        let current = targets.iter().map(sample_cpu).collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut out = io::stdout().lock();
+        if clear_screen {
+            write!(out, "\x1b[2J\x1b[H")?;
+        }
+        writeln!(
+            out,
+            "{:<16} {:>8} {:>22} {:>8} {:>6} {:>20}",
+            "CONTAINER ID", "CPU %", "MEM USAGE / LIMIT", "MEM %", "PIDS", "PRESSURE CPU/MEM/IO"
+        )?;
+
+        for ((target, before), after) in targets.iter().zip(&previous).zip(&current) {
+            let memory = target.cgroup.get_memory_stats()?;
+            let limit = memory.limit_in_bytes.unwrap_or(host_memory).min(host_memory);
+            let pids = target.cgroup.get_pids_current().map(|pids| pids.to_string()).unwrap_or_else(|_| "--".to_string());
+
+            writeln!(
+                out,
+                "{:<16} {:>7.2}% {:>22} {:>7.2}% {:>6} {:>20}",
+                target.id,
+                after.percent_since(before),
+                format!("{} / {}", format_bytes(memory.usage_in_bytes), format_bytes(limit)),
+                percent(memory.usage_in_bytes, limit),
+                pids,
+                pressure(&target.cgroup),
+            )?;
+        }
+        out.flush()?;
+
+        if no_stream || !targets.iter().any(|target| is_alive(target.pid)) {
+            return Ok(());
+        }
+        previous = current;
+    }
+}
+
+fn open(manager: &CgroupManager, id: &str) -> anyhow::Result<Target> {
+    let state = ContainerState::load(id)?;
+    let pid = Pid::from_raw(state.pid);
+    if !is_alive(pid) {
+        bail!("Container {} is not running", id);
+    }
+
+    let name = state.cgroup
+        .with_context(|| format!("Container {} runs without a cgroup, it has no stats", id))?;
+    let cgroup = manager.get_cgroup(&name, Some(Controller::Cpu))
+        .with_context(|| format!("Failed to open cgroup {} of container {}", name, id))?;
+
+    Ok(Target { id: id.to_string(), pid, cgroup })
+}
+
+fn sample_cpu(target: &Target) -> anyhow::Result<CpuSample> {
+    let usage_ns = target.cgroup.get_cpu_stats()?.usage_ns;
+    Ok(CpuSample { usage_ns, at: Instant::now() })
+}
+
+impl CpuSample {
+    fn percent_since(&self, before: &CpuSample) -> f64 {
+        lrng_cgroup::cpu_percent(self.usage_ns.saturating_sub(before.usage_ns), self.at.duration_since(before.at))
+    }
+}
+
+/// `some avg10` of cpu, memory and io, the share of the last 10s the container was stalled on each
+fn pressure(cgroup: &Cgroup) -> String {
+    [PressureResource::Cpu, PressureResource::Memory, PressureResource::Io]
+        .map(|resource| match cgroup.get_pressure(resource) {
+            Ok(stats) => format!("{:.2}", stats.some.avg10),
+            Err(_) => "--".to_string(),
+        })
+        .join("/")
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 / whole as f64 * 100.0,
+    }
+}
+
+/// Binary units, like `docker stats`
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{}B", bytes),
+        _ => format!("{:.2}{}", size, UNITS[unit]),
+    }
+}
@@ -0,0 +1,125 @@
+use std::{os::unix::io::RawFd, thread::{self, JoinHandle}};
+
+use anyhow::Context;
+use nix::{
+    errno::Errno,
+    pty::openpty,
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios},
+    unistd::{close, dup2, isatty, read, setsid, write},
+};
+use signal_hook::{consts::SIGWINCH, iterator::Signals};
+
+pub struct Pty {
+    pub master: RawFd,
+    pub slave: RawFd,
+}
+
+/// Allocate a pseudo terminal sized like the host's
+pub fn open_pty() -> anyhow::Result<Pty> {
+    let pty = openpty(None, None).context("Failed to allocate a pty")?;
+    copy_window_size(libc::STDOUT_FILENO, pty.master);
+
+    Ok(Pty { master: pty.master, slave: pty.slave })
+}
+
+/// Child side: become session leader and make the slave our controlling terminal and stdio
+pub fn attach_to_slave(pty: &Pty) -> anyhow::Result<()> {
+    close(pty.master)?;
+    setsid().context("Failed to create a new session")?;
+
+    if unsafe { libc::ioctl(pty.slave, libc::TIOCSCTTY, 0) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to set controlling terminal");
+    }
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        dup2(pty.slave, fd)?;
+    }
+    close(pty.slave)?;
+
+    Ok(())
+}
+
+/// Puts the host terminal in raw mode and restores it when dropped, including on error paths
+pub struct RawModeGuard {
+    original: Option<Termios>,
+}
+
+impl RawModeGuard {
+    pub fn enable() -> anyhow::Result<Self> {
+        if !isatty(libc::STDIN_FILENO).unwrap_or(false) {
+            return Ok(RawModeGuard { original: None });
+        }
+
+        let original = tcgetattr(libc::STDIN_FILENO).context("Failed to read terminal attributes")?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(libc::STDIN_FILENO, SetArg::TCSANOW, &raw).context("Failed to set raw mode")?;
+
+        Ok(RawModeGuard { original: Some(original) })
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            tcsetattr(libc::STDIN_FILENO, SetArg::TCSANOW, original).ok();
+        }
+    }
+}
+
+/// Parent side: feed the host terminal's input and size changes to the pty master.
+///
+/// Output coming back from the master is handled by the log writer.
+pub fn forward_input(master: RawFd, forward_stdin: bool) -> anyhow::Result<()> {
+    if forward_stdin {
+        // Blocks on the host's stdin forever, so it's left detached
+        thread::spawn(move || copy(libc::STDIN_FILENO, master));
+    }
+
+    let mut signals = Signals::new([SIGWINCH]).context("Failed to watch for window resizes")?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            copy_window_size(libc::STDOUT_FILENO, master);
+        }
+    });
+
+    Ok(())
+}
+
+/// Copy pty output straight to the host terminal, for callers that don't log it
+pub fn forward_output(master: RawFd) -> JoinHandle<()> {
+    thread::spawn(move || copy(master, libc::STDOUT_FILENO))
+}
+
+fn copy(from: RawFd, to: RawFd) {
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let n = match read(from, &mut buf) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(Errno::EINTR) => continue,
+            // The master reports EIO once every slave fd is closed
+            Err(_) => return,
+        };
+
+        let mut written = 0;
+        while written < n {
+            match write(to, &buf[written..n]) {
+                Ok(w) => written += w,
+                Err(Errno::EINTR) => continue,
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+fn copy_window_size(from: RawFd, to: RawFd) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+
+    unsafe {
+        if libc::ioctl(from, libc::TIOCGWINSZ, &mut size) == 0 {
+            libc::ioctl(to, libc::TIOCSWINSZ, &size);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+/// Bytes from `512`, `64k`, `512m`, `2g` or `1t`. Every unit is a power of 1024 as
+/// with docker and tmpfs, `ki`, `mi`, `gi` and `ti` are the same as `k`, `m`, `g` and `t`.
+/// Case-insensitive, with an optional trailing `b`.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let lower = s.trim().to_ascii_lowercase();
+    if lower.starts_with('-') {
+        bail!("Invalid size {:?}, it can't be negative", s);
+    }
+
+    let at = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+    let (number, suffix) = lower.split_at(at);
+    let multiplier: u64 = match suffix.strip_suffix('b').unwrap_or(suffix) {
+        "" => 1,
+        "k" | "ki" => 1 << 10,
+        "m" | "mi" => 1 << 20,
+        "g" | "gi" => 1 << 30,
+        "t" | "ti" => 1 << 40,
+        _ => bail!("Invalid size {:?}, expected a number of bytes with an optional k, m, g, t, ki, mi, gi or ti unit", s),
+    };
+
+    let number: u64 = number.parse().with_context(|| format!("Invalid size {:?}, expected a whole number", s))?;
+    number.checked_mul(multiplier).with_context(|| format!("Size {:?} is too large", s))
+}
+
+/// A duration like docker takes them, `30s`, `1m30s`, `500ms` or `1h`, a bare number is seconds
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    if let Ok(seconds) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let at = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(at);
+        let unit_len = tail.find(|c: char| c.is_ascii_digit()).unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+
+        let number: u64 = number.parse()
+            .with_context(|| format!("Invalid duration {:?}, expected e.g. 30s, 1m30s or 500ms", s))?;
+        total += match unit {
+            "ms" => Duration::from_millis(number),
+            "s" => Duration::from_secs(number),
+            "m" => Duration::from_secs(number * 60),
+            "h" => Duration::from_secs(number * 3600),
+            _ => bail!("Invalid duration {:?}, units are ms, s, m and h", s),
+        };
+        rest = tail;
+    }
+
+    if s.is_empty() {
+        bail!("Invalid duration {:?}, expected e.g. 30s, 1m30s or 500ms", s);
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_bytes() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("512b").unwrap(), 512);
+        assert_eq!(parse_size(" 4096 ").unwrap(), 4096);
+        assert_eq!(parse_size("18446744073709551615").unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn suffixes_are_binary() {
+        assert_eq!(parse_size("64k").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("512m").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("2g").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("1t").unwrap(), 1 << 40);
+        assert_eq!(parse_size("64ki").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("512mi").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("2gi").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("1ti").unwrap(), 1 << 40);
+        assert_eq!(parse_size("1kb").unwrap(), 1024);
+        assert_eq!(parse_size("1kib").unwrap(), 1024);
+    }
+
+    #[test]
+    fn suffixes_are_case_insensitive() {
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("2Gi").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1KiB").unwrap(), 1024);
+        assert_eq!(parse_size("1GB").unwrap(), 1 << 30);
+    }
+
+    #[test]
+    fn rejects_negative_and_garbage() {
+        for input in ["", "-1", "-512m", "m", "ki", "1.5g", "512x", "512 m", "1e9", "0x10", "12mm", "1ib"] {
+            assert!(parse_size(input).is_err(), "{:?} should be rejected", input);
+        }
+        assert!(parse_size("-1").unwrap_err().to_string().contains("negative"));
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert!(parse_size("18446744073709551616").is_err());
+        assert!(parse_size("16777216ti").is_err());
+        assert_eq!(parse_size("16777215ti").unwrap(), 16777215 << 40);
+    }
+}
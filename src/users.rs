@@ -0,0 +1,134 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context};
+use nix::unistd::{setgid, setgroups, setuid, Gid, Uid};
+
+/// Who a container's process runs as, from `--user` or the image's `User`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecUser {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups, the ones /etc/group lists the user in
+    pub groups: Vec<u32>,
+    /// Home directory from /etc/passwd
+    pub home: Option<String>,
+}
+
+/// One line of /etc/passwd
+struct PasswdEntry {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String,
+}
+
+/// One line of /etc/group
+struct GroupEntry {
+    name: String,
+    gid: u32,
+    members: Vec<String>,
+}
+
+/// Resolve `user[:group]`, names or ids, against the passwd and group files under `root`.
+///
+/// Like docker, ids need no entry: a uid without one runs with gid 0 and no
+/// supplementary groups.
+pub fn resolve(spec: &str, root: &Path) -> anyhow::Result<ExecUser> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    if user.is_empty() || group.is_some_and(str::is_empty) {
+        bail!("Invalid user {:?}, expected user[:group] as names or ids", spec);
+    }
+
+    let passwd = read_passwd(root)?;
+    let entry = match user.parse::<u32>() {
+        Ok(uid) => passwd.into_iter().find(|entry| entry.uid == uid),
+        Err(_) => Some(passwd.into_iter()
+            .find(|entry| entry.name == user)
+            .with_context(|| format!("No user {:?} in the container's /etc/passwd", user))?),
+    };
+    let uid = match &entry {
+        Some(entry) => entry.uid,
+        None => user.parse()?,
+    };
+
+    let groups = read_group(root)?;
+    let gid = match group {
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => groups.iter()
+                .find(|entry| entry.name == group)
+                .map(|entry| entry.gid)
+                .with_context(|| format!("No group {:?} in the container's /etc/group", group))?,
+        },
+        None => entry.as_ref().map_or(0, |entry| entry.gid),
+    };
+
+    // An explicit group replaces the supplementary ones as well
+    let supplementary = match (&entry, group) {
+        (Some(entry), None) => groups.iter()
+            .filter(|group| group.members.contains(&entry.name))
+            .map(|group| group.gid)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Ok(ExecUser { uid, gid, groups: supplementary, home: entry.map(|entry| entry.home) })
+}
+
+/// Become `user`, groups first, which takes root to do
+pub fn switch_to(user: &ExecUser) -> anyhow::Result<()> {
+    let mut groups: Vec<Gid> = vec![Gid::from_raw(user.gid)];
+    groups.extend(user.groups.iter().map(|gid| Gid::from_raw(*gid)));
+
+    setgroups(&groups).context("Failed to set supplementary groups")?;
+    setgid(Gid::from_raw(user.gid)).with_context(|| format!("Failed to switch to gid {}", user.gid))?;
+    setuid(Uid::from_raw(user.uid)).with_context(|| format!("Failed to switch to uid {}", user.uid))?;
+
+    Ok(())
+}
+
+/// Lines of `name:password:uid:gid:gecos:home:shell`, a missing file has no users
+fn read_passwd(root: &Path) -> anyhow::Result<Vec<PasswdEntry>> {
+    Ok(read_entries(&root.join("etc/passwd"))?
+        .filter_map(|fields| match fields.as_slice() {
+            [name, _, uid, gid, _, home, ..] => Some(PasswdEntry {
+                name: name.to_string(),
+                uid: uid.parse().ok()?,
+                gid: gid.parse().ok()?,
+                home: home.to_string(),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Lines of `name:password:gid:member,member`
+fn read_group(root: &Path) -> anyhow::Result<Vec<GroupEntry>> {
+    Ok(read_entries(&root.join("etc/group"))?
+        .filter_map(|fields| match fields.as_slice() {
+            [name, _, gid, members, ..] => Some(GroupEntry {
+                name: name.to_string(),
+                gid: gid.parse().ok()?,
+                members: members.split(',').filter(|m| !m.is_empty()).map(str::to_string).collect(),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+fn read_entries(path: &Path) -> anyhow::Result<impl Iterator<Item = Vec<String>>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    Ok(content.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(str::to_string).collect::<Vec<_>>())
+        .collect::<Vec<_>>()
+        .into_iter())
+}
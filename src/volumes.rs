@@ -0,0 +1,248 @@
+use std::{collections::VecDeque, ffi::OsString, fs, path::{Component, Path, PathBuf}};
+
+use anyhow::{bail, Context};
+use nix::mount::{mount, MsFlags};
+use tracing::debug;
+
+use crate::{idmap, units};
+
+#[derive(Debug, Clone)]
+pub struct VolumeMount {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub read_only: bool,
+}
+
+impl VolumeMount {
+    /// Parse a `host_path:container_path[:ro|rw]` spec
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = spec.split(':').collect();
+
+        let (source, target, read_only) = match parts.as_slice() {
+            [source, target] => (source, target, false),
+            [source, target, "ro"] => (source, target, true),
+            [source, target, "rw"] => (source, target, false),
+            [_, _, mode] => bail!("Invalid volume mode {:?} in {:?}, expected ro or rw", mode, spec),
+            _ => bail!("Invalid volume {:?}, expected host_path:container_path[:ro]", spec),
+        };
+
+        // Canonicalize now, the container setup changes directories before mounting
+        let source = fs::canonicalize(source)
+            .with_context(|| format!("Volume source {} does not exist", source))?;
+
+        let target = parse_target(target, "Volume")?;
+
+        Ok(VolumeMount { source, target, read_only })
+    }
+}
+
+/// A tmpfs of its own inside the container
+#[derive(Debug, Clone)]
+pub struct TmpfsMount {
+    pub target: PathBuf,
+    pub flags: MsFlags,
+    /// Passed on to tmpfs, e.g. `size=16777216,mode=0755`
+    pub data: String,
+}
+
+impl TmpfsMount {
+    /// Parse a `container_path[:options]` spec, options being a comma separated list
+    /// of size=, mode=, uid=, gid=, nr_inodes= and the ro, noexec, exec, nosuid,
+    /// suid, nodev, dev and noatime flags. Without them it's nosuid and nodev.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let (target, options) = spec.split_once(':').unwrap_or((spec, ""));
+        let target = parse_target(target, "Tmpfs")?;
+
+        let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+        let mut data = Vec::new();
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                None => match option {
+                    "ro" => flags.insert(MsFlags::MS_RDONLY),
+                    "rw" => flags.remove(MsFlags::MS_RDONLY),
+                    "noexec" => flags.insert(MsFlags::MS_NOEXEC),
+                    "exec" => flags.remove(MsFlags::MS_NOEXEC),
+                    "nosuid" => flags.insert(MsFlags::MS_NOSUID),
+                    "suid" => flags.remove(MsFlags::MS_NOSUID),
+                    "nodev" => flags.insert(MsFlags::MS_NODEV),
+                    "dev" => flags.remove(MsFlags::MS_NODEV),
+                    "noatime" => flags.insert(MsFlags::MS_NOATIME),
+                    other => bail!("Unknown tmpfs option {:?} in {:?}", other, spec),
+                },
+                Some(("size", size)) => {
+                    // tmpfs takes a percentage of RAM as is, and size=0 as unlimited
+                    let parsed = match size.strip_suffix('%') {
+                        Some(percent) => percent.parse::<u32>().ok().filter(|percent| *percent > 0).map(|_| size.to_string()),
+                        None => units::parse_size(size).ok().filter(|bytes| *bytes > 0).map(|bytes| bytes.to_string()),
+                    };
+                    let size = parsed.with_context(|| format!("Invalid tmpfs size {:?} in {:?}", size, spec))?;
+                    data.push(format!("size={}", size));
+                }
+                Some(("mode", mode)) => {
+                    if !u32::from_str_radix(mode, 8).is_ok_and(|mode| mode <= 0o7777) {
+                        bail!("Invalid tmpfs mode {:?} in {:?}, expected octal like 1777", mode, spec);
+                    }
+                    data.push(option.to_string());
+                }
+                Some((key @ ("uid" | "gid" | "nr_inodes"), value)) => {
+                    if value.parse::<u32>().is_err() {
+                        bail!("Invalid tmpfs {} {:?} in {:?}", key, value, spec);
+                    }
+                    data.push(option.to_string());
+                }
+                Some((key, _)) => bail!("Unknown tmpfs option {:?} in {:?}", key, spec),
+            }
+        }
+
+        Ok(TmpfsMount { target, flags, data: data.join(",") })
+    }
+}
+
+/// An absolute path inside the container that can't climb out of it
+fn parse_target(target: &str, kind: &str) -> anyhow::Result<PathBuf> {
+    let target = PathBuf::from(target);
+    if !target.is_absolute() {
+        bail!("{} target {} must be an absolute path inside the container", kind, target.display());
+    }
+    if target.components().any(|c| c == Component::ParentDir) {
+        bail!("{} target {} must not contain '..'", kind, target.display());
+    }
+    Ok(target)
+}
+
+/// `target` under `root` with the image's symlinks resolved as the container would see
+/// them, like docker does: an absolute link or a `..` can't climb above `root`.
+///
+/// The mount points are created from the host side, where a link like `data -> /etc`
+/// would otherwise lead to the host's directories.
+fn resolve_target(root: &Path, target: &Path) -> anyhow::Result<PathBuf> {
+    const MAX_LINKS: usize = 40;
+
+    // Parts still to resolve, `None` for a `..`
+    let parts = |path: &Path| -> Vec<Option<OsString>> {
+        path.components()
+            .filter_map(|component| match component {
+                Component::Normal(part) => Some(Some(part.to_os_string())),
+                Component::ParentDir => Some(None),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let mut resolved = PathBuf::new();
+    let mut pending: VecDeque<_> = parts(target).into();
+    let mut links = 0;
+    while let Some(part) = pending.pop_front() {
+        let Some(part) = part else {
+            resolved.pop();
+            continue;
+        };
+
+        let candidate = resolved.join(part);
+        let path = root.join(&candidate);
+        if !path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            resolved = candidate;
+            continue;
+        }
+
+        links += 1;
+        if links > MAX_LINKS {
+            bail!("Too many levels of symlinks in {}", target.display());
+        }
+        let link = fs::read_link(&path).with_context(|| format!("Failed to read link {}", path.display()))?;
+        if link.is_absolute() {
+            resolved.clear();
+        }
+        for part in parts(&link).into_iter().rev() {
+            pending.push_front(part);
+        }
+    }
+
+    Ok(root.join(resolved))
+}
+
+/// Mount each tmpfs under `root`, which must be the directory about to become `/`
+pub fn mount_tmpfs(root: &Path, mounts: &[TmpfsMount]) -> anyhow::Result<()> {
+    for tmpfs in mounts {
+        let target = resolve_target(root, &tmpfs.target)?;
+        idmap::create_dir_all_owned(&target)?;
+
+        mount(
+            Some("tmpfs"),
+            &target,
+            Some("tmpfs"),
+            tmpfs.flags,
+            Some(tmpfs.data.as_str()).filter(|data| !data.is_empty())
+        ).with_context(|| format!("Failed to mount a tmpfs at {}", tmpfs.target.display()))?;
+
+        debug!("Mounted tmpfs at {}", tmpfs.target.display());
+    }
+
+    Ok(())
+}
+
+/// Bind each volume under `root`, which must be the directory about to become `/`
+pub fn mount_volumes(root: &Path, volumes: &[VolumeMount]) -> anyhow::Result<()> {
+    for volume in volumes {
+        let target = resolve_target(root, &volume.target)?;
+
+        // Bind mounts need a target of the same kind as the source
+        if volume.source.is_dir() {
+            idmap::create_dir_all_owned(&target)?;
+        } else if !target.exists() {
+            if let Some(parent) = target.parent() {
+                idmap::create_dir_all_owned(parent)?;
+            }
+            fs::File::create(&target)?;
+        }
+
+        mount(
+            Some(&volume.source),
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>
+        ).with_context(|| format!("Failed to bind mount {}", volume.source.display()))?;
+
+        // MS_RDONLY is ignored on the initial bind, it only sticks on a remount
+        if volume.read_only {
+            mount(
+                None::<&str>,
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                None::<&str>
+            ).with_context(|| format!("Failed to make {} read-only", volume.target.display()))?;
+        }
+
+        debug!("Mounted volume {} -> {}", volume.source.display(), volume.target.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_resolve_inside_the_root() {
+        let tmp = std::env::temp_dir().join(format!("woody-volumes-{}", std::process::id()));
+        let root = tmp.join("root");
+        fs::create_dir_all(root.join("run")).unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("data")).unwrap();
+        std::os::unix::fs::symlink("../../run", root.join("var")).unwrap();
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+
+        let data = resolve_target(&root, Path::new("/data/conf"));
+        let var = resolve_target(&root, Path::new("/var/app"));
+        let plain = resolve_target(&root, Path::new("/srv/www"));
+        let looping = resolve_target(&root, Path::new("/loop/x"));
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert_eq!(data.unwrap(), root.join("etc/conf"));
+        assert_eq!(var.unwrap(), root.join("run/app"));
+        assert_eq!(plain.unwrap(), root.join("srv/www"));
+        assert!(looping.is_err());
+    }
+}